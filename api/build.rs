@@ -0,0 +1,27 @@
+//! 编译rpc服务的idl, 生成的描述文件嵌入到网关中, 用于按 `google.api.http` 注解注册路由和json转码
+
+use std::path::PathBuf;
+
+const IDL: [(&str, &str); 2] = [
+    ("../rpc/user/idl", "user.proto"),
+    ("../rpc/order/idl", "order.proto"),
+];
+
+/// 多个服务共用的 `google/api` 注解
+const SHARED_IDL: &str = "../rpc/idl";
+
+fn main() {
+    let mut includes: Vec<&str> = IDL.iter().map(|(include, _)| *include).collect();
+    includes.push(SHARED_IDL);
+    let files: Vec<&str> = IDL.iter().map(|(_, file)| *file).collect();
+    for include in includes.iter() {
+        println!("cargo:rerun-if-changed={}", include);
+    }
+
+    let mut compiler = protox::Compiler::new(includes).expect("invalid idl include path");
+    compiler.include_imports(true);
+    compiler.open_files(files).expect("compile idl failed");
+
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("gateway_descriptor.bin");
+    std::fs::write(out, compiler.encode_file_descriptor_set()).unwrap();
+}
@@ -1,4 +1,21 @@
+use crate::client;
+use crate::client::ClientOptions;
+use crate::concurrency_limiter::AdaptiveMode;
+use crate::consts::{
+    ADMIN_CONFIG_PATH, ADMIN_CONFIG_ROLLBACK_PATH, GRPC_PROXY_PATH, METRICS_PATH, RANDOM_PATH,
+};
+use crate::descriptor::ProtoRegistry;
+use crate::hash_key::CompiledHashKeyRule;
+use crate::ip_net::IpNet;
+use crate::openapi::{DEFAULT_OPENAPI_PATH, DEFAULT_SWAGGER_UI_PATH};
+use crate::rate_limiter::{LimiterBackend, MatchMode, RateLimitKey};
+use crate::retry;
+use crate::route_table::{paths_overlap, CompiledRoute};
+use anyhow::anyhow;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use volo_http::http::HeaderName;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AppConfig {
@@ -7,14 +24,474 @@ pub struct AppConfig {
     pub timeout: Option<u64>,
     // 订阅服务列表
     pub subscribe_service: Vec<String>,
+    // 每个rpc服务的客户端配置, key为服务名
+    #[serde(default)]
+    pub clients: HashMap<String, ClientConfig>,
+    // 一致性hash的key提取规则
+    #[serde(default)]
+    pub hash_key: Vec<HashKeyRule>,
+    // http路由到rpc方法的映射
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    // 运行时加载的idl, 用于转发没有生成代码的rpc方法
+    pub proto: Option<ProtoConfig>,
+    // 分布式限流使用的redis, 限流规则中 backend="redis" 时生效
+    pub redis: Option<RedisConfig>,
+    // 可信的代理地址(ip或网段), 只有来自这些地址的请求才按 X-Forwarded-For / X-Real-IP 取客户端ip
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    // 全局重试预算, 所有rpc服务共享
+    pub retry_budget: Option<RetryBudgetConfig>,
+    // OpenAPI文档和Swagger UI, 不配置时不提供
+    pub openapi: Option<OpenApiConfig>,
+    // 跨域规则, nacos动态配置中的 cors 会覆盖这里的配置; 都不配置时不返回跨域响应头
+    pub cors: Option<CorsConfig>,
+    // 管理接口的访问控制, 管理接口只在单独的 metric_port 上提供
+    pub admin: Option<AdminConfig>,
     // 服务注册中心配置
     pub sd: ServerDiscover,
 }
 
+impl AppConfig {
+    /// 启动时校验配置, 返回校验时加载的idl, 启动时直接使用, 不再重复加载
+    pub fn validate(&self) -> anyhow::Result<ProtoRegistry> {
+        let proto = ProtoRegistry::load(self.proto.as_ref())?;
+        for svc_name in self.subscribe_service.iter() {
+            if svc_name.contains(':') {
+                return Err(anyhow!(
+                    "invalid subscribe_service {}, use [clients.\"<service>\"] pool_size/max_pool_size to size the client pool",
+                    svc_name
+                ));
+            }
+            let known =
+                client::default_options(svc_name).is_some() || proto.contains_service(svc_name);
+            if !known {
+                return Err(anyhow!(
+                    "unknown rpc service in subscribe_service: {}",
+                    svc_name
+                ));
+            }
+        }
+        for svc_name in self.clients.keys() {
+            if !self.subscribe_service.contains(svc_name) {
+                return Err(anyhow!(
+                    "[clients.\"{}\"] is configured but the service is not in subscribe_service",
+                    svc_name
+                ));
+            }
+            self.client_options(svc_name)?;
+        }
+        for cb in self.circuit_breaker_rules() {
+            cb.validate().map_err(|e| {
+                anyhow!(
+                    "invalid [clients.\"{}\".circuit_breaker]: {}",
+                    cb.service,
+                    e
+                )
+            })?;
+        }
+        for (svc_name, retry) in self
+            .clients
+            .iter()
+            .filter_map(|(svc_name, x)| Some((svc_name, x.retry.as_ref()?)))
+        {
+            retry
+                .validate()
+                .map_err(|e| anyhow!("invalid [clients.\"{}\".retry]: {}", svc_name, e))?;
+        }
+        for (svc_name, hedge) in self
+            .clients
+            .iter()
+            .filter_map(|(svc_name, x)| Some((svc_name, x.hedge.as_ref()?)))
+        {
+            hedge
+                .validate()
+                .map_err(|e| anyhow!("invalid [clients.\"{}\".hedge]: {}", svc_name, e))?;
+        }
+        if let Some(budget) = self.retry_budget.as_ref() {
+            budget
+                .validate()
+                .map_err(|e| anyhow!("invalid [retry_budget]: {}", e))?;
+        }
+        if let Some(cors) = self.cors.as_ref() {
+            cors.validate()
+                .map_err(|e| anyhow!("invalid [cors]: {}", e))?;
+        }
+        if let Some(admin) = self.admin.as_ref() {
+            admin
+                .validate()
+                .map_err(|e| anyhow!("invalid [admin]: {}", e))?;
+        }
+        if let Some(openapi) = self.openapi.as_ref() {
+            openapi
+                .validate()
+                .map_err(|e| anyhow!("invalid [openapi]: {}", e))?;
+        }
+        for proxy in self.trusted_proxies.iter() {
+            IpNet::parse(proxy).map_err(|e| anyhow!("invalid trusted_proxies: {}", e))?;
+        }
+        if let Some(redis) = self.redis.as_ref() {
+            if redis.addr.is_empty() {
+                return Err(anyhow!("[redis] addr is empty"));
+            }
+        }
+        for rule in self.hash_key.iter() {
+            CompiledHashKeyRule::compile(rule)?;
+        }
+        let mut seen = HashSet::new();
+        for route in self.routes.iter() {
+            if !self.subscribe_service.contains(&route.service) {
+                return Err(anyhow!(
+                    "route {} {} calls {} which is not in subscribe_service",
+                    route.method,
+                    route.path,
+                    route.service
+                ));
+            }
+            let compiled = CompiledRoute::compile(route, Some(&proto))?;
+            if !seen.insert((compiled.method, compiled.path)) {
+                return Err(anyhow!(
+                    "duplicate route {} {} in [[routes]]",
+                    route.method,
+                    route.path
+                ));
+            }
+        }
+        self.check_builtin_routes(&proto)?;
+        Ok(proto)
+    }
+
+    /// 路由表和idl注解中的路由不能覆盖网关内置的路由
+    fn check_builtin_routes(&self, proto: &ProtoRegistry) -> anyhow::Result<()> {
+        let mut builtin = vec![
+            RANDOM_PATH,
+            METRICS_PATH,
+            GRPC_PROXY_PATH,
+            ADMIN_CONFIG_PATH,
+            ADMIN_CONFIG_ROLLBACK_PATH,
+        ];
+        if let Some(openapi) = self.openapi.as_ref() {
+            builtin.extend([openapi.path(), openapi.ui_path()]);
+        }
+        let routes = self
+            .routes
+            .iter()
+            .map(|x| (x.method.as_str(), x.path.as_str()))
+            .chain(
+                proto
+                    .http_routes()
+                    .iter()
+                    .map(|x| (x.method.as_str(), x.path.as_str())),
+            );
+        for (method, path) in routes {
+            if let Some(b) = builtin.iter().find(|b| paths_overlap(path, b)) {
+                return Err(anyhow!(
+                    "route {} {} conflicts with built-in route {}",
+                    method,
+                    path,
+                    b
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 服务的客户端参数: 服务默认值 + `[clients.<service>]` 配置, 只有idl的服务使用通用的默认值
+    pub fn client_options(&self, svc_name: &str) -> anyhow::Result<ClientOptions> {
+        let defaults = match client::default_options(svc_name) {
+            Some(options) => options,
+            None if self.is_proto_service(svc_name) => ClientOptions::default(),
+            None => return Err(anyhow!("unknown rpc service: {}", svc_name)),
+        };
+        match self.clients.get(svc_name) {
+            Some(client_config) => ClientOptions::apply_config(defaults, svc_name, client_config),
+            None => Ok(defaults),
+        }
+    }
+
+    /// `[clients.<service>.circuit_breaker]` 中的熔断规则
+    pub fn circuit_breaker_rules(&self) -> Vec<CircuitBreakerConfig> {
+        self.clients
+            .iter()
+            .filter_map(|(svc_name, x)| {
+                x.circuit_breaker.clone().map(|cb| CircuitBreakerConfig {
+                    service: svc_name.clone(),
+                    ..cb
+                })
+            })
+            .collect()
+    }
+
+    /// `[clients.<service>.retry]` 中的重试策略, key为服务名
+    pub fn retry_rules(&self) -> HashMap<String, RetryConfig> {
+        self.clients
+            .iter()
+            .filter_map(|(svc_name, x)| Some((svc_name.clone(), x.retry.clone()?)))
+            .collect()
+    }
+
+    /// `[clients.<service>.hedge]` 中的对冲配置, key为服务名
+    pub fn hedge_rules(&self) -> HashMap<String, HedgeConfig> {
+        self.clients
+            .iter()
+            .filter_map(|(svc_name, x)| Some((svc_name.clone(), x.hedge.clone()?)))
+            .collect()
+    }
+
+    /// 是否是在 `[proto.services]` 中配置的服务
+    pub fn is_proto_service(&self, svc_name: &str) -> bool {
+        self.proto
+            .as_ref()
+            .is_some_and(|p| p.services.values().any(|x| x == svc_name))
+    }
+}
+
+/// rpc客户端配置, 未配置的项使用服务的默认值
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct ClientConfig {
+    /// 负载均衡策略: consistent_hash | weighted_random
+    pub load_balance: Option<String>,
+    /// 最少客户端数量
+    pub pool_size: Option<usize>,
+    /// 最多客户端数量, 客户端的在途请求接近 `max_concurrent_streams` 时自动扩容
+    pub max_pool_size: Option<usize>,
+    /// 每个客户端(http2连接)允许的最大并发stream数, 不应超过rpc服务端的 `http2_max_concurrent_streams`
+    pub max_concurrent_streams: Option<usize>,
+    pub connect_timeout_ms: Option<u64>,
+    /// 单次rpc请求超时时间
+    pub request_timeout_ms: Option<u64>,
+    pub http2_max_frame_size: Option<u32>,
+    pub http2_init_stream_window_size: Option<u32>,
+    pub http2_init_connection_window_size: Option<u32>,
+    pub http2_adaptive_window: Option<bool>,
+    pub http2_keepalive_interval_ms: Option<u64>,
+    pub http2_keepalive_timeout_ms: Option<u64>,
+    pub http2_keepalive_while_idle: Option<bool>,
+    pub http2_max_concurrent_reset_streams: Option<usize>,
+    /// 熔断规则, 不需要填写 `service`
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// 重试策略, 默认只有幂等方法重试
+    pub retry: Option<RetryConfig>,
+    /// 对冲请求, 只对幂等方法生效
+    pub hedge: Option<HedgeConfig>,
+}
+
+/// 一致性hash的key提取规则, 按 `path` 正则匹配请求, 第一个匹配的规则生效
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct HashKeyRule {
+    pub path: String,
+    /// key的来源, 多个来源的值组合成一个key: query:<name> | header:<name> | path:<index>
+    pub sources: Vec<String>,
+}
+
+/// http路由到rpc方法的映射, 如 `GET /order/by-user` -> `volo-boot-order.rpc` 的 `GetOrder`
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct RouteConfig {
+    pub method: String,
+    pub path: String,
+    /// rpc服务名, 需要在 subscribe_service 中
+    pub service: String,
+    /// rpc方法名
+    pub rpc: String,
+    /// rpc请求字段 -> `<source>:<name>[:<type>]`, source: query | path | header | body,
+    /// type: string | int | float | bool | json; 不配置时使用json请求体作为rpc请求
+    #[serde(default)]
+    pub bindings: HashMap<String, String>,
+}
+
+/// 运行时加载的idl, 修改idl后不需要重新编译api即可转发新的rpc方法
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct ProtoConfig {
+    /// proto文件的搜索路径
+    #[serde(default)]
+    pub includes: Vec<String>,
+    /// proto文件, 相对于 `includes`
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// 编译好的描述文件, 如 `protoc --include_imports --descriptor_set_out=order.pb order.proto`
+    #[serde(default)]
+    pub descriptor_sets: Vec<String>,
+    /// idl中的服务全名 -> 注册中心中的服务名, 如 "order.OrderService" = "volo-boot-order.rpc"
+    #[serde(default)]
+    pub services: HashMap<String, String>,
+}
+
+/// redis配置, 只用到了限流需要的命令, 兼容redis协议的服务都可以使用
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct RedisConfig {
+    /// 如 127.0.0.1:6379
+    pub addr: String,
+    pub password: Option<String>,
+    pub db: Option<u32>,
+    /// 连接数, 默认8
+    pub pool_size: Option<usize>,
+    /// 单次请求超时时间, 超时后使用本地限流, 默认50ms
+    pub timeout_ms: Option<u64>,
+    /// key前缀, 默认 volo-boot:rate:
+    pub key_prefix: Option<String>,
+}
+
+/// 管理接口的访问控制: 来源地址在 `allow_ips` 中, 或者请求头 `Authorization: Bearer <token>` 与 `token` 一致
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct AdminConfig {
+    pub token: Option<String>,
+    /// 允许访问的来源地址(ip或网段), 默认只允许本机
+    pub allow_ips: Option<Vec<String>>,
+}
+
+impl AdminConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.token.as_ref().is_some_and(|x| x.trim().is_empty()) {
+            return Err(anyhow!("token is empty"));
+        }
+        for ip in self.allow_ips.iter().flatten() {
+            IpNet::parse(ip)?;
+        }
+        Ok(())
+    }
+}
+
+/// OpenAPI文档, 由idl中的路由注解和路由表生成
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct OpenApiConfig {
+    /// 文档路径, 默认 /openapi.json
+    pub path: Option<String>,
+    /// Swagger UI路径, 默认 /swagger-ui
+    pub ui_path: Option<String>,
+    /// 文档标题, 默认 volo-boot-api
+    pub title: Option<String>,
+    /// Swagger UI静态资源的SRI哈希, 如 sha384-xxx, 必须是 `SWAGGER_UI_VERSION` 版本的文件的哈希
+    pub ui_css_integrity: Option<String>,
+    pub ui_js_integrity: Option<String>,
+}
+
+impl OpenApiConfig {
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or(DEFAULT_OPENAPI_PATH)
+    }
+
+    pub fn ui_path(&self) -> &str {
+        self.ui_path.as_deref().unwrap_or(DEFAULT_SWAGGER_UI_PATH)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for path in [self.path(), self.ui_path()] {
+            if !path.starts_with('/') || path.contains('{') {
+                return Err(anyhow!(
+                    "path {} must start with / and have no path variable",
+                    path
+                ));
+            }
+        }
+        if self.path() == self.ui_path() {
+            return Err(anyhow!("path and ui_path are both {}", self.path()));
+        }
+        for (name, integrity) in [
+            ("ui_css_integrity", &self.ui_css_integrity),
+            ("ui_js_integrity", &self.ui_js_integrity),
+        ] {
+            let integrity = integrity
+                .as_deref()
+                .ok_or_else(|| anyhow!("{} is required", name))?;
+            let hash = ["sha256-", "sha384-", "sha512-"]
+                .iter()
+                .find_map(|x| integrity.strip_prefix(x))
+                .ok_or_else(|| anyhow!("{} must start with sha256-/sha384-/sha512-", name))?;
+            let base64 = |c: char| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '=');
+            if hash.is_empty() || !hash.chars().all(base64) {
+                return Err(anyhow!("{} is not a base64 hash: {}", name, integrity));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 跨域规则, 预检请求(OPTIONS)由网关直接返回, 不转发到rpc服务
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CorsConfig {
+    /// 允许的Origin, 如 https://example.com; `*` 表示所有
+    #[serde(default)]
+    pub allow_origins: Vec<String>,
+    /// 允许的Origin正则, 如 ^https://.*\.example\.com$, 与 `allow_origins` 满足一个即可
+    pub allow_origin_regex: Option<String>,
+    /// 允许的方法, 默认 GET, POST, PUT, DELETE, PATCH
+    pub allow_methods: Option<Vec<String>>,
+    /// 允许的请求头, `*` 表示预检请求中的所有请求头; 默认 `*`
+    pub allow_headers: Option<Vec<String>>,
+    /// 允许浏览器读取的响应头
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    /// 是否允许携带cookie, 为true时 `allow_origins` 不能为 `*`
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// 预检结果的缓存时间(秒)
+    pub max_age: Option<u64>,
+}
+
+/// 跨域规则支持的http方法
+const CORS_METHODS: [&str; 7] = ["GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS"];
+
+impl CorsConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.allow_origins.is_empty() && self.allow_origin_regex.is_none() {
+            return Err(anyhow!("allow_origins or allow_origin_regex is required"));
+        }
+        if self.allow_credentials && self.allow_origins.iter().any(|x| x == "*") {
+            return Err(anyhow!(
+                "allow_origins can not be * when allow_credentials is true"
+            ));
+        }
+        if let Some(regex) = self.allow_origin_regex.as_deref() {
+            Regex::new(regex).map_err(|e| anyhow!("invalid allow_origin_regex: {}", e))?;
+        }
+        for m in self.allow_methods.iter().flatten() {
+            if !CORS_METHODS.contains(&m.to_uppercase().as_str()) {
+                return Err(anyhow!("invalid method: {}", m));
+            }
+        }
+        for name in self
+            .allow_headers
+            .iter()
+            .flatten()
+            .chain(self.expose_headers.iter())
+            .filter(|x| *x != "*")
+        {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| anyhow!("invalid header name: {}", name))?;
+        }
+        Ok(())
+    }
+}
+
 /// 服务发现配置
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ServerDiscover {
-    pub nacos: NacosConfig,
+    /// 注册中心类型, 不配置时默认为nacos
+    #[serde(default)]
+    pub kind: SdKind,
+    pub nacos: Option<NacosConfig>,
+    /// 静态服务发现, 用于没有nacos的环境(本地开发、CI)
+    #[serde(rename = "static")]
+    pub static_sd: Option<StaticConfig>,
+}
+
+impl ServerDiscover {
+    /// 当前注册中心下本服务的名称, 同时也是动态配置的data_id
+    pub fn service_name(&self) -> Option<&str> {
+        match self.kind {
+            SdKind::Nacos => self.nacos.as_ref().map(|c| c.service_name.as_str()),
+            SdKind::Static => self.static_sd.as_ref().map(|c| c.service_name.as_str()),
+        }
+    }
+}
+
+/// 注册中心类型
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SdKind {
+    #[default]
+    Nacos,
+    Static,
 }
 
 /// nacos
@@ -27,15 +504,330 @@ pub struct NacosConfig {
     pub service_name: String,
 }
 
+/// 静态服务发现
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct StaticConfig {
+    pub service_name: String,
+    /// 服务名 -> 实例地址列表, 如 "volo-boot-user.rpc" = ["127.0.0.1:8081"]
+    #[serde(default)]
+    pub services: HashMap<String, Vec<String>>,
+    /// 实例文件(toml/yaml), 包含地址、权重和标签, 文件变化后自动重新加载; 配置后忽略 `services`
+    pub instance_file: Option<String>,
+    /// 本地动态配置文件(yaml), 替代nacos配置中心
+    pub config_file: Option<String>,
+}
+
 /// 从nacos中获取的配置
 #[derive(Default, Debug, Clone, Deserialize, Serialize)]
 pub struct DynamicConfig {
     pub url_rate: Option<Vec<UrlRateConfig>>,
+    /// 多条限流规则匹配同一个请求时: all(默认, 都消耗令牌) | first(只使用优先级最高的一条)
+    pub url_rate_mode: Option<String>,
+    /// 泳道(金丝雀)路由规则
+    pub lane: Option<Vec<LaneRule>>,
+    /// 并发限制规则
+    pub concurrency: Option<Vec<ConcurrencyConfig>>,
+    /// 熔断规则, 覆盖 `[clients.<service>.circuit_breaker]` 中同一个服务的规则
+    pub circuit_breaker: Option<Vec<CircuitBreakerConfig>>,
+    /// 跨域规则, 整体覆盖 `[cors]`
+    pub cors: Option<CorsConfig>,
 }
 
 #[derive(Default, Debug, Clone, Deserialize, Serialize)]
 pub struct UrlRateConfig {
     pub url: String,
     pub method: Vec<String>,
+    /// 每个窗口补充的令牌数
     pub rate: u64,
+    /// 窗口大小(秒), 默认1秒
+    pub window: Option<u64>,
+    /// 令牌桶容量, 即允许的突发请求数, 默认等于 rate
+    pub burst: Option<u64>,
+    /// 限流key: global(默认, 所有请求共用) | ip | header:<name> | query:<name>
+    pub key: Option<String>,
+    /// 限流状态的存储: memory(默认, 每个网关实例单独计数) | redis(所有网关实例共享)
+    pub backend: Option<String>,
+    /// 优先级, 数值越小越先匹配, 默认0; 相同时按配置顺序
+    pub priority: Option<i32>,
+}
+
+impl DynamicConfig {
+    /// 校验所有规则, 任何一条不合法时整个配置都不生效
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(mode) = self.url_rate_mode.as_deref() {
+            MatchMode::parse(mode)?;
+        }
+        for urc in self.url_rate.iter().flatten() {
+            urc.validate()
+                .map_err(|e| anyhow!("url_rate {}: {}", urc.url, e))?;
+        }
+        for cc in self.concurrency.iter().flatten() {
+            cc.validate()
+                .map_err(|e| anyhow!("concurrency {:?}: {}", cc, e))?;
+        }
+        for cb in self.circuit_breaker.iter().flatten() {
+            if cb.service.is_empty() {
+                return Err(anyhow!("circuit_breaker requires service"));
+            }
+            cb.validate()
+                .map_err(|e| anyhow!("circuit_breaker {}: {}", cb.service, e))?;
+        }
+        if let Some(cors) = self.cors.as_ref() {
+            cors.validate().map_err(|e| anyhow!("cors: {}", e))?;
+        }
+        let mut percent: HashMap<&str, u32> = HashMap::new();
+        for rule in self.lane.iter().flatten() {
+            if rule.service.is_empty() || rule.lane.is_empty() {
+                return Err(anyhow!("lane rule requires service and lane: {:?}", rule));
+            }
+            let total = percent.entry(rule.service.as_str()).or_default();
+            *total += rule.percent;
+            if *total > 100 {
+                return Err(anyhow!(
+                    "lane percent of {} must <= 100, got {}",
+                    rule.service,
+                    total
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 限流和并发限制规则支持的http方法
+const RATE_LIMIT_METHODS: [&str; 7] = ["get", "post", "put", "delete", "patch", "head", "options"];
+
+impl UrlRateConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        Regex::new(self.url.as_str())?;
+        if self.method.is_empty() {
+            return Err(anyhow!("method is empty"));
+        }
+        for m in self.method.iter() {
+            if !RATE_LIMIT_METHODS.contains(&m.to_lowercase().as_str()) {
+                return Err(anyhow!("invalid method: {}", m));
+            }
+        }
+        if self.rate == 0 {
+            return Err(anyhow!("rate must > 0"));
+        }
+        if self.window == Some(0) {
+            return Err(anyhow!("window must > 0"));
+        }
+        if self.burst == Some(0) {
+            return Err(anyhow!("burst must > 0"));
+        }
+        RateLimitKey::parse(self.key.as_deref().unwrap_or("global"))?;
+        LimiterBackend::parse(self.backend.as_deref().unwrap_or("memory"))?;
+        Ok(())
+    }
+}
+
+/// 并发限制规则, `url` 和 `service` 二选一
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ConcurrencyConfig {
+    /// path正则, 限制匹配的请求同时处理的数量
+    pub url: Option<String>,
+    /// 限制的http方法, 为空时不区分方法
+    #[serde(default)]
+    pub method: Vec<String>,
+    /// 上游rpc服务名, 限制同时调用该服务的请求数
+    pub service: Option<String>,
+    /// 并发上限; 自适应时在 [min_limit, limit] 之间调整, 初始为limit
+    pub limit: u64,
+    /// 自适应调整的下限, 默认1
+    pub min_limit: Option<u64>,
+    /// 上限的调整方式: fixed(默认) | aimd | gradient
+    pub adaptive: Option<String>,
+    /// aimd的目标延迟(毫秒), 超过时减小并发上限
+    pub latency_ms: Option<u64>,
+}
+
+impl ConcurrencyConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match (self.url.as_deref(), self.service.as_deref()) {
+            (Some(url), None) => {
+                Regex::new(url)?;
+            }
+            (None, Some(service)) if !service.is_empty() => {}
+            _ => return Err(anyhow!("exactly one of url and service is required")),
+        }
+        for m in self.method.iter() {
+            if !RATE_LIMIT_METHODS.contains(&m.to_lowercase().as_str()) {
+                return Err(anyhow!("invalid method: {}", m));
+            }
+        }
+        if self.limit == 0 {
+            return Err(anyhow!("limit must > 0"));
+        }
+        if self.min_limit.is_some_and(|x| x == 0 || x > self.limit) {
+            return Err(anyhow!("min_limit must in [1, limit]"));
+        }
+        let adaptive = AdaptiveMode::parse(self.adaptive.as_deref().unwrap_or("fixed"))?;
+        if adaptive == AdaptiveMode::Aimd && self.latency_ms.unwrap_or_default() == 0 {
+            return Err(anyhow!("aimd requires latency_ms > 0"));
+        }
+        Ok(())
+    }
+}
+
+/// 熔断规则, 错误率和慢调用比例任一超过阈值时熔断
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CircuitBreakerConfig {
+    /// 注册中心中的服务名
+    #[serde(default)]
+    pub service: String,
+    /// 是否同时按实例熔断, 某个实例故障时只熔断该实例
+    pub per_instance: Option<bool>,
+    /// 统计窗口(秒), 默认10
+    pub window: Option<u64>,
+    /// 窗口内请求数少于这个值时不熔断, 默认20
+    pub min_requests: Option<u64>,
+    /// 错误率阈值(百分比), 默认50; 只统计不可用、超时等下游故障
+    pub error_rate: Option<u32>,
+    /// 超过这个时间(毫秒)的请求为慢调用, 不设置时不统计
+    pub slow_call_ms: Option<u64>,
+    /// 慢调用比例阈值(百分比), 默认50
+    pub slow_call_rate: Option<u32>,
+    /// 熔断时间(秒), 之后放行探测请求, 默认5
+    pub break_duration: Option<u64>,
+    /// 半开状态的探测请求数, 全部成功后恢复, 默认3
+    pub half_open_requests: Option<u32>,
+    /// 熔断时各个方法返回的数据, key为idl中的方法名, 没有设置的方法返回503
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub fallback: HashMap<String, serde_json::Value>,
+}
+
+impl CircuitBreakerConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (field, v) in [
+            ("error_rate", self.error_rate),
+            ("slow_call_rate", self.slow_call_rate),
+        ] {
+            if v.is_some_and(|x| x == 0 || x > 100) {
+                return Err(anyhow!("{} must in 1..=100", field));
+            }
+        }
+        if self.window == Some(0) {
+            return Err(anyhow!("window must > 0"));
+        }
+        if self.slow_call_ms == Some(0) {
+            return Err(anyhow!("slow_call_ms must > 0"));
+        }
+        if self.half_open_requests == Some(0) {
+            return Err(anyhow!("half_open_requests must > 0"));
+        }
+        Ok(())
+    }
+}
+
+/// rpc方法的重试策略, 未配置的项使用服务级别的配置或默认值
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RetryPolicyConfig {
+    /// 是否重试, 不设置时只有幂等方法重试; 非幂等方法需要显式设置为true
+    pub enabled: Option<bool>,
+    /// 最多调用次数, 包括第一次调用, 默认2
+    pub max_attempts: Option<u32>,
+    /// 第一次重试前的退避时间(毫秒), 之后每次翻倍, 默认10
+    pub backoff_ms: Option<u64>,
+    /// 最大退避时间(毫秒), 默认200
+    pub max_backoff_ms: Option<u64>,
+    /// 可以重试的grpc状态码, 如 unavailable、deadline_exceeded, 默认只有unavailable
+    pub codes: Option<Vec<String>>,
+}
+
+impl RetryPolicyConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.max_attempts == Some(0) {
+            return Err(anyhow!("max_attempts must > 0"));
+        }
+        if self.max_attempts.is_some_and(|x| x > MAX_RETRY_ATTEMPTS) {
+            return Err(anyhow!("max_attempts must <= {}", MAX_RETRY_ATTEMPTS));
+        }
+        for code in self.codes.iter().flatten() {
+            retry::parse_code(code)?;
+        }
+        Ok(())
+    }
+}
+
+/// 重试次数过多时即使有预算也会放大下游的压力
+const MAX_RETRY_ATTEMPTS: u32 = 5;
+
+/// 服务的重试策略, `methods` 中按方法名覆盖服务级别的配置
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RetryConfig {
+    #[serde(flatten)]
+    pub policy: RetryPolicyConfig,
+    #[serde(default)]
+    pub methods: HashMap<String, RetryPolicyConfig>,
+}
+
+impl RetryConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.policy.validate()?;
+        for (method, policy) in self.methods.iter() {
+            policy
+                .validate()
+                .map_err(|e| anyhow!("method {}: {}", method, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// 重试预算: 最近10秒内的重试数不超过请求数的 `ratio`, 另外每秒固定允许 `min_retries_per_sec` 次,
+/// 下游整体故障时限制重试放大的流量
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RetryBudgetConfig {
+    /// 重试数和请求数的比例, 默认0.1
+    pub ratio: Option<f64>,
+    /// 请求量很小时也允许的重试数, 默认10
+    pub min_retries_per_sec: Option<u64>,
+}
+
+impl RetryBudgetConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.ratio.is_some_and(|x| !(0.0..=1.0).contains(&x)) {
+            return Err(anyhow!("ratio must in 0.0..=1.0"));
+        }
+        Ok(())
+    }
+}
+
+/// 对冲请求: 第一个请求超过延迟还没有返回时向另一个实例发送第二个请求, 使用先成功的结果并取消另一个;
+/// 对冲请求和重试共享重试预算
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct HedgeConfig {
+    /// 开启对冲的方法名, 非幂等方法会被忽略
+    pub methods: Vec<String>,
+    /// 固定的对冲延迟(毫秒), 同时配置 `percentile` 时作为样本不足时的延迟
+    pub delay_ms: Option<u64>,
+    /// 按方法最近耗时的分位数(百分比, 如95)作为对冲延迟
+    pub percentile: Option<f64>,
+}
+
+impl HedgeConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.methods.is_empty() {
+            return Err(anyhow!("methods is empty"));
+        }
+        if self.delay_ms.is_none() && self.percentile.is_none() {
+            return Err(anyhow!("delay_ms or percentile is required"));
+        }
+        if self.percentile.is_some_and(|x| x <= 0.0 || x >= 100.0) {
+            return Err(anyhow!("percentile must in (0, 100)"));
+        }
+        Ok(())
+    }
+}
+
+/// 泳道规则: 请求头 `x-lane` 指定泳道时直接走该泳道, 否则按 `percent` 随机分流;
+/// 泳道内的实例通过实例元数据 `lane` 标识, 没有 `lane` 的实例属于基准泳道
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct LaneRule {
+    pub service: String,
+    pub lane: String,
+    /// 0-100, 同一个服务多条规则的百分比累加
+    #[serde(default)]
+    pub percent: u32,
 }
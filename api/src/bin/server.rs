@@ -1,13 +1,22 @@
-use api::rate_limiter::{init_limiter, RateLimiterConfigListener, DEFAULT_GROUP};
-use api::{consts, router, svc_discover, ServiceContext};
+use api::app_config::AppConfig;
+use api::circuit_breaker::init_circuit_breaker;
+use api::client;
+use api::controller::admin_controller::init_admin;
+use api::cors::init_cors;
+use api::descriptor::ProtoRegistry;
+use api::dynamic_config::{init_dynamic_config, DynamicConfigListener};
+use api::hash_key::init_hash_key;
+use api::hedge::init_hedge;
+use api::lane::LaneDiscover;
+use api::rate_limiter::{init_redis_limiter, init_trusted_proxies, DEFAULT_GROUP};
+use api::registry::{Registry, ServiceRegistry};
+use api::retry::init_retry;
+use api::{router, ServiceContext};
 use clap::Parser;
-use order::order::OrderServiceClient;
 use pd_rs_common::load_config::LoadConfig;
-use pd_rs_common::svc::nacos::NacosNamingAndConfigData;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::{net::SocketAddr, time::Duration};
-use user::user::UserServiceClient;
 use volo_http::{
     context::ServerContext,
     http::StatusCode,
@@ -36,23 +45,32 @@ async fn main() {
 
     // 加载配置
     let config_file_path = args.config;
-    let app_config = api::app_config::AppConfig::load_toml(config_file_path.as_str()).unwrap();
-    let app_config_clone = app_config.clone();
-
-    // 注册服务
-    let nacos_config = app_config.sd.nacos;
-
-    // 获取nacos naming service
-    let nacos_naming_data = Arc::new(
-        NacosNamingAndConfigData::new(
-            nacos_config.server_addr,
-            nacos_config.namespace.unwrap_or("".to_string()),
-            nacos_config.service_name.clone(),
-            nacos_config.username,
-            nacos_config.password,
-        )
-        .unwrap(),
-    );
+    let app_config = AppConfig::load_toml(config_file_path.as_str()).unwrap();
+    let proto = match app_config.validate() {
+        Ok(proto) => proto,
+        Err(e) => {
+            tracing::error!("invalid config {}: {}", config_file_path, e);
+            panic!("invalid config {}: {}", config_file_path, e);
+        }
+    };
+    init_hash_key(&app_config.hash_key).unwrap();
+    init_trusted_proxies(&app_config.trusted_proxies).unwrap();
+    if let Some(redis) = app_config.redis.as_ref() {
+        init_redis_limiter(redis);
+    }
+    init_circuit_breaker(app_config.circuit_breaker_rules());
+    init_cors(app_config.cors.clone());
+    init_admin(app_config.admin.clone()).unwrap();
+    init_retry(app_config.retry_rules(), app_config.retry_budget.clone());
+    init_hedge(app_config.hedge_rules());
+
+    // 根据配置选择注册中心
+    let registry = Registry::from_config(&app_config.sd).unwrap();
+    let service_name = app_config
+        .sd
+        .service_name()
+        .expect("service_name is required in [sd]")
+        .to_string();
 
     // 指标抓取端口
     let metric_port = app_config.metric_port.unwrap_or(app_config.port);
@@ -60,12 +78,10 @@ async fn main() {
     if metric_port != app_config.port {
         need_standard_metrics = true;
         // 注册用于抓取指标的服务实例
-        let _metrics_nacos_svc_inst = nacos_naming_data
-            .register_service(
-                nacos_config.service_name.clone() + "_metrics",
-                metric_port as i32,
-                None,
-                None,
+        let _metrics_svc_inst = registry
+            .register(
+                service_name.clone() + "_metrics",
+                metric_port,
                 Default::default(),
             )
             .await;
@@ -76,39 +92,39 @@ async fn main() {
     // 如果metrics端口是单独的则, 需要屏蔽服务端口的指标抓取
     if need_standard_metrics {
         meta_map.insert("disable_metrics".to_string(), "true".to_string());
+    } else {
+        tracing::warn!("metric_port is not set, admin api is disabled");
+    }
+    if let Err(e) = registry
+        .register(service_name.clone(), app_config.port, meta_map)
+        .await
+    {
+        tracing::error!("register service {} err: {}", service_name, e);
     }
-    let _nacos_svc_inst = nacos_naming_data
-        .register_service(
-            nacos_config.service_name.clone(),
-            app_config.port as i32,
-            None,
-            None,
-            meta_map,
-        )
-        .await;
 
     // 订阅rpc服务
-    let service_context =
-        subscribe_service(nacos_naming_data.clone(), app_config.subscribe_service).await;
+    let service_context = subscribe_service(&registry, &app_config, proto)
+        .await
+        .unwrap();
 
-    // 获取配置
-    init_limiter(nacos_naming_data.clone(), app_config_clone.clone()).await;
+    // 获取配置(限流和泳道规则)
+    init_dynamic_config(&registry, service_name.clone()).await;
 
     // 监听配置
-    let rate_limiter_lis = Arc::new(RateLimiterConfigListener {
-        data_id: nacos_config.service_name.clone(),
+    let dynamic_config_lis = Arc::new(DynamicConfigListener {
+        data_id: service_name.clone(),
     });
-    match nacos_naming_data
-        .add_config_listener(
-            nacos_config.service_name.clone(),
+    match registry
+        .add_listener(
+            service_name.clone(),
             DEFAULT_GROUP.to_string(),
-            rate_limiter_lis,
+            dynamic_config_lis,
         )
         .await
     {
         Ok(_) => tracing::info!(
             "add config listener: {} {}",
-            nacos_config.service_name,
+            service_name,
             DEFAULT_GROUP.to_string()
         ),
         Err(e) => tracing::error!("add config listener err: {}", e),
@@ -130,10 +146,15 @@ async fn main() {
 
     // 启动http服务
     let biz_app = Router::new()
-        .merge(router::build_biz_router(
-            service_context,
-            !need_standard_metrics,
-        ))
+        .merge(
+            router::build_biz_router(
+                service_context,
+                &app_config.routes,
+                app_config.openapi.as_ref(),
+                !need_standard_metrics,
+            )
+            .unwrap(),
+        )
         .layer(TimeoutLayer::new(
             Duration::from_secs(app_config.timeout.unwrap_or(10)),
             timeout_handler,
@@ -148,79 +169,45 @@ async fn main() {
 }
 
 async fn subscribe_service(
-    nacos_naming_data: Arc<NacosNamingAndConfigData>,
-    service_names: Vec<String>,
-) -> ServiceContext {
+    registry: &Registry,
+    app_config: &AppConfig,
+    proto: ProtoRegistry,
+) -> anyhow::Result<ServiceContext> {
     let mut ret: ServiceContext = Default::default();
+    let service_names = &app_config.subscribe_service;
+
+    // 启动时校验配置已经加载过idl
+    ret.proto = Some(Arc::new(proto));
 
     if !service_names.is_empty() {
-        let discover = svc_discover::NacosDiscover::new(nacos_naming_data.clone());
+        // 按泳道过滤实例
+        let discover = LaneDiscover::new(registry.discover());
 
         tracing::info!("subscribe services: {}", service_names.join(", "));
-        for sub_svc in service_names {
-            // 构建grpc客户端
-            let mut svc_name = sub_svc.clone();
-            let mut rpc_client_num = 1;
-            let split = sub_svc.split(":").collect::<Vec<&str>>();
-            if split.len() >= 2 {
-                svc_name = split[0].trim().to_string();
-                rpc_client_num = split[1].trim().parse::<i32>().unwrap();
-            }
-
-
-            let sub_ret = nacos_naming_data.subscribe_service(svc_name.clone()).await;
+        for svc_name in service_names {
+            let sub_ret = registry.subscribe(svc_name.clone()).await;
             match sub_ret {
                 Ok(_) => {
-                    tracing::info!("subscribe service: {} success.", sub_svc.clone());
+                    tracing::info!("subscribe service: {} success.", svc_name);
                 }
                 Err(e) => {
-                    tracing::error!("subscribe service: {} field, error: {}", sub_svc.clone(), e);
+                    tracing::error!("subscribe service: {} field, error: {}", svc_name, e);
                 }
             }
 
-            for _ in 0..rpc_client_num {
-                match svc_name.as_str() {
-                    consts::RPC_USER_KEY => {
-                        let user_client: UserServiceClient =
-                            user::user::UserServiceClientBuilder::new(svc_name.clone())
-                                .discover(discover.clone())
-                                // .load_balance(volo::loadbalance::random::WeightedRandomBalance::new())
-                                .load_balance(
-                                    volo::loadbalance::consistent_hash::ConsistentHashBalance::new(
-                                        Default::default(),
-                                    ),
-                                )
-                                // .http2_max_frame_size(32 * 1024u32)
-                                // .http2_init_stream_window_size(8 * 1024 * 1024u32)
-                                // .http2_init_connection_window_size(16 * 1024 * 1024u32)
-                                // .http2_adaptive_window(false)
-                                // .http2_keepalive_while_idle(true)
-                                .http2_max_concurrent_reset_streams(50usize)
-                                // .connect_timeout(Duration::from_millis(500))
-                                .build();
-                        ret.rpc_cli_user.push(user_client);
-                    }
-                    consts::RPC_ORDER_KEY => {
-                        let order_client: OrderServiceClient =
-                            order::order::OrderServiceClientBuilder::new(svc_name.clone())
-                                .discover(discover.clone())
-                                .load_balance(volo::loadbalance::random::WeightedRandomBalance::new())
-                                // .http2_max_frame_size(32 * 1024u32)
-                                // .http2_init_stream_window_size(8 * 1024 * 1024u32)
-                                // .http2_init_connection_window_size(16 * 1024 * 1024u32)
-                                // .http2_adaptive_window(false)
-                                // .http2_keepalive_while_idle(true)
-                                .http2_max_concurrent_reset_streams(50usize)
-                                // .connect_timeout(Duration::from_millis(500))
-                                .build();
-                        ret.rpc_cli_order.push(order_client);
-                    }
-                    _ => {}
-                }
+            // 构建grpc客户端
+            let options = app_config.client_options(svc_name)?;
+            tracing::info!("build rpc clients for {}: {:?}", svc_name, options);
+            if client::default_options(svc_name).is_some() {
+                ret.clients
+                    .register(svc_name.as_str(), discover.clone(), &options)?;
+            } else if app_config.is_proto_service(svc_name) {
+                // 没有生成代码的服务使用通用grpc客户端调用
+                ret.clients
+                    .register_raw(svc_name.as_str(), discover.clone(), &options);
             }
-
         }
     }
 
-    ret
+    Ok(ret)
 }
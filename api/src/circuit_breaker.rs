@@ -0,0 +1,504 @@
+use crate::app_config::{CircuitBreakerConfig, DynamicConfig};
+use crate::consts::REJECTED_METADATA_KEY;
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use volo::context::Context;
+use volo::discovery::Instance;
+use volo_grpc::context::ClientContext;
+use volo_grpc::metadata::{MetadataMap, MetadataValue};
+use volo_grpc::{Code, Status};
+
+lazy_static! {
+    /// 服务名 -> 服务级熔断器
+    static ref SERVICE_BREAKERS: ArcSwap<HashMap<String, Arc<CircuitBreaker>>> = ArcSwap::default();
+    /// 服务名@实例地址 -> 实例级熔断器, 第一次调用实例时创建, 规则变更时清空
+    static ref INSTANCE_BREAKERS: DashMap<String, Arc<CircuitBreaker>> = DashMap::new();
+}
+
+/// `[clients.<service>.circuit_breaker]` 中的熔断规则, 动态配置中同一个服务的规则会覆盖它
+static STATIC_RULES: OnceLock<Vec<CircuitBreakerConfig>> = OnceLock::new();
+
+/// 统计窗口分成的桶数, 窗口随时间滑动
+const BUCKETS: usize = 10;
+/// 半开状态的探测请求最少等待这么久, 之后认为探测请求已经丢失
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    /// 熔断中, 直接拒绝请求
+    Open,
+    /// 熔断时间结束, 放行少量探测请求
+    HalfOpen,
+}
+
+impl BreakerState {
+    fn gauge(&self) -> f64 {
+        match self {
+            BreakerState::Closed => 0.0,
+            BreakerState::Open => 1.0,
+            BreakerState::HalfOpen => 2.0,
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Bucket {
+    /// 桶对应的时间片序号
+    slot: u64,
+    total: u64,
+    failures: u64,
+    slow: u64,
+}
+
+struct Inner {
+    state: BreakerState,
+    /// 进入当前状态的时间
+    since: Instant,
+    buckets: [Bucket; BUCKETS],
+    /// 半开状态已放行和已成功的探测请求数
+    probes: u32,
+    probe_successes: u32,
+}
+
+/// 按错误率和慢调用比例熔断: 窗口内请求数达到 `min_requests` 且任一比例超过阈值时打开,
+/// `break_duration` 后半开, 探测请求全部成功则关闭, 任一失败则重新打开
+pub struct CircuitBreaker {
+    name: String,
+    config: CircuitBreakerConfig,
+    start: Instant,
+    bucket_width: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: String, config: CircuitBreakerConfig) -> Self {
+        let window = Duration::from_secs(config.window.unwrap_or(10).max(1));
+        let now = Instant::now();
+        metrics::gauge!("circuit_breaker_state", "name" => name.clone())
+            .set(BreakerState::Closed.gauge());
+        Self {
+            name,
+            config,
+            start: now,
+            bucket_width: window / BUCKETS as u32,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                since: now,
+                buckets: [Bucket::default(); BUCKETS],
+                probes: 0,
+                probe_successes: 0,
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn config(&self) -> &CircuitBreakerConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.lock().state
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn break_duration(&self) -> Duration {
+        Duration::from_secs(self.config.break_duration.unwrap_or(5))
+    }
+
+    fn half_open_requests(&self) -> u32 {
+        self.config.half_open_requests.unwrap_or(3).max(1)
+    }
+
+    /// 是否放行这次请求, 放行后需要调用 `record` 记录结果
+    pub fn allow(&self) -> bool {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open if now - inner.since < self.break_duration() => {
+                metrics::counter!("circuit_breaker_rejected_total", "name" => self.name.clone())
+                    .increment(1);
+                false
+            }
+            BreakerState::Open => {
+                self.transition(&mut inner, BreakerState::HalfOpen, now);
+                inner.probes = 1;
+                true
+            }
+            BreakerState::HalfOpen => {
+                // 探测请求被取消时不会有结果, 超时后重新开始探测
+                if now - inner.since >= self.break_duration().max(PROBE_TIMEOUT) {
+                    self.transition(&mut inner, BreakerState::HalfOpen, now);
+                }
+                if inner.probes < self.half_open_requests() {
+                    inner.probes += 1;
+                    true
+                } else {
+                    metrics::counter!("circuit_breaker_rejected_total", "name" => self.name.clone())
+                        .increment(1);
+                    false
+                }
+            }
+        }
+    }
+
+    /// 记录一次请求的结果
+    pub fn record(&self, ok: bool, latency: Duration) {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        match inner.state {
+            BreakerState::HalfOpen if !ok => self.transition(&mut inner, BreakerState::Open, now),
+            BreakerState::HalfOpen => {
+                inner.probe_successes += 1;
+                if inner.probe_successes >= self.half_open_requests() {
+                    self.transition(&mut inner, BreakerState::Closed, now);
+                }
+            }
+            BreakerState::Open => {}
+            BreakerState::Closed => {
+                let slow = self
+                    .config
+                    .slow_call_ms
+                    .is_some_and(|x| latency >= Duration::from_millis(x));
+                let slot = self.slot(now);
+                let bucket = &mut inner.buckets[slot as usize % BUCKETS];
+                if bucket.slot != slot {
+                    *bucket = Bucket {
+                        slot,
+                        ..Default::default()
+                    };
+                }
+                bucket.total += 1;
+                bucket.failures += !ok as u64;
+                bucket.slow += slow as u64;
+                if self.should_open(&inner.buckets, slot) {
+                    self.transition(&mut inner, BreakerState::Open, now);
+                }
+            }
+        }
+    }
+
+    fn slot(&self, now: Instant) -> u64 {
+        ((now - self.start).as_millis() / self.bucket_width.as_millis().max(1)) as u64
+    }
+
+    fn should_open(&self, buckets: &[Bucket; BUCKETS], slot: u64) -> bool {
+        let (total, failures, slow) = buckets
+            .iter()
+            .filter(|b| b.slot + (BUCKETS as u64) > slot)
+            .fold((0, 0, 0), |(t, f, s), b| {
+                (t + b.total, f + b.failures, s + b.slow)
+            });
+        if total == 0 || total < self.config.min_requests.unwrap_or(20) {
+            return false;
+        }
+        let error_rate = self.config.error_rate.unwrap_or(50) as u64;
+        let slow_call_rate = self.config.slow_call_rate.unwrap_or(50) as u64;
+        failures * 100 >= error_rate * total || slow * 100 >= slow_call_rate * total
+    }
+
+    fn transition(&self, inner: &mut Inner, state: BreakerState, now: Instant) {
+        if inner.state != state {
+            tracing::warn!(
+                "circuit breaker {} {:?} -> {:?}",
+                self.name,
+                inner.state,
+                state
+            );
+            metrics::gauge!("circuit_breaker_state", "name" => self.name.clone())
+                .set(state.gauge());
+        }
+        inner.state = state;
+        inner.since = now;
+        inner.probes = 0;
+        inner.probe_successes = 0;
+        if state == BreakerState::Closed {
+            inner.buckets = [Bucket::default(); BUCKETS];
+        }
+    }
+}
+
+/// 下游故障才计入熔断, 参数错误、数据不存在等业务错误和网关自己拒绝的请求不计入
+pub fn is_failure(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable
+            | Code::DeadlineExceeded
+            | Code::ResourceExhausted
+            | Code::Internal
+            | Code::Unknown
+    ) && !is_rejected(status)
+}
+
+/// 网关自己拒绝的请求, 没有到达上游: 返回 `Unavailable`, 并在metadata中标记
+pub fn rejected(message: String) -> Status {
+    let mut metadata = MetadataMap::new();
+    metadata.insert(REJECTED_METADATA_KEY, MetadataValue::from_static("true"));
+    Status::with_metadata(Code::Unavailable, message, metadata)
+}
+
+/// 是否是网关自己拒绝的请求
+pub fn is_rejected(status: &Status) -> bool {
+    status.metadata().contains_key(REJECTED_METADATA_KEY)
+}
+
+/// 启动时设置 `[clients]` 中的熔断规则
+pub fn init_circuit_breaker(rules: Vec<CircuitBreakerConfig>) {
+    let _ = STATIC_RULES.set(rules);
+    reset_circuit_breaker(DynamicConfig::default());
+}
+
+/// 合并静态规则和动态规则后重建服务级熔断器, 配置没有变化的熔断器保留状态
+pub fn reset_circuit_breaker(dynamic_config: DynamicConfig) {
+    let mut rules: HashMap<String, CircuitBreakerConfig> = STATIC_RULES
+        .get()
+        .into_iter()
+        .flatten()
+        .chain(dynamic_config.circuit_breaker.iter().flatten())
+        .map(|x| (x.service.clone(), x.clone()))
+        .collect();
+
+    let old = SERVICE_BREAKERS.load();
+    let breakers: HashMap<String, Arc<CircuitBreaker>> = rules
+        .drain()
+        .map(|(service, config)| {
+            let breaker = match old.get(service.as_str()) {
+                Some(x) if x.config == config => x.clone(),
+                _ => {
+                    tracing::info!("reset circuit breaker for {}: {:?}", service, config);
+                    Arc::new(CircuitBreaker::new(service.clone(), config))
+                }
+            };
+            (service, breaker)
+        })
+        .collect();
+    SERVICE_BREAKERS.store(Arc::new(breakers));
+    INSTANCE_BREAKERS.clear();
+}
+
+/// 实例下线后删除它的实例级熔断器
+pub fn evict_instances(service: &str, removed: &[Arc<Instance>]) {
+    for instance in removed {
+        let name = format!("{}@{}", service, instance.address);
+        if INSTANCE_BREAKERS.remove(name.as_str()).is_some() {
+            tracing::info!("evict circuit breaker {}", name);
+        }
+    }
+}
+
+/// 按服务熔断, 熔断时返回该方法配置的降级数据, 没有降级数据时返回 `Unavailable`
+pub async fn call_service<F>(
+    service: &str,
+    method: &str,
+    call: F,
+) -> Result<serde_json::Value, Status>
+where
+    F: Future<Output = Result<serde_json::Value, Status>>,
+{
+    let breaker = SERVICE_BREAKERS.load().get(service).cloned();
+    match breaker {
+        Some(breaker) => breaker.call_method(method, call).await,
+        None => call.await,
+    }
+}
+
+impl CircuitBreaker {
+    /// 经过熔断器调用服务的一个方法, 熔断时返回该方法的降级数据
+    async fn call_method<F>(&self, method: &str, call: F) -> Result<serde_json::Value, Status>
+    where
+        F: Future<Output = Result<serde_json::Value, Status>>,
+    {
+        if !self.allow() {
+            return match self.config.fallback.get(method) {
+                Some(fallback) => Ok(fallback.clone()),
+                None => Err(rejected(format!("{} is circuit broken", self.name()))),
+            };
+        }
+
+        let start = Instant::now();
+        let result = call.await;
+        let ok = result.as_ref().map_or_else(|e| !is_failure(e), |_| true);
+        self.record(ok, start.elapsed());
+        result
+    }
+}
+
+/// 实例级熔断, 作为grpc客户端的inner layer, 负载均衡选出实例之后执行
+#[derive(Clone, Copy, Default)]
+pub struct InstanceBreakerLayer;
+
+impl<S> volo::Layer<S> for InstanceBreakerLayer {
+    type Service = InstanceBreakerService<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        InstanceBreakerService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct InstanceBreakerService<S> {
+    inner: S,
+}
+
+impl<S, Req> volo::Service<ClientContext, Req> for InstanceBreakerService<S>
+where
+    S: volo::Service<ClientContext, Req, Error = Status> + Send + Sync,
+    Req: Send,
+{
+    type Response = S::Response;
+    type Error = Status;
+
+    async fn call(&self, cx: &mut ClientContext, req: Req) -> Result<Self::Response, Status> {
+        let Some(breaker) = instance_breaker(cx) else {
+            return self.inner.call(cx, req).await;
+        };
+        if !breaker.allow() {
+            return Err(rejected(format!("{} is circuit broken", breaker.name())));
+        }
+
+        let start = Instant::now();
+        let result = self.inner.call(cx, req).await;
+        let ok = result.as_ref().map_or_else(|e| !is_failure(e), |_| true);
+        breaker.record(ok, start.elapsed());
+        result
+    }
+}
+
+/// 服务开启了 `per_instance` 时返回调用实例的熔断器
+fn instance_breaker(cx: &ClientContext) -> Option<Arc<CircuitBreaker>> {
+    let callee = cx.rpc_info().callee();
+    let service = callee.service_name_ref();
+    let config = SERVICE_BREAKERS
+        .load()
+        .get(service)
+        .filter(|x| x.config.per_instance.unwrap_or(false))
+        .map(|x| x.config.clone())?;
+    let name = format!("{}@{}", service, callee.address()?);
+    let breaker = INSTANCE_BREAKERS
+        .entry(name.clone())
+        .or_insert_with(|| Arc::new(CircuitBreaker::new(name, config)))
+        .clone();
+    Some(breaker)
+}
+
+#[cfg(test)]
+mod circuit_breaker_test {
+    use super::*;
+
+    fn breaker(break_duration: u64) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test".to_string(),
+            CircuitBreakerConfig {
+                service: "test".to_string(),
+                min_requests: Some(4),
+                error_rate: Some(50),
+                slow_call_ms: Some(100),
+                slow_call_rate: Some(80),
+                break_duration: Some(break_duration),
+                half_open_requests: Some(2),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn open_on_error_rate() {
+        let breaker = breaker(60);
+        for ok in [true, true, false] {
+            assert!(breaker.allow());
+            breaker.record(ok, Duration::from_millis(1));
+        }
+        // 请求数不足 min_requests 时不熔断
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record(false, Duration::from_millis(1));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn open_on_slow_calls() {
+        let breaker = breaker(60);
+        for _ in 0..4 {
+            breaker.record(true, Duration::from_millis(200));
+        }
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn half_open_probe() {
+        let breaker = breaker(0);
+        for _ in 0..4 {
+            breaker.record(false, Duration::from_millis(1));
+        }
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // 熔断时间结束后放行 half_open_requests 个探测请求
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.record(true, Duration::from_millis(1));
+        assert!(breaker.allow());
+        breaker.record(true, Duration::from_millis(1));
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        for _ in 0..4 {
+            breaker.record(false, Duration::from_millis(1));
+        }
+        assert!(breaker.allow());
+        breaker.record(false, Duration::from_millis(1));
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn rejected_is_not_failure() {
+        assert!(is_failure(&Status::unavailable("down")));
+        assert!(!is_failure(&rejected("test is overloaded".to_string())));
+        assert!(!is_failure(&Status::not_found("user")));
+    }
+
+    #[tokio::test]
+    async fn fallback_per_method() {
+        let breaker = CircuitBreaker::new(
+            "fallback".to_string(),
+            CircuitBreakerConfig {
+                service: "fallback".to_string(),
+                min_requests: Some(1),
+                break_duration: Some(60),
+                fallback: HashMap::from([("GetUser".to_string(), serde_json::json!({ "id": 0 }))]),
+                ..Default::default()
+            },
+        );
+        breaker.record(false, Duration::from_millis(1));
+
+        let call = async { Ok(serde_json::json!({ "id": 1 })) };
+        let resp = breaker.call_method("GetUser", call).await.unwrap();
+        assert_eq!(resp, serde_json::json!({ "id": 0 }));
+        let call = async { Ok(serde_json::json!({ "id": 1 })) };
+        let e = breaker.call_method("GetOrder", call).await.unwrap_err();
+        assert!(is_rejected(&e));
+    }
+
+    #[test]
+    fn evict_removed_instances() {
+        let instance = Arc::new(Instance {
+            address: volo::net::Address::Ip("127.0.0.1:8081".parse().unwrap()),
+            weight: 100,
+            tags: Default::default(),
+        });
+        let name = format!("evict@{}", instance.address);
+        INSTANCE_BREAKERS.insert(name.clone(), Arc::new(breaker(60)));
+        evict_instances("evict", &[instance]);
+        assert!(!INSTANCE_BREAKERS.contains_key(name.as_str()));
+    }
+}
@@ -0,0 +1,532 @@
+use crate::app_config::ClientConfig;
+use crate::circuit_breaker;
+use crate::controller::extract::Validator;
+use crate::lane::LaneDiscover;
+use crate::svc_discover::SvcDiscover;
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use volo_grpc::Status;
+
+pub mod order;
+pub mod pool;
+pub mod raw;
+pub mod user;
+
+pub use order::OrderRpc;
+pub use pool::{evict_instances, ClientPool, PoolOptions, PooledClient, StreamLimitLayer};
+pub use raw::RawClient;
+pub use user::UserRpc;
+
+/// 构建rpc客户端用的服务发现
+pub type ClientDiscover = LaneDiscover<SvcDiscover>;
+
+/// 负载均衡策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadBalanceKind {
+    /// 一致性hash, 需要在METAINFO中设置 `RequestHash`
+    ConsistentHash,
+    WeightedRandom,
+}
+
+/// 构建rpc客户端的参数, 未设置的项使用volo的默认值
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub load_balance: LoadBalanceKind,
+    /// 最少客户端数量
+    pub pool_size: usize,
+    /// 最多客户端数量, 所有客户端都接近 `max_concurrent_streams` 时才会新建
+    pub max_pool_size: usize,
+    /// 每个客户端(http2连接)允许的最大并发stream数
+    pub max_concurrent_streams: usize,
+    pub connect_timeout: Option<Duration>,
+    pub rpc_timeout: Option<Duration>,
+    pub http2_max_frame_size: Option<u32>,
+    pub http2_init_stream_window_size: Option<u32>,
+    pub http2_init_connection_window_size: Option<u32>,
+    pub http2_adaptive_window: Option<bool>,
+    pub http2_keepalive_interval: Option<Duration>,
+    pub http2_keepalive_timeout: Option<Duration>,
+    pub http2_keepalive_while_idle: Option<bool>,
+    pub http2_max_concurrent_reset_streams: usize,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            load_balance: LoadBalanceKind::WeightedRandom,
+            pool_size: 1,
+            max_pool_size: 16,
+            max_concurrent_streams: 100,
+            connect_timeout: None,
+            rpc_timeout: None,
+            http2_max_frame_size: None,
+            http2_init_stream_window_size: None,
+            http2_init_connection_window_size: None,
+            http2_adaptive_window: None,
+            http2_keepalive_interval: None,
+            http2_keepalive_timeout: None,
+            http2_keepalive_while_idle: None,
+            http2_max_concurrent_reset_streams: 50,
+        }
+    }
+}
+
+/// http2 帧大小范围, 参考 RFC 9113 SETTINGS_MAX_FRAME_SIZE
+const HTTP2_MIN_FRAME_SIZE: u32 = 16 * 1024;
+const HTTP2_MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024 - 1;
+/// http2 流控窗口上限, 参考 RFC 9113 SETTINGS_INITIAL_WINDOW_SIZE
+const HTTP2_MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+const MAX_POOL_SIZE: usize = 1024;
+
+impl ClientOptions {
+    /// 在服务默认参数的基础上应用 `[clients.<service>]` 配置, 配置不合法时返回错误
+    pub fn from_config(service_name: &str, config: &ClientConfig) -> anyhow::Result<Self> {
+        let options = default_options(service_name)
+            .ok_or_else(|| anyhow!("unknown rpc service in [clients]: {}", service_name))?;
+        Self::apply_config(options, service_name, config)
+    }
+
+    /// 在给定参数的基础上应用 `[clients.<service>]` 配置, 用于没有生成代码、只有idl的服务
+    pub fn apply_config(
+        mut options: ClientOptions,
+        service_name: &str,
+        config: &ClientConfig,
+    ) -> anyhow::Result<Self> {
+        let invalid = |field: &str, reason: String| {
+            anyhow!(
+                "invalid [clients.\"{}\"] {}: {}",
+                service_name,
+                field,
+                reason
+            )
+        };
+
+        if let Some(lb) = config.load_balance.as_deref() {
+            options.load_balance = match lb {
+                "consistent_hash" => LoadBalanceKind::ConsistentHash,
+                "weighted_random" => LoadBalanceKind::WeightedRandom,
+                _ => {
+                    return Err(invalid(
+                        "load_balance",
+                        format!("{}, expect consistent_hash or weighted_random", lb),
+                    ))
+                }
+            };
+        }
+        if let Some(pool_size) = config.pool_size {
+            if pool_size == 0 || pool_size > MAX_POOL_SIZE {
+                return Err(invalid(
+                    "pool_size",
+                    format!("{}, expect 1..={}", pool_size, MAX_POOL_SIZE),
+                ));
+            }
+            options.pool_size = pool_size;
+        }
+        if let Some(max_pool_size) = config.max_pool_size {
+            if max_pool_size == 0 || max_pool_size > MAX_POOL_SIZE {
+                return Err(invalid(
+                    "max_pool_size",
+                    format!("{}, expect 1..={}", max_pool_size, MAX_POOL_SIZE),
+                ));
+            }
+            options.max_pool_size = max_pool_size;
+        }
+        if options.max_pool_size < options.pool_size {
+            return Err(invalid(
+                "max_pool_size",
+                format!(
+                    "{} is less than pool_size {}",
+                    options.max_pool_size, options.pool_size
+                ),
+            ));
+        }
+        if let Some(max_concurrent_streams) = config.max_concurrent_streams {
+            if max_concurrent_streams == 0 {
+                return Err(invalid(
+                    "max_concurrent_streams",
+                    "must be greater than 0".to_string(),
+                ));
+            }
+            options.max_concurrent_streams = max_concurrent_streams;
+        }
+
+        let millis = |field: &str, v: Option<u64>| -> anyhow::Result<Option<Duration>> {
+            match v {
+                Some(0) => Err(invalid(field, "must be greater than 0".to_string())),
+                Some(v) => Ok(Some(Duration::from_millis(v))),
+                None => Ok(None),
+            }
+        };
+        options.connect_timeout =
+            millis("connect_timeout_ms", config.connect_timeout_ms)?.or(options.connect_timeout);
+        options.rpc_timeout =
+            millis("request_timeout_ms", config.request_timeout_ms)?.or(options.rpc_timeout);
+        options.http2_keepalive_interval = millis(
+            "http2_keepalive_interval_ms",
+            config.http2_keepalive_interval_ms,
+        )?
+        .or(options.http2_keepalive_interval);
+        options.http2_keepalive_timeout = millis(
+            "http2_keepalive_timeout_ms",
+            config.http2_keepalive_timeout_ms,
+        )?
+        .or(options.http2_keepalive_timeout);
+
+        if let Some(v) = config.http2_max_frame_size {
+            if !(HTTP2_MIN_FRAME_SIZE..=HTTP2_MAX_FRAME_SIZE).contains(&v) {
+                return Err(invalid(
+                    "http2_max_frame_size",
+                    format!(
+                        "{}, expect {}..={}",
+                        v, HTTP2_MIN_FRAME_SIZE, HTTP2_MAX_FRAME_SIZE
+                    ),
+                ));
+            }
+            options.http2_max_frame_size = Some(v);
+        }
+        for (field, v) in [
+            (
+                "http2_init_stream_window_size",
+                config.http2_init_stream_window_size,
+            ),
+            (
+                "http2_init_connection_window_size",
+                config.http2_init_connection_window_size,
+            ),
+        ] {
+            if let Some(v) = v {
+                if v == 0 || v > HTTP2_MAX_WINDOW_SIZE {
+                    return Err(invalid(
+                        field,
+                        format!("{}, expect 1..={}", v, HTTP2_MAX_WINDOW_SIZE),
+                    ));
+                }
+            }
+        }
+        if config.http2_init_stream_window_size.is_some() {
+            options.http2_init_stream_window_size = config.http2_init_stream_window_size;
+        }
+        if config.http2_init_connection_window_size.is_some() {
+            options.http2_init_connection_window_size = config.http2_init_connection_window_size;
+        }
+        if config.http2_adaptive_window.is_some() {
+            options.http2_adaptive_window = config.http2_adaptive_window;
+        }
+        if config.http2_keepalive_while_idle.is_some() {
+            options.http2_keepalive_while_idle = config.http2_keepalive_while_idle;
+        }
+        if let Some(v) = config.http2_max_concurrent_reset_streams {
+            options.http2_max_concurrent_reset_streams = v;
+        }
+
+        Ok(options)
+    }
+}
+
+/// 一个网关可以调用的rpc服务
+pub trait RpcService: 'static {
+    /// 注册中心中的服务名
+    const NAME: &'static str;
+    /// idl中的服务全名, 如 user.UserService
+    const PROTO_SERVICE: &'static str;
+
+    type Client: Clone + Send + Sync + 'static;
+
+    /// 该服务的默认客户端参数
+    fn default_options() -> ClientOptions {
+        ClientOptions::default()
+    }
+
+    fn build_client(discover: ClientDiscover, options: &ClientOptions) -> Self::Client;
+
+    /// 可以通过json调用的rpc方法, 用于路由表
+    fn methods() -> Vec<RpcMethod> {
+        vec![]
+    }
+}
+
+pub type RpcFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<serde_json::Value, Status>> + Send + 'a>>;
+
+/// 可以通过json调用的rpc方法
+#[derive(Clone, Copy)]
+pub struct RpcMethod {
+    /// idl中的方法名, 如 GetUser
+    pub name: &'static str,
+    /// 幂等方法失败时默认重试
+    pub idempotent: bool,
+    pub call: for<'a> fn(&'a ClientRegistry, serde_json::Value) -> RpcFuture<'a>,
+    /// 调用前校验json格式的rpc请求, 路由和grpc转发都会执行
+    pub validate: Option<fn(&serde_json::Value, &mut Validator)>,
+}
+
+/// 把json反序列化为rpc请求, 调用rpc方法后再把响应序列化为json, 依赖volo-gen中 `SerdePlugin` 生成的serde实现
+pub async fn call_json<S, Req, Resp, F, Fut>(
+    clients: &ClientRegistry,
+    params: serde_json::Value,
+    f: F,
+) -> Result<serde_json::Value, Status>
+where
+    S: RpcService,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    F: FnOnce(PooledClient<S::Client>, Req) -> Fut,
+    Fut: Future<Output = Result<volo_grpc::Response<Resp>, Status>>,
+{
+    let req: Req = serde_json::from_value(params)
+        .map_err(|e| Status::invalid_argument(format!("invalid request: {}", e)))?;
+    let rpc_cli = clients
+        .acquire::<S>()
+        .ok_or_else(|| circuit_breaker::rejected(format!("{} is not subscribed", S::NAME)))?;
+    let resp = f(rpc_cli, req).await?;
+    serde_json::to_value(resp.into_inner()).map_err(|e| Status::internal(e.to_string()))
+}
+
+type ErasedClients = Arc<dyn Any + Send + Sync>;
+type BuildFn = fn(ClientDiscover, &ClientOptions) -> ErasedClients;
+
+/// 类型擦除后的rpc服务
+struct ServiceEntry {
+    build: BuildFn,
+    proto_service: &'static str,
+    default_options: fn() -> ClientOptions,
+    methods: fn() -> Vec<RpcMethod>,
+}
+
+fn entry<S: RpcService>() -> ServiceEntry {
+    ServiceEntry {
+        build: build_erased::<S>,
+        proto_service: S::PROTO_SERVICE,
+        default_options: S::default_options,
+        methods: S::methods,
+    }
+}
+
+fn pool_options(options: &ClientOptions) -> PoolOptions {
+    PoolOptions {
+        min_size: options.pool_size,
+        max_size: options.max_pool_size,
+        max_concurrent_streams: options.max_concurrent_streams,
+    }
+}
+
+fn build_erased<S: RpcService>(discover: ClientDiscover, options: &ClientOptions) -> ErasedClients {
+    let pool_options = pool_options(options);
+    let options = options.clone();
+    let pool: Arc<ClientPool<S::Client>> = ClientPool::new(pool_options, move || {
+        S::build_client(discover.clone(), &options)
+    });
+    Arc::new(pool)
+}
+
+/// 网关可以调用的所有rpc服务, 新增rpc服务时实现 `RpcService` 并在这里加一行
+fn known_services() -> HashMap<&'static str, ServiceEntry> {
+    HashMap::from([
+        (UserRpc::NAME, entry::<UserRpc>()),
+        (OrderRpc::NAME, entry::<OrderRpc>()),
+    ])
+}
+
+/// 服务的默认客户端参数, 未知服务返回None
+pub fn default_options(service_name: &str) -> Option<ClientOptions> {
+    known_services()
+        .get(service_name)
+        .map(|x| (x.default_options)())
+}
+
+/// 有生成代码的服务: idl中的服务全名 -> 注册中心中的服务名
+pub fn proto_services() -> HashMap<&'static str, &'static str> {
+    known_services()
+        .into_iter()
+        .map(|(name, x)| (x.proto_service, name))
+        .collect()
+}
+
+/// 查找服务的rpc方法
+pub fn find_method(service_name: &str, method_name: &str) -> Option<RpcMethod> {
+    let services = known_services();
+    let methods = (services.get(service_name)?.methods)();
+    methods.into_iter().find(|x| x.name == method_name)
+}
+
+/// rpc客户端注册表, key为服务名
+#[derive(Clone, Default)]
+pub struct ClientRegistry {
+    clients: Arc<HashMap<String, ErasedClients>>,
+    /// 只有idl的服务, 使用通用grpc客户端
+    raw_clients: Arc<HashMap<String, Arc<ClientPool<RawClient>>>>,
+}
+
+impl ClientRegistry {
+    /// 为订阅的服务构建客户端, 未知的服务名返回错误
+    pub fn register(
+        &mut self,
+        service_name: &str,
+        discover: ClientDiscover,
+        options: &ClientOptions,
+    ) -> anyhow::Result<()> {
+        let services = known_services();
+        let Some(service) = services.get(service_name) else {
+            let mut known: Vec<&str> = services.keys().copied().collect();
+            known.sort();
+            return Err(anyhow!(
+                "unknown rpc service: {}, known services: {}",
+                service_name,
+                known.join(", ")
+            ));
+        };
+        Arc::make_mut(&mut self.clients)
+            .insert(service_name.to_string(), (service.build)(discover, options));
+        Ok(())
+    }
+
+    /// 为只有idl的服务构建通用grpc客户端
+    pub fn register_raw(
+        &mut self,
+        service_name: &str,
+        discover: ClientDiscover,
+        options: &ClientOptions,
+    ) {
+        let pool_options = pool_options(options);
+        let name = service_name.to_string();
+        let options = options.clone();
+        let pool = ClientPool::new(pool_options, move || {
+            raw::build_raw_client(name.as_str(), discover.clone(), &options)
+        });
+        Arc::make_mut(&mut self.raw_clients).insert(service_name.to_string(), pool);
+    }
+
+    /// 从只有idl的服务的客户端池中取出客户端, 服务未订阅时返回None
+    pub fn acquire_raw(&self, service_name: &str) -> Option<PooledClient<RawClient>> {
+        self.raw_clients
+            .get(service_name)
+            .map(|pool| pool.acquire())
+    }
+
+    /// 获取某个服务的客户端池
+    pub fn pool<S: RpcService>(&self) -> Option<&Arc<ClientPool<S::Client>>> {
+        self.clients
+            .get(S::NAME)?
+            .downcast_ref::<Arc<ClientPool<S::Client>>>()
+    }
+
+    /// 从服务的客户端池中取出在途请求最少的客户端, 服务未订阅时返回None
+    pub fn acquire<S: RpcService>(&self) -> Option<PooledClient<S::Client>> {
+        self.pool::<S>().map(|pool| pool.acquire())
+    }
+}
+
+/// 按 `ClientOptions` 构建grpc客户端, 每个rpc服务的 ClientBuilder 类型都不同, 所以用宏
+macro_rules! build_grpc_client {
+    ($builder:expr, $discover:expr, $options:expr) => {{
+        let options: &$crate::client::ClientOptions = $options;
+        let mut builder = $builder
+            .discover($discover)
+            // 负载均衡选出实例之后按实例熔断
+            .layer_inner($crate::circuit_breaker::InstanceBreakerLayer)
+            // 记录调用过的实例, 重试时换一个实例
+            .layer_inner($crate::retry::TriedInstanceLayer)
+            // 每个实例连接上的并发stream数不超过 max_concurrent_streams, 与池的扩容阈值对应
+            .layer_inner($crate::client::StreamLimitLayer::new(
+                options.max_concurrent_streams,
+            ))
+            .http2_max_concurrent_reset_streams(options.http2_max_concurrent_reset_streams);
+        if let Some(t) = options.connect_timeout {
+            builder = builder.connect_timeout(t);
+        }
+        if let Some(t) = options.rpc_timeout {
+            builder = builder.rpc_timeout(Some(t));
+        }
+        if let Some(v) = options.http2_max_frame_size {
+            builder = builder.http2_max_frame_size(v);
+        }
+        if let Some(v) = options.http2_init_stream_window_size {
+            builder = builder.http2_init_stream_window_size(v);
+        }
+        if let Some(v) = options.http2_init_connection_window_size {
+            builder = builder.http2_init_connection_window_size(v);
+        }
+        if let Some(v) = options.http2_adaptive_window {
+            builder = builder.http2_adaptive_window(v);
+        }
+        if let Some(v) = options.http2_keepalive_interval {
+            builder = builder.http2_keepalive_interval(v);
+        }
+        if let Some(v) = options.http2_keepalive_timeout {
+            builder = builder.http2_keepalive_timeout(v);
+        }
+        if let Some(v) = options.http2_keepalive_while_idle {
+            builder = builder.http2_keepalive_while_idle(v);
+        }
+        match options.load_balance {
+            $crate::client::LoadBalanceKind::ConsistentHash => builder
+                .load_balance($crate::retry::RetryBalance::new(
+                    volo::loadbalance::consistent_hash::ConsistentHashBalance::new(
+                        Default::default(),
+                    ),
+                ))
+                .build(),
+            $crate::client::LoadBalanceKind::WeightedRandom => builder
+                .load_balance($crate::retry::RetryBalance::new(
+                    volo::loadbalance::random::WeightedRandomBalance::new(),
+                ))
+                .build(),
+        }
+    }};
+}
+pub(crate) use build_grpc_client;
+
+#[cfg(test)]
+mod client_options_test {
+    use super::*;
+
+    #[test]
+    fn client_options_from_config() {
+        let options = ClientOptions::from_config(
+            OrderRpc::NAME,
+            &ClientConfig {
+                load_balance: Some("consistent_hash".to_string()),
+                pool_size: Some(4),
+                max_pool_size: Some(8),
+                connect_timeout_ms: Some(500),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(options.load_balance, LoadBalanceKind::ConsistentHash);
+        assert_eq!(options.pool_size, 4);
+        assert_eq!(options.max_pool_size, 8);
+        assert_eq!(options.connect_timeout, Some(Duration::from_millis(500)));
+
+        let invalid = [
+            ClientConfig {
+                load_balance: Some("round_robin".to_string()),
+                ..Default::default()
+            },
+            ClientConfig {
+                pool_size: Some(0),
+                ..Default::default()
+            },
+            ClientConfig {
+                pool_size: Some(32),
+                max_pool_size: Some(8),
+                ..Default::default()
+            },
+            ClientConfig {
+                http2_max_frame_size: Some(1024),
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(ClientOptions::from_config(OrderRpc::NAME, &config).is_err());
+        }
+        assert!(ClientOptions::from_config("unknown.rpc", &Default::default()).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+use crate::client::{
+    build_grpc_client, call_json, ClientDiscover, ClientOptions, RpcMethod, RpcService,
+};
+use order::order::{
+    GetOrderRequest, GetRandomReq, Order, OrderServiceClient, OrderServiceClientBuilder, RandomResp,
+};
+
+/// order rpc服务
+pub struct OrderRpc;
+
+impl RpcService for OrderRpc {
+    const NAME: &'static str = "volo-boot-order.rpc";
+    const PROTO_SERVICE: &'static str = "order.OrderService";
+
+    type Client = OrderServiceClient;
+
+    fn build_client(discover: ClientDiscover, options: &ClientOptions) -> Self::Client {
+        build_grpc_client!(
+            OrderServiceClientBuilder::new(Self::NAME),
+            discover,
+            options
+        )
+    }
+
+    fn methods() -> Vec<RpcMethod> {
+        vec![
+            RpcMethod {
+                name: "GetOrder",
+                idempotent: true,
+                call: |clients, params| {
+                    Box::pin(call_json::<Self, GetOrderRequest, Order, _, _>(
+                        clients,
+                        params,
+                        |cli, req| async move { cli.get_order(req).await },
+                    ))
+                },
+                validate: Some(|params, v| {
+                    let present = |field: &str| params.get(field).is_some_and(|x| !x.is_null());
+                    v.any_required(&[("id", present("id")), ("user_id", present("user_id"))]);
+                }),
+            },
+            RpcMethod {
+                name: "GetRandom",
+                idempotent: true,
+                call: |clients, params| {
+                    Box::pin(call_json::<Self, GetRandomReq, RandomResp, _, _>(
+                        clients,
+                        params,
+                        |cli, req| async move { cli.get_random(req).await },
+                    ))
+                },
+                validate: None,
+            },
+        ]
+    }
+}
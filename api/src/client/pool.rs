@@ -0,0 +1,365 @@
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+use volo::context::Context;
+use volo::discovery::Instance;
+use volo_grpc::context::ClientContext;
+use volo_grpc::Status;
+
+/// `服务名@实例地址` -> 该实例连接上的stream许可
+type StreamPermits = DashMap<String, Arc<Semaphore>>;
+
+lazy_static! {
+    /// 所有客户端的stream许可表, 实例下线时从中删除它的许可
+    static ref STREAM_PERMITS: Mutex<Vec<Weak<StreamPermits>>> = Mutex::new(vec![]);
+}
+
+/// 在途请求数达到 `max_concurrent_streams` 的这个比例时认为客户端接近饱和
+const HIGH_WATERMARK: f64 = 0.8;
+/// 空闲回收检查间隔
+const SHRINK_INTERVAL: Duration = Duration::from_secs(30);
+/// 超过这个时间没有请求的客户端会被回收(保留 `min_size` 个)
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 连接池参数
+#[derive(Debug, Clone, Copy)]
+pub struct PoolOptions {
+    /// 最少客户端数量
+    pub min_size: usize,
+    /// 最多客户端数量
+    pub max_size: usize,
+    /// 每个客户端(http2连接)允许的最大并发stream数, 由 `StreamLimitLayer` 限制在客户端上,
+    /// 不应超过rpc服务端的 `http2_max_concurrent_streams`
+    pub max_concurrent_streams: usize,
+}
+
+struct Slot<C> {
+    client: C,
+    in_flight: AtomicUsize,
+    last_used_ms: AtomicU64,
+}
+
+/// rpc客户端池: 每个客户端维护自己到各个实例的http2连接, 池中记录每个客户端的在途请求(stream)数,
+/// 每次选择在途请求最少的客户端; 所有客户端都接近 `max_concurrent_streams` 时新建客户端,
+/// 空闲的客户端会被定期回收, 连接数随负载变化而不是固定的数量
+pub struct ClientPool<C> {
+    slots: RwLock<Vec<Arc<Slot<C>>>>,
+    factory: Box<dyn Fn() -> C + Send + Sync>,
+    options: PoolOptions,
+}
+
+impl<C> ClientPool<C>
+where
+    C: Send + Sync + 'static,
+{
+    pub fn new<F>(options: PoolOptions, factory: F) -> Arc<Self>
+    where
+        F: Fn() -> C + Send + Sync + 'static,
+    {
+        let min_size = options.min_size.max(1);
+        let slots = (0..min_size)
+            .map(|_| Arc::new(Slot::new(factory())))
+            .collect();
+        let pool = Arc::new(Self {
+            slots: RwLock::new(slots),
+            factory: Box::new(factory),
+            options: PoolOptions {
+                min_size,
+                max_size: options.max_size.max(min_size),
+                max_concurrent_streams: options.max_concurrent_streams.max(1),
+            },
+        });
+
+        // 只持有弱引用, 池被释放后回收任务自动退出
+        if min_size < pool.options.max_size {
+            let weak = Arc::downgrade(&pool);
+            tokio::spawn(shrink_loop(weak));
+        }
+
+        pool
+    }
+
+    /// 选择在途请求最少的客户端, 返回的 `PooledClient` 释放时在途请求数减一
+    pub fn acquire(&self) -> PooledClient<C> {
+        let slot = {
+            let slots = self.slots.read().unwrap();
+            let slot = least_loaded(&slots);
+            if self.saturated(&slot) && slots.len() < self.options.max_size {
+                drop(slots);
+                self.grow().unwrap_or(slot)
+            } else {
+                slot
+            }
+        };
+
+        slot.in_flight.fetch_add(1, Ordering::AcqRel);
+        slot.last_used_ms.store(now_ms(), Ordering::Relaxed);
+        PooledClient { slot }
+    }
+
+    /// 当前客户端数量
+    pub fn size(&self) -> usize {
+        self.slots.read().unwrap().len()
+    }
+
+    /// 所有客户端的在途请求总数
+    pub fn in_flight(&self) -> usize {
+        self.slots
+            .read()
+            .unwrap()
+            .iter()
+            .map(|x| x.in_flight.load(Ordering::Acquire))
+            .sum()
+    }
+
+    fn saturated(&self, slot: &Slot<C>) -> bool {
+        let in_flight = slot.in_flight.load(Ordering::Acquire) as f64;
+        in_flight >= self.options.max_concurrent_streams as f64 * HIGH_WATERMARK
+    }
+
+    /// 新建一个客户端, 拿到写锁后再检查一次, 避免并发时重复创建
+    fn grow(&self) -> Option<Arc<Slot<C>>> {
+        let mut slots = self.slots.write().unwrap();
+        let slot = least_loaded(&slots);
+        if !self.saturated(&slot) {
+            return Some(slot);
+        }
+        if slots.len() >= self.options.max_size {
+            return None;
+        }
+
+        let slot = Arc::new(Slot::new((self.factory)()));
+        slots.push(slot.clone());
+        tracing::info!("rpc client pool grow to {}", slots.len());
+        Some(slot)
+    }
+
+    /// 回收空闲的客户端, 至少保留 `min_size` 个
+    fn shrink(&self) {
+        let mut slots = self.slots.write().unwrap();
+        if slots.len() <= self.options.min_size {
+            return;
+        }
+
+        let deadline = now_ms().saturating_sub(IDLE_TIMEOUT.as_millis() as u64);
+        let mut removable = slots.len() - self.options.min_size;
+        slots.retain(|x| {
+            let idle = x.in_flight.load(Ordering::Acquire) == 0
+                && x.last_used_ms.load(Ordering::Relaxed) < deadline
+                // 还有 PooledClient 引用时不回收
+                && Arc::strong_count(x) == 1;
+            if idle && removable > 0 {
+                removable -= 1;
+                false
+            } else {
+                true
+            }
+        });
+        tracing::debug!("rpc client pool size: {}", slots.len());
+    }
+}
+
+async fn shrink_loop<C>(pool: Weak<ClientPool<C>>)
+where
+    C: Send + Sync + 'static,
+{
+    let mut ticker = tokio::time::interval(SHRINK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let Some(pool) = pool.upgrade() else {
+            break;
+        };
+        pool.shrink();
+    }
+}
+
+impl<C> Slot<C> {
+    fn new(client: C) -> Self {
+        Self {
+            client,
+            in_flight: AtomicUsize::new(0),
+            last_used_ms: AtomicU64::new(now_ms()),
+        }
+    }
+}
+
+fn least_loaded<C>(slots: &[Arc<Slot<C>>]) -> Arc<Slot<C>> {
+    slots
+        .iter()
+        .min_by_key(|x| x.in_flight.load(Ordering::Acquire))
+        .cloned()
+        .expect("client pool is never empty")
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// 从池中取出的客户端, 释放时在途请求数减一
+pub struct PooledClient<C> {
+    slot: Arc<Slot<C>>,
+}
+
+impl<C> Deref for PooledClient<C> {
+    type Target = C;
+
+    fn deref(&self) -> &Self::Target {
+        &self.slot.client
+    }
+}
+
+impl<C> Drop for PooledClient<C> {
+    fn drop(&mut self) {
+        self.slot.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// 限制客户端到每个实例的http2连接上的并发stream数, 作为grpc客户端的inner layer,
+/// 超过 `max_concurrent_streams` 的请求排队等待, 和服务端SETTINGS帧限制stream数时的行为一致,
+/// 保证池的扩容阈值与连接上实际的stream上限对应
+#[derive(Clone, Copy)]
+pub struct StreamLimitLayer {
+    max_concurrent_streams: usize,
+}
+
+impl StreamLimitLayer {
+    pub fn new(max_concurrent_streams: usize) -> Self {
+        Self {
+            max_concurrent_streams: max_concurrent_streams.max(1),
+        }
+    }
+}
+
+impl<S> volo::Layer<S> for StreamLimitLayer {
+    type Service = StreamLimitService<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        let streams: Arc<StreamPermits> = Default::default();
+        let mut permits = STREAM_PERMITS.lock().unwrap();
+        // 池回收的客户端的许可表已经释放
+        permits.retain(|x| x.strong_count() > 0);
+        permits.push(Arc::downgrade(&streams));
+        drop(permits);
+        StreamLimitService {
+            inner,
+            max_concurrent_streams: self.max_concurrent_streams,
+            streams,
+        }
+    }
+}
+
+/// 实例下线后删除各个客户端上它的stream许可
+pub fn evict_instances(service: &str, removed: &[Arc<Instance>]) {
+    if removed.is_empty() {
+        return;
+    }
+    let names: Vec<String> = removed
+        .iter()
+        .map(|x| format!("{}@{}", service, x.address))
+        .collect();
+    STREAM_PERMITS.lock().unwrap().retain(|streams| {
+        let Some(streams) = streams.upgrade() else {
+            return false;
+        };
+        for name in names.iter() {
+            streams.remove(name.as_str());
+        }
+        true
+    });
+}
+
+#[derive(Clone)]
+pub struct StreamLimitService<S> {
+    inner: S,
+    max_concurrent_streams: usize,
+    streams: Arc<StreamPermits>,
+}
+
+impl<S, Req> volo::Service<ClientContext, Req> for StreamLimitService<S>
+where
+    S: volo::Service<ClientContext, Req, Error = Status> + Send + Sync,
+    Req: Send,
+{
+    type Response = S::Response;
+    type Error = Status;
+
+    async fn call(&self, cx: &mut ClientContext, req: Req) -> Result<Self::Response, Status> {
+        let callee = cx.rpc_info().callee();
+        let Some(address) = callee.address() else {
+            return self.inner.call(cx, req).await;
+        };
+        let semaphore = self
+            .streams
+            .entry(format!("{}@{}", callee.service_name_ref(), address))
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_concurrent_streams)))
+            .clone();
+        let _permit = semaphore
+            .acquire_owned()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        self.inner.call(cx, req).await
+    }
+}
+
+#[cfg(test)]
+mod client_pool_test {
+    use super::*;
+
+    #[tokio::test]
+    async fn grow_when_saturated() {
+        let pool = ClientPool::new(
+            PoolOptions {
+                min_size: 1,
+                max_size: 2,
+                max_concurrent_streams: 2,
+            },
+            || (),
+        );
+
+        let c1 = pool.acquire();
+        let c2 = pool.acquire();
+        assert_eq!(pool.size(), 1);
+        // 第一个客户端已经有2个在途请求, 新建客户端
+        let c3 = pool.acquire();
+        assert_eq!(pool.size(), 2);
+        assert_eq!(pool.in_flight(), 3);
+
+        // 达到上限后不再新建
+        let _c4 = pool.acquire();
+        let _c5 = pool.acquire();
+        assert_eq!(pool.size(), 2);
+
+        drop((c1, c2, c3));
+        assert_eq!(pool.in_flight(), 2);
+    }
+
+    #[test]
+    fn evict_stream_permits() {
+        let layer = StreamLimitLayer::new(4);
+        let service = volo::Layer::layer(layer, ());
+        let instance = Arc::new(Instance {
+            address: volo::net::Address::Ip("127.0.0.1:18081".parse().unwrap()),
+            weight: 10,
+            tags: Default::default(),
+        });
+        let name = format!("evict-stream@{}", instance.address);
+        service
+            .streams
+            .insert(name.clone(), Arc::new(Semaphore::new(4)));
+        service.streams.insert(
+            "other@127.0.0.1:18082".to_string(),
+            Arc::new(Semaphore::new(4)),
+        );
+
+        evict_instances("evict-stream", &[instance]);
+        assert!(!service.streams.contains_key(name.as_str()));
+        assert!(service.streams.contains_key("other@127.0.0.1:18082"));
+    }
+}
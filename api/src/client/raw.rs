@@ -0,0 +1,131 @@
+use crate::client::{build_grpc_client, ClientDiscover, ClientOptions};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use pilota::pb::encoding::{decode_varint, encode_key, encode_varint, DecodeContext, WireType};
+use pilota::pb::{DecodeError, Message};
+use pilota::LinkedBytes;
+use volo::layer::Identity;
+use volo::service::BoxCloneService;
+use volo_grpc::context::ClientContext;
+use volo_grpc::{Request, Response, Status};
+
+/// 已经编码好的protobuf消息, 用于没有生成代码的rpc方法: 请求和响应由 `descriptor` 模块按idl编解码,
+/// 这里只负责原样发送和接收字节; 解码时逐个字段追加到缓冲区
+#[derive(Debug, Default, Clone)]
+pub struct RawMessage(pub BytesMut);
+
+impl Message for RawMessage {
+    fn encode_raw(&self, buf: &mut LinkedBytes) {
+        buf.put_slice(self.0.as_ref());
+    }
+
+    /// 解码时逐个字段回调, 把字段原样追加到缓冲区, 得到完整的消息字节
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut Bytes,
+        _ctx: &mut DecodeContext,
+        _is_root: bool,
+    ) -> Result<(), DecodeError> {
+        encode_key(tag, wire_type, &mut self.0);
+        match wire_type {
+            WireType::Varint => encode_varint(decode_varint(buf)?, &mut self.0),
+            WireType::SixtyFourBit | WireType::ThirtyTwoBit => {
+                let len = if wire_type == WireType::SixtyFourBit {
+                    8
+                } else {
+                    4
+                };
+                if buf.remaining() < len {
+                    return Err(DecodeError::new("buffer underflow"));
+                }
+                self.0.put(buf.split_to(len));
+            }
+            WireType::LengthDelimited => {
+                let len = decode_varint(buf)? as usize;
+                if buf.remaining() < len {
+                    return Err(DecodeError::new("buffer underflow"));
+                }
+                encode_varint(len as u64, &mut self.0);
+                self.0.put(buf.split_to(len));
+            }
+            WireType::StartGroup | WireType::EndGroup => {
+                return Err(DecodeError::new(
+                    "deprecated group encoding is not supported",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+pub type RawService =
+    BoxCloneService<ClientContext, Request<RawMessage>, Response<RawMessage>, Status>;
+
+/// 不依赖生成代码的grpc客户端, 调用方指定方法路径, 如 `/order.OrderService/GetOrder`
+#[derive(Clone)]
+pub struct RawGenericClient<S>(volo_grpc::Client<S>);
+
+pub type RawClient = RawGenericClient<RawService>;
+
+pub struct MkRawClient;
+
+impl<S> volo::client::MkClient<volo_grpc::Client<S>> for MkRawClient {
+    type Target = RawGenericClient<S>;
+
+    fn mk_client(&self, service: volo_grpc::Client<S>) -> Self::Target {
+        RawGenericClient(service)
+    }
+}
+
+impl<S> RawGenericClient<S>
+where
+    S: volo::service::Service<
+            ClientContext,
+            Request<RawMessage>,
+            Response = Response<RawMessage>,
+            Error = Status,
+        > + Sync
+        + Send
+        + 'static,
+{
+    /// 一元调用
+    pub async fn unary(&self, path: &'static str, message: Bytes) -> Result<Bytes, Status> {
+        let mut cx = self.0.make_cx(path);
+        let resp =
+            volo::Service::call(&self.0, &mut cx, Request::new(RawMessage(message.into()))).await?;
+        Ok(resp.into_inner().0.freeze())
+    }
+}
+
+/// 按 `ClientOptions` 构建通用grpc客户端
+pub fn build_raw_client(
+    service_name: &str,
+    discover: ClientDiscover,
+    options: &ClientOptions,
+) -> RawClient {
+    build_grpc_client!(
+        volo_grpc::client::ClientBuilder::<Identity, Identity, MkRawClient, RawMessage, RawMessage>::new(
+            service_name.to_string()
+        ),
+        discover,
+        options
+    )
+}
+
+#[cfg(test)]
+mod raw_message_test {
+    use super::*;
+
+    #[test]
+    fn decode_keeps_raw_bytes() {
+        // field 1 varint 150, field 2 string "hi"
+        let bytes = Bytes::from_static(&[0x08, 0x96, 0x01, 0x12, 0x02, b'h', b'i']);
+        let msg = RawMessage::decode(bytes.clone()).unwrap();
+        assert_eq!(msg.0.freeze(), bytes);
+    }
+}
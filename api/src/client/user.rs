@@ -0,0 +1,43 @@
+use crate::client::{
+    build_grpc_client, call_json, ClientDiscover, ClientOptions, LoadBalanceKind, RpcMethod,
+    RpcService,
+};
+use user::user::{GetUserRequest, User, UserServiceClient, UserServiceClientBuilder};
+
+/// user rpc服务
+pub struct UserRpc;
+
+impl RpcService for UserRpc {
+    const NAME: &'static str = "volo-boot-user.rpc";
+    const PROTO_SERVICE: &'static str = "user.UserService";
+
+    type Client = UserServiceClient;
+
+    fn default_options() -> ClientOptions {
+        ClientOptions {
+            load_balance: LoadBalanceKind::ConsistentHash,
+            ..Default::default()
+        }
+    }
+
+    fn build_client(discover: ClientDiscover, options: &ClientOptions) -> Self::Client {
+        build_grpc_client!(UserServiceClientBuilder::new(Self::NAME), discover, options)
+    }
+
+    fn methods() -> Vec<RpcMethod> {
+        vec![RpcMethod {
+            name: "GetUser",
+            idempotent: true,
+            call: |clients, params| {
+                Box::pin(call_json::<Self, GetUserRequest, User, _, _>(
+                    clients,
+                    params,
+                    |cli, req| async move { cli.get_user(req).await },
+                ))
+            },
+            validate: Some(|params, v| {
+                v.required("id", params.get("id").filter(|x| !x.is_null()));
+            }),
+        }]
+    }
+}
@@ -0,0 +1,481 @@
+use crate::app_config::{ConcurrencyConfig, DynamicConfig};
+use crate::circuit_breaker;
+use crate::controller::R;
+use anyhow::anyhow;
+use arc_swap::ArcSwap;
+use lazy_static::lazy_static;
+use regex::RegexSet;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use volo::METAINFO;
+use volo_grpc::{Code, Status};
+use volo_http::context::ServerContext;
+use volo_http::http::header::RETRY_AFTER;
+use volo_http::http::{HeaderValue, StatusCode, Uri};
+use volo_http::request::Request;
+use volo_http::response::Response;
+use volo_http::server::extract::Json;
+use volo_http::server::middleware::Next;
+use volo_http::server::IntoResponse;
+
+lazy_static! {
+    static ref CONCURRENCY_TABLE: ArcSwap<ConcurrencyTable> = ArcSwap::default();
+}
+
+/// gradient模式下每隔这么多个请求重新统计最小延迟, 避免下游扩容后仍按旧的最小延迟限制
+const MIN_RTT_RESET_SAMPLES: u64 = 1000;
+
+/// 并发上限的调整方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdaptiveMode {
+    /// 固定上限
+    Fixed,
+    /// 延迟低于目标时缓慢增加(每 `limit` 个请求加1), 超过目标或失败时乘以0.9
+    Aimd,
+    /// 按 最小延迟/当前延迟 的比例调整, 延迟上升说明下游开始排队, 不需要配置目标延迟
+    Gradient,
+}
+
+impl AdaptiveMode {
+    pub fn parse(mode: &str) -> anyhow::Result<Self> {
+        match mode.trim() {
+            "fixed" => Ok(AdaptiveMode::Fixed),
+            "aimd" => Ok(AdaptiveMode::Aimd),
+            "gradient" => Ok(AdaptiveMode::Gradient),
+            _ => Err(anyhow!(
+                "invalid adaptive mode: {}, expect fixed, aimd or gradient",
+                mode
+            )),
+        }
+    }
+}
+
+struct LimitState {
+    limit: f64,
+    min_rtt: Option<Duration>,
+    samples: u64,
+}
+
+/// 限制同时处理的请求数, 超过上限的请求直接拒绝, 不排队
+pub struct ConcurrencyLimiter {
+    name: String,
+    config: ConcurrencyConfig,
+    mode: AdaptiveMode,
+    min_limit: f64,
+    max_limit: f64,
+    target_latency: Duration,
+    in_flight: AtomicUsize,
+    /// 当前的整数上限, 取令牌时无锁读取
+    limit: AtomicUsize,
+    state: Mutex<LimitState>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(name: String, config: ConcurrencyConfig) -> anyhow::Result<Self> {
+        let mode = AdaptiveMode::parse(config.adaptive.as_deref().unwrap_or("fixed"))?;
+        let max_limit = config.limit.max(1);
+        Ok(Self {
+            name,
+            mode,
+            min_limit: config.min_limit.unwrap_or(1).clamp(1, max_limit) as f64,
+            max_limit: max_limit as f64,
+            target_latency: Duration::from_millis(config.latency_ms.unwrap_or_default()),
+            in_flight: AtomicUsize::new(0),
+            limit: AtomicUsize::new(max_limit as usize),
+            state: Mutex::new(LimitState {
+                limit: max_limit as f64,
+                min_rtt: None,
+                samples: 0,
+            }),
+            config,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// 未达到上限时返回许可, 许可释放时记录这次请求的延迟
+    pub fn try_acquire(self: &Arc<Self>) -> Option<ConcurrencyPermit> {
+        let limit = self.limit();
+        let acquired = self
+            .in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |x| {
+                (x < limit).then_some(x + 1)
+            });
+        match acquired {
+            Ok(x) => {
+                metrics::gauge!("concurrency_in_flight", "name" => self.name.clone())
+                    .set((x + 1) as f64);
+                Some(ConcurrencyPermit {
+                    limiter: self.clone(),
+                    start: Instant::now(),
+                    released: false,
+                })
+            }
+            Err(_) => {
+                metrics::counter!("concurrency_rejected_total", "name" => self.name.clone())
+                    .increment(1);
+                None
+            }
+        }
+    }
+
+    fn release(&self, sample: Option<(bool, Duration)>) {
+        let x = self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        metrics::gauge!("concurrency_in_flight", "name" => self.name.clone()).set((x - 1) as f64);
+        if let Some((ok, latency)) = sample {
+            self.update(ok, latency);
+        }
+    }
+
+    fn update(&self, ok: bool, latency: Duration) {
+        if self.mode == AdaptiveMode::Fixed {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let limit = state.limit;
+        let new_limit = match self.mode {
+            AdaptiveMode::Fixed => limit,
+            _ if !ok => limit * 0.9,
+            AdaptiveMode::Aimd if latency > self.target_latency => limit * 0.9,
+            AdaptiveMode::Aimd => limit + 1.0 / limit,
+            AdaptiveMode::Gradient => {
+                state.samples += 1;
+                if state.samples % MIN_RTT_RESET_SAMPLES == 0 {
+                    state.min_rtt = None;
+                }
+                let min_rtt = state.min_rtt.map_or(latency, |x| x.min(latency));
+                state.min_rtt = Some(min_rtt);
+                let gradient =
+                    (min_rtt.as_secs_f64() / latency.as_secs_f64().max(1e-6)).clamp(0.5, 1.0);
+                // 允许sqrt(limit)的排队, 平滑后避免抖动
+                let target = limit * gradient + limit.sqrt();
+                limit * 0.8 + target * 0.2
+            }
+        };
+        state.limit = new_limit.clamp(self.min_limit, self.max_limit);
+        let limit = state.limit as usize;
+        if limit != self.limit.swap(limit, Ordering::Relaxed) {
+            metrics::gauge!("concurrency_limit", "name" => self.name.clone()).set(limit as f64);
+        }
+    }
+}
+
+/// 并发许可, 未调用 `finish` 就被丢弃时(如请求被 `TimeoutLayer` 取消)按失败计算
+pub struct ConcurrencyPermit {
+    limiter: Arc<ConcurrencyLimiter>,
+    start: Instant,
+    released: bool,
+}
+
+impl ConcurrencyPermit {
+    /// 请求完成, `ok` 为false表示上游过载(不可用、超时、资源耗尽)
+    pub fn finish(mut self, ok: bool) {
+        self.released = true;
+        self.limiter.release(Some((ok, self.start.elapsed())));
+    }
+
+    /// 请求没有执行, 只归还许可
+    pub fn cancel(mut self) {
+        self.released = true;
+        self.limiter.release(None);
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        if !self.released {
+            self.limiter.release(Some((false, self.start.elapsed())));
+        }
+    }
+}
+
+struct RouteRule {
+    methods: Vec<String>,
+    limiter: Arc<ConcurrencyLimiter>,
+}
+
+/// 编译后的并发限制规则, 配置变更时整体重建后替换
+#[derive(Default)]
+pub struct ConcurrencyTable {
+    set: RegexSet,
+    routes: Vec<RouteRule>,
+    services: HashMap<String, Arc<ConcurrencyLimiter>>,
+}
+
+impl ConcurrencyTable {
+    /// 配置没有变化的规则沿用 `old` 中的限制器, 保留正在处理的请求数和自适应的上限
+    pub fn build(configs: Vec<ConcurrencyConfig>, old: &ConcurrencyTable) -> anyhow::Result<Self> {
+        let old_limiters: HashMap<&str, &Arc<ConcurrencyLimiter>> = old
+            .routes
+            .iter()
+            .map(|x| &x.limiter)
+            .chain(old.services.values())
+            .map(|x| (x.name(), x))
+            .collect();
+        let limiter =
+            |name: String, config: ConcurrencyConfig| match old_limiters.get(name.as_str()) {
+                Some(x) if x.config == config => Ok((*x).clone()),
+                _ => {
+                    tracing::info!("reset concurrency limiter {}: {:?}", name, config);
+                    ConcurrencyLimiter::new(name, config).map(Arc::new)
+                }
+            };
+
+        let mut urls = vec![];
+        let mut routes = vec![];
+        let mut services = HashMap::new();
+        for config in configs {
+            config.validate()?;
+            if let Some(service) = config.service.clone() {
+                let name = format!("service:{}", service);
+                services.insert(service, limiter(name, config)?);
+            } else if let Some(url) = config.url.clone() {
+                let methods: Vec<String> = config.method.iter().map(|m| m.to_lowercase()).collect();
+                let name = format!("route:{}:{}", url, methods.join(","));
+                routes.push(RouteRule {
+                    methods,
+                    limiter: limiter(name, config)?,
+                });
+                urls.push(url);
+            }
+        }
+        let set =
+            RegexSet::new(urls.iter()).map_err(|e| anyhow!("compile concurrency rules: {}", e))?;
+        Ok(Self {
+            set,
+            routes,
+            services,
+        })
+    }
+
+    fn match_route(&self, path: &str, method: &str) -> Vec<Arc<ConcurrencyLimiter>> {
+        if self.routes.is_empty() {
+            return vec![];
+        }
+        self.set
+            .matches(path)
+            .into_iter()
+            .map(|i| &self.routes[i])
+            .filter(|x| x.methods.is_empty() || x.methods.iter().any(|m| m == method))
+            .map(|x| x.limiter.clone())
+            .collect()
+    }
+}
+
+/// 重建并发限制规则表后整体替换
+pub fn reset_concurrency(dynamic_config: DynamicConfig) -> anyhow::Result<()> {
+    let configs = dynamic_config.concurrency.unwrap_or_default();
+    let table = ConcurrencyTable::build(configs, &CONCURRENCY_TABLE.load())?;
+    tracing::info!(
+        "reset concurrency limiter: {} routes, {} services",
+        table.routes.len(),
+        table.services.len()
+    );
+    CONCURRENCY_TABLE.store(Arc::new(table));
+    Ok(())
+}
+
+/// 按路由限制并发, 超过上限时返回503, 在下游rpc服务饱和之前丢弃请求
+pub async fn do_concurrency_limiter(
+    uri: Uri,
+    cx: &mut ServerContext,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let method = req.method().to_string().to_lowercase();
+    let limiters = CONCURRENCY_TABLE
+        .load()
+        .match_route(uri.path(), method.as_str());
+
+    let mut permits = Vec::with_capacity(limiters.len());
+    for limiter in limiters.iter() {
+        match limiter.try_acquire() {
+            Some(permit) => permits.push(permit),
+            None => {
+                permits.into_iter().for_each(ConcurrencyPermit::cancel);
+                return Ok(overloaded(limiter.name()));
+            }
+        }
+    }
+
+    let response = next.run(cx, req).await;
+    // 只有上游rpc过载才算失败, 网关自己返回的5xx(熔断、限流等)不计入; 超时由 `ConcurrencyPermit` 的Drop计算
+    let ok = !upstream_overloaded();
+    permits.into_iter().for_each(|p| p.finish(ok));
+    let Ok(r) = response else {
+        return Ok(response.into_response());
+    };
+    Ok(r)
+}
+
+/// 按上游服务限制并发, 超过上限时返回 `Unavailable`, 不再调用rpc。
+/// 许可在重试和对冲之外获取, 一次调用的所有重试和对冲请求只占用一个许可
+pub async fn call_service<T, F>(service: &str, call: F) -> Result<T, Status>
+where
+    F: Future<Output = Result<T, Status>>,
+{
+    let limiter = CONCURRENCY_TABLE.load().services.get(service).cloned();
+    let Some(limiter) = limiter else {
+        return mark_overloaded(call.await);
+    };
+    let Some(permit) = limiter.try_acquire() else {
+        tracing::warn!(
+            "shed request to {}, concurrency limit {}",
+            service,
+            limiter.limit()
+        );
+        return Err(circuit_breaker::rejected(format!(
+            "{} is overloaded",
+            service
+        )));
+    };
+
+    let result = mark_overloaded(call.await);
+    permit.finish(!result.as_ref().is_err_and(is_overload));
+    result
+}
+
+/// 请求中有上游rpc返回过载错误的标记, 放在METAINFO中, 供路由级的并发限制判断
+#[derive(Debug, Clone, Copy)]
+struct UpstreamOverloaded;
+
+/// 上游rpc过载: 不可用、超时或资源耗尽, 不包括网关自己拒绝的请求
+fn is_overload(e: &Status) -> bool {
+    matches!(
+        e.code(),
+        Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted
+    ) && !circuit_breaker::is_rejected(e)
+}
+
+fn mark_overloaded<T>(result: Result<T, Status>) -> Result<T, Status> {
+    if result.as_ref().is_err_and(is_overload) {
+        METAINFO.with(|m| m.borrow_mut().insert(UpstreamOverloaded));
+    }
+    result
+}
+
+fn upstream_overloaded() -> bool {
+    METAINFO.with(|m| m.borrow().get::<UpstreamOverloaded>().is_some())
+}
+
+/// 503响应, 使用 `R` 格式的响应体
+fn overloaded(name: &str) -> Response {
+    tracing::warn!("shed request, concurrency limit {} reached", name);
+    let body = R::<()>::error_status_code(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "server is overloaded, please retry later",
+    );
+    let mut resp = (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response();
+    resp.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(1u64));
+    resp
+}
+
+#[cfg(test)]
+mod concurrency_limiter_test {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn limiter(limit: u64, adaptive: &str, latency_ms: Option<u64>) -> Arc<ConcurrencyLimiter> {
+        let config = ConcurrencyConfig {
+            service: Some("test".to_string()),
+            limit,
+            min_limit: Some(2),
+            adaptive: Some(adaptive.to_string()),
+            latency_ms,
+            ..Default::default()
+        };
+        Arc::new(ConcurrencyLimiter::new("test".to_string(), config).unwrap())
+    }
+
+    #[test]
+    fn reject_over_limit() {
+        let limiter = limiter(2, "fixed", None);
+        let p1 = limiter.try_acquire().unwrap();
+        let p2 = limiter.try_acquire().unwrap();
+        assert!(limiter.try_acquire().is_none());
+        p1.finish(true);
+        assert!(limiter.try_acquire().is_some());
+        drop(p2);
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[test]
+    fn aimd_decrease_on_slow_response() {
+        let limiter = limiter(10, "aimd", Some(100));
+        limiter.update(false, Duration::from_millis(10));
+        assert_eq!(limiter.limit(), 9);
+        for _ in 0..20 {
+            limiter.update(true, Duration::from_millis(500));
+        }
+        assert_eq!(limiter.limit(), 2);
+        // 延迟恢复后缓慢增加
+        for _ in 0..10 {
+            limiter.update(true, Duration::from_millis(10));
+        }
+        assert!(limiter.limit() > 2);
+    }
+
+    #[test]
+    fn gradient_decrease_when_latency_grows() {
+        let limiter = limiter(100, "gradient", None);
+        limiter.update(true, Duration::from_millis(10));
+        for _ in 0..50 {
+            limiter.update(true, Duration::from_millis(100));
+        }
+        assert!(limiter.limit() < 50);
+    }
+
+    #[test]
+    fn only_upstream_overload_is_failure() {
+        METAINFO.sync_scope(RefCell::new(Default::default()), || {
+            let _ = mark_overloaded::<()>(Err(Status::invalid_argument("bad request")));
+            let _ = mark_overloaded::<()>(Err(Status::internal("bug")));
+            let rejected = circuit_breaker::rejected("user is circuit broken".to_string());
+            let _ = mark_overloaded::<()>(Err(rejected));
+            assert!(!upstream_overloaded());
+            let _ = mark_overloaded::<()>(Err(Status::deadline_exceeded("timeout")));
+            assert!(upstream_overloaded());
+        });
+    }
+
+    #[test]
+    fn table_match_route() {
+        let configs = vec![
+            ConcurrencyConfig {
+                url: Some("^/user/".to_string()),
+                method: vec!["GET".to_string()],
+                limit: 10,
+                ..Default::default()
+            },
+            ConcurrencyConfig {
+                service: Some("volo-boot-order.rpc".to_string()),
+                limit: 10,
+                ..Default::default()
+            },
+        ];
+        let table = ConcurrencyTable::build(configs.clone(), &ConcurrencyTable::default()).unwrap();
+        assert_eq!(table.match_route("/user/query-one", "get").len(), 1);
+        assert!(table.match_route("/user/query-one", "post").is_empty());
+        assert!(table.services.contains_key("volo-boot-order.rpc"));
+
+        let rebuilt = ConcurrencyTable::build(configs, &table).unwrap();
+        assert!(Arc::ptr_eq(
+            &rebuilt.routes[0].limiter,
+            &table.routes[0].limiter
+        ));
+    }
+}
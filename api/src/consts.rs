@@ -1,4 +1,26 @@
 pub const BINCODE_CONFIG_STANDARD: bincode::config::Configuration = bincode::config::standard();
 
-pub const RPC_USER_KEY: &'static str = "volo-boot-user.rpc";
-pub const RPC_ORDER_KEY: &'static str = "volo-boot-order.rpc";
+/// 服务实例tags中的key, 除了下面这些, nacos实例的元数据(如 version、zone)也会原样放入tags
+pub const TAG_CLUSTER: &str = "cluster";
+pub const TAG_HEALTHY: &str = "healthy";
+pub const TAG_ENABLED: &str = "enabled";
+pub const TAG_EPHEMERAL: &str = "ephemeral";
+pub const TAG_LANE: &str = "lane";
+
+/// 网关自己拒绝的请求(熔断、并发限制等)在 `Status` 的metadata中带上这个key, 不计入熔断和过载统计
+pub const REJECTED_METADATA_KEY: &str = "x-gateway-rejected";
+
+/// 指定泳道的请求头, 如 `x-lane: canary`
+pub const LANE_HEADER: &str = "x-lane";
+
+/// prometheus指标
+pub const METRICS_PATH: &str = "/metrics";
+/// 随机数示例接口
+pub const RANDOM_PATH: &str = "/random";
+
+/// 通用grpc转发路由, service为idl中的服务全名
+pub const GRPC_PROXY_PATH: &str = "/grpc/{service}/{method}";
+
+/// 动态配置的状态和回滚, 只在单独的 metric_port 上提供
+pub const ADMIN_CONFIG_PATH: &str = "/admin/config";
+pub const ADMIN_CONFIG_ROLLBACK_PATH: &str = "/admin/config/rollback";
@@ -0,0 +1,125 @@
+use crate::app_config::AdminConfig;
+use crate::controller::R;
+use crate::dynamic_config::{self, ConfigStatus};
+use crate::ip_net::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::OnceLock;
+use volo::context::Context;
+use volo::net::Address;
+use volo_http::context::ServerContext;
+use volo_http::http::header::AUTHORIZATION;
+use volo_http::http::{HeaderMap, StatusCode};
+use volo_http::request::Request;
+use volo_http::response::Response;
+use volo_http::server::middleware::Next;
+use volo_http::server::IntoResponse;
+
+/// 管理接口的访问控制
+struct AdminAuth {
+    token: Option<String>,
+    allow_ips: Vec<IpNet>,
+}
+
+static ADMIN_AUTH: OnceLock<AdminAuth> = OnceLock::new();
+
+impl AdminAuth {
+    fn new(config: AdminConfig) -> anyhow::Result<Self> {
+        let allow_ips = match config.allow_ips {
+            Some(ips) => ips
+                .iter()
+                .map(|x| IpNet::parse(x))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            None => vec![
+                IpNet::parse(Ipv4Addr::LOCALHOST.to_string().as_str())?,
+                IpNet::parse(Ipv6Addr::LOCALHOST.to_string().as_str())?,
+            ],
+        };
+        Ok(Self {
+            token: config.token,
+            allow_ips,
+        })
+    }
+
+    fn allow(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> bool {
+        if peer.is_some_and(|ip| self.allow_ips.iter().any(|x| x.contains(ip))) {
+            return true;
+        }
+        let bearer = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        match (self.token.as_deref(), bearer) {
+            (Some(token), Some(bearer)) => constant_time_eq(token.as_bytes(), bearer.as_bytes()),
+            _ => false,
+        }
+    }
+}
+
+/// 比较token时不因为第一个不同的字节提前返回, 避免通过响应时间猜测token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// 启动时设置 `[admin]` 中的访问控制, 不配置时只允许本机访问
+pub fn init_admin(config: Option<AdminConfig>) -> anyhow::Result<()> {
+    let _ = ADMIN_AUTH.set(AdminAuth::new(config.unwrap_or_default())?);
+    Ok(())
+}
+
+/// 管理接口的访问控制中间件, 不允许的请求返回403
+pub async fn do_admin_auth(
+    cx: &mut ServerContext,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let peer = match cx.rpc_info().caller().address() {
+        Some(Address::Ip(addr)) => Some(addr.ip()),
+        _ => None,
+    };
+    let allowed = ADMIN_AUTH
+        .get()
+        .is_some_and(|auth| auth.allow(peer, req.headers()));
+    if !allowed {
+        tracing::warn!("admin request {} from {:?} is forbidden", req.uri(), peer);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(next.run(cx, req).await.into_response())
+}
+
+/// 动态配置的当前版本、上一个版本和最近一次错误
+pub async fn get_config_status() -> R<ConfigStatus> {
+    R::ok(dynamic_config::config_status().as_ref().clone())
+}
+
+/// 回滚到上一个版本的动态配置
+pub async fn rollback_config() -> R<ConfigStatus> {
+    match dynamic_config::rollback_config() {
+        Ok(_) => R::ok(dynamic_config::config_status().as_ref().clone()),
+        Err(e) => R::error(400, &e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod admin_controller_test {
+    use super::*;
+    use volo_http::http::HeaderValue;
+
+    #[test]
+    fn allow_loopback_or_token() {
+        let auth = AdminAuth::new(AdminConfig {
+            token: Some("secret".to_string()),
+            allow_ips: None,
+        })
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        assert!(auth.allow(Some("127.0.0.1".parse().unwrap()), &headers));
+        assert!(auth.allow(Some("::1".parse().unwrap()), &headers));
+        assert!(!auth.allow(Some("10.0.0.1".parse().unwrap()), &headers));
+        assert!(!auth.allow(None, &headers));
+
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secre"));
+        assert!(!auth.allow(Some("10.0.0.1".parse().unwrap()), &headers));
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        assert!(auth.allow(Some("10.0.0.1".parse().unwrap()), &headers));
+    }
+}
@@ -0,0 +1,257 @@
+use crate::controller::R;
+use biz_error::ErrorDetail;
+use bytes::Bytes;
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::ops::{Bound, RangeBounds};
+use volo_http::body::Body;
+use volo_http::context::ServerContext;
+use volo_http::http::request::Parts;
+use volo_http::response::Response;
+use volo_http::server::extract::{FromContext, FromRequest};
+use volo_http::server::param::PathParams;
+use volo_http::server::IntoResponse;
+
+/// 收集请求参数的所有校验错误, 一次返回给调用方
+#[derive(Debug, Default)]
+pub struct Validator {
+    violations: Vec<ErrorDetail>,
+}
+
+impl Validator {
+    pub fn violation(&mut self, field: Option<&str>, reason: impl Into<String>) -> &mut Self {
+        self.violations.push(ErrorDetail {
+            field: field.map(|x| x.to_string()),
+            reason: reason.into(),
+        });
+        self
+    }
+
+    /// 字段必须有值
+    pub fn required<V>(&mut self, field: &str, value: Option<V>) -> &mut Self {
+        if value.is_none() {
+            self.violation(Some(field), "不能为空");
+        }
+        self
+    }
+
+    /// 有值时必须在 `range` 内
+    pub fn range<V, B>(&mut self, field: &str, value: Option<V>, range: B) -> &mut Self
+    where
+        V: PartialOrd + Display,
+        B: RangeBounds<V>,
+    {
+        if value.as_ref().is_some_and(|v| !range.contains(v)) {
+            self.violation(Some(field), format!("必须{}", describe(&range)));
+        }
+        self
+    }
+
+    /// 有值时字符数必须在 `range` 内
+    pub fn length<B: RangeBounds<usize>>(
+        &mut self,
+        field: &str,
+        value: Option<&str>,
+        range: B,
+    ) -> &mut Self {
+        if value.is_some_and(|v| !range.contains(&v.chars().count())) {
+            self.violation(Some(field), format!("长度必须{}", describe(&range)));
+        }
+        self
+    }
+
+    /// 有值时必须匹配 `regex`
+    pub fn regex(&mut self, field: &str, value: Option<&str>, regex: &Regex) -> &mut Self {
+        if value.is_some_and(|v| !regex.is_match(v)) {
+            self.violation(
+                Some(field),
+                format!("格式错误, 需要匹配 {}", regex.as_str()),
+            );
+        }
+        self
+    }
+
+    /// `fields` 中至少有一个字段有值, 如 "id 或 user_id"
+    pub fn any_required(&mut self, fields: &[(&str, bool)]) -> &mut Self {
+        if !fields.iter().any(|(_, present)| *present) {
+            let names: Vec<&str> = fields.iter().map(|(name, _)| *name).collect();
+            self.violation(None, format!("{} 不能同时为空", names.join(" 和 ")));
+        }
+        self
+    }
+
+    pub fn into_result(self) -> Result<(), Vec<ErrorDetail>> {
+        if self.violations.is_empty() {
+            Ok(())
+        } else {
+            Err(self.violations)
+        }
+    }
+}
+
+fn describe<V: Display, B: RangeBounds<V>>(range: &B) -> String {
+    let start = match range.start_bound() {
+        Bound::Included(v) => Some(format!("大于等于 {}", v)),
+        Bound::Excluded(v) => Some(format!("大于 {}", v)),
+        Bound::Unbounded => None,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(v) => Some(format!("小于等于 {}", v)),
+        Bound::Excluded(v) => Some(format!("小于 {}", v)),
+        Bound::Unbounded => None,
+    };
+    start.into_iter().chain(end).collect::<Vec<_>>().join("且")
+}
+
+/// 请求参数的声明式校验, 在 `validate` 中用 `Validator` 声明每个字段的规则
+pub trait Validate {
+    fn validate(&self, v: &mut Validator);
+}
+
+fn check<T: Validate>(value: T) -> Result<T, Vec<ErrorDetail>> {
+    let mut v = Validator::default();
+    value.validate(&mut v);
+    v.into_result().map(|_| value)
+}
+
+/// query和path参数都是字符串, 用urlencoded反序列化时会按字段类型转换
+pub fn from_urlencoded<T>(input: &str) -> Result<T, Vec<ErrorDetail>>
+where
+    T: DeserializeOwned + Validate,
+{
+    let value = serde_urlencoded::from_str(input).map_err(|e| {
+        vec![ErrorDetail {
+            field: None,
+            reason: e.to_string(),
+        }]
+    })?;
+    check(value)
+}
+
+/// 空的请求体按 `{}` 处理
+pub fn from_json<T>(body: &[u8]) -> Result<T, Vec<ErrorDetail>>
+where
+    T: DeserializeOwned + Validate,
+{
+    let body: &[u8] = if body.is_empty() { b"{}" } else { body };
+    let value = serde_json::from_slice(body).map_err(|e| {
+        vec![ErrorDetail {
+            field: None,
+            reason: format!("invalid json body: {}", e),
+        }]
+    })?;
+    check(value)
+}
+
+fn reject(details: Vec<ErrorDetail>) -> Response {
+    R::<()>::invalid_argument(details).into_response()
+}
+
+/// 校验过的query参数, 校验失败时返回400和所有的校验错误
+pub struct ValidQuery<T>(pub T);
+
+impl<T> FromContext for ValidQuery<T>
+where
+    T: DeserializeOwned + Validate + Send,
+{
+    type Rejection = Response;
+
+    async fn from_context(
+        _cx: &mut ServerContext,
+        parts: &mut Parts,
+    ) -> Result<Self, Self::Rejection> {
+        from_urlencoded(parts.uri.query().unwrap_or_default())
+            .map(ValidQuery)
+            .map_err(reject)
+    }
+}
+
+/// 校验过的path参数, 如 `/order/{id}` 中的 `id`
+pub struct ValidPath<T>(pub T);
+
+impl<T> FromContext for ValidPath<T>
+where
+    T: DeserializeOwned + Validate + Send,
+{
+    type Rejection = Response;
+
+    async fn from_context(
+        cx: &mut ServerContext,
+        parts: &mut Parts,
+    ) -> Result<Self, Self::Rejection> {
+        let PathParams(params) = PathParams::<HashMap<String, String>>::from_context(cx, parts)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let encoded = serde_urlencoded::to_string(params).unwrap_or_default();
+        from_urlencoded(encoded.as_str())
+            .map(ValidPath)
+            .map_err(reject)
+    }
+}
+
+/// 校验过的json请求体
+pub struct ValidJson<T>(pub T);
+
+impl<T> FromRequest for ValidJson<T>
+where
+    T: DeserializeOwned + Validate + Send,
+{
+    type Rejection = Response;
+
+    async fn from_request(
+        cx: &mut ServerContext,
+        parts: Parts,
+        body: Body,
+    ) -> Result<Self, Self::Rejection> {
+        let body = Bytes::from_request(cx, parts, body)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        from_json(&body).map(ValidJson).map_err(reject)
+    }
+}
+
+#[cfg(test)]
+mod extract_test {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct OrderQuery {
+        id: Option<i64>,
+        user_id: Option<i64>,
+        name: Option<String>,
+    }
+
+    impl Validate for OrderQuery {
+        fn validate(&self, v: &mut Validator) {
+            v.any_required(&[
+                ("id", self.id.is_some()),
+                ("user_id", self.user_id.is_some()),
+            ])
+            .range("id", self.id, 1..)
+            .range("user_id", self.user_id, 1..)
+            .length("name", self.name.as_deref(), ..=32);
+        }
+    }
+
+    #[test]
+    fn collect_all_violations() {
+        let q: OrderQuery = from_urlencoded("id=1").unwrap();
+        assert_eq!(q.id, Some(1));
+
+        let details = from_urlencoded::<OrderQuery>("").err().unwrap();
+        assert_eq!(details[0].reason, "id 和 user_id 不能同时为空");
+
+        let details = from_urlencoded::<OrderQuery>("id=0&user_id=-1")
+            .err()
+            .unwrap();
+        assert_eq!(details.len(), 2);
+        assert_eq!(details[0].field.as_deref(), Some("id"));
+        assert_eq!(details[0].reason, "必须大于等于 1");
+
+        assert!(from_urlencoded::<OrderQuery>("id=abc").is_err());
+        assert!(from_json::<OrderQuery>(br#"{"user_id": 2}"#).is_ok());
+    }
+}
@@ -0,0 +1,76 @@
+use crate::controller::extract::{ValidPath, Validate, Validator};
+use crate::controller::{self, R};
+use crate::route_table::{call_upstream, RouteTarget};
+use crate::ServiceContext;
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use volo_http::http::{HeaderMap, StatusCode};
+use volo_http::utils::Extension;
+
+lazy_static! {
+    /// idl中的服务全名, 如 order.OrderService
+    static ref SERVICE_NAME: Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*(\.[A-Za-z_][A-Za-z0-9_]*)*$").unwrap();
+    static ref METHOD_NAME: Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+}
+
+#[derive(Deserialize)]
+pub struct ProxyPath {
+    service: String,
+    method: String,
+}
+
+impl Validate for ProxyPath {
+    fn validate(&self, v: &mut Validator) {
+        v.regex("service", Some(self.service.as_str()), &SERVICE_NAME)
+            .regex("method", Some(self.method.as_str()), &METHOD_NAME);
+    }
+}
+
+/// 通用grpc转发: `POST /grpc/{service}/{method}`, service为idl中的服务全名(如 order.OrderService),
+/// 请求体为json格式的rpc请求, 按运行时加载的idl转码后调用rpc服务
+pub async fn proxy(
+    Extension(ctx): Extension<ServiceContext>,
+    ValidPath(ProxyPath { service, method }): ValidPath<ProxyPath>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> R<serde_json::Value> {
+    let Some(proto) = ctx.proto.as_ref() else {
+        return R::error_status_code(StatusCode::NOT_FOUND, "proto is not configured");
+    };
+    let Some(rpc) = proto.find_proto_method(service.as_str(), method.as_str()) else {
+        return R::error_status_code(
+            StatusCode::NOT_FOUND,
+            format!("unknown rpc {}/{}", service, method).as_str(),
+        );
+    };
+
+    let params = if body.is_empty() {
+        serde_json::Value::Object(Default::default())
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(params) => params,
+            Err(e) => {
+                return R::error_status_code(
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid json body: {}", e).as_str(),
+                )
+            }
+        }
+    };
+
+    let target = RouteTarget::Dynamic(rpc.clone());
+    if let Err(details) = target.validate(&params) {
+        return R::invalid_argument(details).with_request_id(controller::request_id(&headers));
+    }
+    let result = call_upstream(&ctx.clients, rpc.service.as_str(), &target, params).await;
+    if let Err(e) = result.as_ref() {
+        tracing::error!("grpc proxy {}/{} error: {:?}", service, method, e);
+    }
+    match result {
+        Ok(data) => R::ok(data),
+        Err(e) => R::from_status(e, controller::accept_language(&headers)),
+    }
+    .with_request_id(controller::request_id(&headers))
+}
@@ -1,73 +1,124 @@
+use biz_error::{ErrorDetail, ErrorInfo, INVALID_ARGUMENT};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::fmt::Formatter;
-use volo_http::http::StatusCode;
+use volo_grpc::{Code, Status};
+use volo_http::http::{HeaderMap, StatusCode};
 use volo_http::response::Response;
 use volo_http::server::extract::Json;
 use volo_http::server::IntoResponse;
 
-pub mod order_controller;
+pub mod admin_controller;
+pub mod extract;
+pub mod grpc_proxy_controller;
 pub mod random_controller;
-pub mod user_controller;
 
 /// 业务响应数据 `R`
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(bound(serialize = "T: Serialize", deserialize = "T: Deserialize<'de>"))]
 pub struct R<T = serde_json::Value> {
+    /// http状态码或 `biz_error` 中的数字错误码
     pub code: i64,
     pub msg: Option<String>,
     pub data: Option<T>,
+    /// 字符串错误码, 如 USER_NOT_FOUND
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<ErrorDetail>,
+    /// 请求头 `x-request-id`, 用于排查问题
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// 响应的http状态码, 不设置时按 `code` 决定
+    #[serde(skip)]
+    pub status: Option<StatusCode>,
 }
 
 impl<T> R<T> {
-    pub fn ok(data: T) -> Self {
+    fn new(code: i64, msg: Option<String>, data: Option<T>) -> Self {
         Self {
-            code: 200,
-            msg: None,
-            data: Some(data),
+            code,
+            msg,
+            data,
+            error: None,
+            details: vec![],
+            request_id: None,
+            status: None,
         }
     }
 
+    pub fn ok(data: T) -> Self {
+        Self::new(200, None, Some(data))
+    }
+
     pub fn error(code: i64, msg: &str) -> R<T> {
-        R {
-            code,
-            msg: Some(msg.to_string()),
-            data: None,
-        }
+        Self::new(code, Some(msg.to_string()), None)
     }
 
     pub fn error_status_code(status_code: StatusCode, msg: &str) -> R<T> {
+        Self::new(status_code.as_u16() as i64, Some(msg.to_string()), None)
+    }
+
+    pub fn server_error(msg: &str) -> R<T> {
+        Self::new(500, Some(msg.to_string()), None)
+    }
+
+    /// rpc错误: 带业务错误码时返回业务错误码, `lang` 不为空且网关认识这个错误码时返回对应语言的提示信息;
+    /// http状态码按grpc状态码决定
+    pub fn from_status(e: Status, lang: Option<&str>) -> Self {
+        let status = http_status_of(e.code());
+        let Some(info) = ErrorInfo::from_status(&e) else {
+            return Self::error_status_code(status, e.message());
+        };
+        let msg = lang
+            .and_then(|lang| info.message(Some(lang)))
+            .unwrap_or(e.message());
         R {
-            code: status_code.as_u16() as i64,
-            msg: Some(msg.to_string()),
-            data: None,
+            error: Some(info.name),
+            details: info.details,
+            status: Some(status),
+            ..Self::new(info.code, Some(msg.to_string()), None)
         }
     }
 
-    pub fn server_error(msg: &str) -> R<T> {
+    /// 参数校验失败: `code` 为400, `error` 为业务错误码, `msg` 中列出所有的校验错误
+    pub fn invalid_argument(details: Vec<ErrorDetail>) -> Self {
+        let msg = details
+            .iter()
+            .map(|x| match x.field.as_deref() {
+                Some(field) => format!("{} {}", field, x.reason),
+                None => x.reason.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
         R {
-            code: 500,
-            msg: Some(msg.to_string()),
-            data: None,
+            error: Some(INVALID_ARGUMENT.name.to_string()),
+            details,
+            ..Self::error_status_code(StatusCode::BAD_REQUEST, msg.as_str())
         }
     }
 
+    pub fn with_request_id(mut self, request_id: Option<String>) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
     pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> R<U> {
         R {
             code: self.code,
             msg: self.msg,
             data: self.data.map(f),
+            error: self.error,
+            details: self.details,
+            request_id: self.request_id,
+            status: self.status,
         }
     }
 }
 
 impl<T> From<anyhow::Error> for R<T> {
     fn from(e: anyhow::Error) -> Self {
-        R {
-            code: 500,
-            msg: Some(e.to_string()),
-            data: None,
-        }
+        Self::server_error(&e.to_string())
     }
 }
 impl<T> From<anyhow::Result<T>> for R<T> {
@@ -79,6 +130,60 @@ impl<T> From<anyhow::Result<T>> for R<T> {
     }
 }
 
+/// 请求头 `accept-language` 中的第一个语言, 如 `en-US,en;q=0.9` 取 `en-US`
+pub fn accept_language(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("accept-language")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split([',', ';']).next())
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty() && *v != "*")
+}
+
+/// 请求头 `x-request-id`, 一般由入口的负载均衡设置
+pub fn request_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+/// grpc状态码对应的http状态码, 与grpc-gateway的映射一致
+pub fn http_status_of(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        // 客户端取消请求, nginx的 499 Client Closed Request
+        Code::Cancelled => StatusCode::from_u16(499).unwrap(),
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl<T> From<Status> for R<T> {
+    fn from(e: Status) -> Self {
+        Self::from_status(e, None)
+    }
+}
+
+impl<T> From<Result<T, Status>> for R<T> {
+    fn from(r: Result<T, Status>) -> Self {
+        match r {
+            Ok(data) => Self::ok(data),
+            Err(e) => e.into(),
+        }
+    }
+}
+
 impl<T: fmt::Debug> fmt::Display for R<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
@@ -91,14 +196,55 @@ impl<T: fmt::Debug> fmt::Display for R<T> {
 
 impl<T: Serialize> IntoResponse for R<T> {
     fn into_response(self) -> Response {
-        let status = match self.code {
-            200..=299 => StatusCode::OK,
-            400..=499 => StatusCode::BAD_REQUEST,
-            500..=599 => StatusCode::INTERNAL_SERVER_ERROR,
+        // 200和4xx、5xx的http状态码原样返回; 1xx、3xx等不能带响应体或需要额外响应头, 和业务错误码一样返回200
+        let status = self.status.unwrap_or(match self.code {
+            200 | 400..=599 => StatusCode::from_u16(self.code as u16).unwrap_or(StatusCode::OK),
             _ => StatusCode::OK,
-        };
+        });
 
         let body = Json(self);
         (status, body).into_response()
     }
 }
+
+#[cfg(test)]
+mod r_test {
+    use super::*;
+
+    #[test]
+    fn grpc_status_to_http() {
+        let r: R<()> = Status::not_found("user not found").into();
+        assert_eq!(r.code, 404);
+        assert_eq!(r.into_response().status(), StatusCode::NOT_FOUND);
+
+        let r: R<()> = Err(Status::deadline_exceeded("timeout")).into();
+        assert_eq!(r.into_response().status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(
+            http_status_of(Code::Unavailable),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(http_status_of(Code::Cancelled).as_u16(), 499);
+
+        let status = biz_error::BizError::new(&biz_error::USER_NOT_FOUND).into_status();
+        let r: R<()> = R::from_status(status, Some("en"));
+        assert_eq!((r.code, r.msg.as_deref()), (20001, Some("user not found")));
+        assert_eq!(r.error.as_deref(), Some("USER_NOT_FOUND"));
+        assert_eq!(r.into_response().status(), StatusCode::NOT_FOUND);
+
+        // 业务错误码不是http状态码
+        assert_eq!(
+            R::<()>::error(10001, "x").into_response().status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            R::<()>::error(429, "x").into_response().status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        for code in [101, 204, 302, 304] {
+            assert_eq!(
+                R::<()>::error(code, "x").into_response().status(),
+                StatusCode::OK
+            );
+        }
+    }
+}
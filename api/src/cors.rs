@@ -0,0 +1,291 @@
+use crate::app_config::{CorsConfig, DynamicConfig};
+use arc_swap::ArcSwap;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::sync::{Arc, OnceLock};
+use volo_http::context::ServerContext;
+use volo_http::http::header::{
+    ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use volo_http::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use volo_http::request::Request;
+use volo_http::response::Response;
+use volo_http::server::middleware::Next;
+use volo_http::server::IntoResponse;
+
+lazy_static! {
+    /// 当前生效的跨域规则, None 表示不返回跨域响应头
+    static ref CORS: ArcSwap<Option<Cors>> = ArcSwap::from_pointee(None);
+}
+
+/// `[cors]` 中的跨域规则
+static STATIC_CONFIG: OnceLock<Option<CorsConfig>> = OnceLock::new();
+
+const DEFAULT_METHODS: [&str; 5] = ["GET", "POST", "PUT", "DELETE", "PATCH"];
+
+/// 编译后的跨域规则
+pub struct Cors {
+    config: CorsConfig,
+    any_origin: bool,
+    origin_regex: Option<Regex>,
+    methods: Vec<String>,
+    /// None 表示原样返回预检请求中的请求头
+    allow_headers: Option<HeaderValue>,
+}
+
+impl Cors {
+    pub fn new(config: CorsConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        let origin_regex = config
+            .allow_origin_regex
+            .as_deref()
+            .map(Regex::new)
+            .transpose()?;
+        let methods = match config.allow_methods.as_ref() {
+            Some(methods) => methods.iter().map(|x| x.to_uppercase()).collect(),
+            None => DEFAULT_METHODS.iter().map(|x| x.to_string()).collect(),
+        };
+        let allow_headers = match config.allow_headers.as_ref() {
+            Some(headers) if !headers.iter().any(|x| x == "*") => {
+                Some(HeaderValue::from_str(headers.join(", ").as_str())?)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            any_origin: config.allow_origins.iter().any(|x| x == "*"),
+            config,
+            origin_regex,
+            methods,
+            allow_headers,
+        })
+    }
+
+    fn allow_origin(&self, origin: &str) -> bool {
+        self.any_origin
+            || self.config.allow_origins.iter().any(|x| x == origin)
+            || self
+                .origin_regex
+                .as_ref()
+                .is_some_and(|x| x.is_match(origin))
+    }
+
+    /// 预检请求和实际请求都需要的响应头, Origin不允许时返回None
+    fn origin_headers(&self, origin: &HeaderValue) -> Option<HeaderMap> {
+        if !self.allow_origin(origin.to_str().ok()?) {
+            return None;
+        }
+        let mut headers = HeaderMap::new();
+        // 允许所有Origin且不携带cookie时返回 *, 响应可以被共享缓存
+        if self.any_origin && !self.config.allow_credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        if self.config.allow_credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        Some(headers)
+    }
+
+    /// 预检请求的响应头, 方法不允许时返回None
+    pub fn preflight_headers(
+        &self,
+        origin: &HeaderValue,
+        request_headers: &HeaderMap,
+    ) -> Option<HeaderMap> {
+        let method = request_headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| v.to_str().ok())?;
+        if !self.methods.iter().any(|x| x == method) {
+            return None;
+        }
+        let mut headers = self.origin_headers(origin)?;
+        if let Ok(methods) = HeaderValue::from_str(self.methods.join(", ").as_str()) {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        let allow_headers = self
+            .allow_headers
+            .clone()
+            .or_else(|| request_headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned());
+        if let Some(allow_headers) = allow_headers {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+        if self.allow_headers.is_none() {
+            headers.append(
+                VARY,
+                HeaderValue::from_static("Access-Control-Request-Headers"),
+            );
+        }
+        if let Some(max_age) = self.config.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+        }
+        Some(headers)
+    }
+
+    /// 实际请求的响应头, 没有Origin或Origin不允许时只有 `Vary: Origin`
+    pub fn response_headers(&self, origin: Option<&HeaderValue>) -> HeaderMap {
+        let Some(mut headers) = origin.and_then(|x| self.origin_headers(x)) else {
+            return self.vary_headers();
+        };
+        if !self.config.expose_headers.is_empty() {
+            if let Ok(expose) =
+                HeaderValue::from_str(self.config.expose_headers.join(", ").as_str())
+            {
+                headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, expose);
+            }
+        }
+        headers
+    }
+
+    /// 除了允许所有Origin且不携带cookie, 响应都随Origin变化, 需要 `Vary: Origin`,
+    /// 否则共享缓存可能把没有跨域响应头的响应返回给允许的Origin
+    fn vary_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if !self.any_origin || self.config.allow_credentials {
+            headers.insert(VARY, HeaderValue::from_static("Origin"));
+        }
+        headers
+    }
+}
+
+/// 启动时设置 `[cors]` 中的跨域规则
+pub fn init_cors(config: Option<CorsConfig>) {
+    let _ = STATIC_CONFIG.set(config);
+    reset_cors(DynamicConfig::default());
+}
+
+/// 动态配置中有跨域规则时使用动态配置, 否则使用 `[cors]`
+pub fn reset_cors(dynamic_config: DynamicConfig) {
+    let config = dynamic_config
+        .cors
+        .or_else(|| STATIC_CONFIG.get().cloned().flatten());
+    let cors = match config.map(Cors::new).transpose() {
+        Ok(cors) => cors,
+        Err(e) => {
+            // 配置的合法性由 `validate` 保证
+            tracing::error!("invalid cors config: {}", e);
+            return;
+        }
+    };
+    if let Some(cors) = cors.as_ref() {
+        tracing::info!("reset cors: {:?}", cors.config);
+    }
+    CORS.store(Arc::new(cors));
+}
+
+/// 跨域中间件: 直接返回预检请求, 实际请求的响应加上跨域响应头; 没有Origin的请求只加上 `Vary: Origin`
+pub async fn do_cors(
+    cx: &mut ServerContext,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let cors = CORS.load_full();
+    let Some(cors) = &*cors else {
+        return Ok(next.run(cx, req).await.into_response());
+    };
+
+    let origin = req.headers().get(ORIGIN).cloned();
+    let is_preflight = req.method() == Method::OPTIONS
+        && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+    if let Some(origin) = origin.as_ref().filter(|_| is_preflight) {
+        let (status, headers) = match cors.preflight_headers(origin, req.headers()) {
+            Some(headers) => (StatusCode::NO_CONTENT, headers),
+            None => (StatusCode::FORBIDDEN, cors.vary_headers()),
+        };
+        let mut response = status.into_response();
+        response.headers_mut().extend(headers);
+        return Ok(response);
+    }
+
+    let mut response = next.run(cx, req).await.into_response();
+    for (name, value) in cors.response_headers(origin.as_ref()).iter() {
+        if name == VARY {
+            response.headers_mut().append(name, value.clone());
+        } else {
+            response.headers_mut().insert(name, value.clone());
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod cors_test {
+    use super::*;
+
+    fn request_headers(method: &str, headers: Option<&'static str>) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        map.insert(
+            ACCESS_CONTROL_REQUEST_METHOD,
+            HeaderValue::from_str(method).unwrap(),
+        );
+        if let Some(headers) = headers {
+            map.insert(
+                ACCESS_CONTROL_REQUEST_HEADERS,
+                HeaderValue::from_static(headers),
+            );
+        }
+        map
+    }
+
+    #[test]
+    fn preflight_and_response() {
+        let cors = Cors::new(CorsConfig {
+            allow_origins: vec!["https://a.example.com".to_string()],
+            allow_origin_regex: Some(r"^https://.*\.test\.com$".to_string()),
+            allow_methods: Some(vec!["get".to_string(), "post".to_string()]),
+            expose_headers: vec!["x-request-id".to_string()],
+            allow_credentials: true,
+            max_age: Some(600),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let origin = HeaderValue::from_static("https://b.test.com");
+        let headers = cors
+            .preflight_headers(&origin, &request_headers("POST", Some("content-type")))
+            .unwrap();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://b.test.com");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "content-type");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+
+        // 方法或Origin不允许
+        assert!(cors
+            .preflight_headers(&origin, &request_headers("DELETE", None))
+            .is_none());
+        let other = HeaderValue::from_static("https://evil.com");
+        let headers = cors.response_headers(Some(&other));
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        // 不允许的Origin和没有Origin的响应也要 Vary: Origin
+        assert_eq!(headers[VARY], "Origin");
+        assert_eq!(cors.response_headers(None)[VARY], "Origin");
+
+        let headers =
+            cors.response_headers(Some(&HeaderValue::from_static("https://a.example.com")));
+        assert_eq!(headers[ACCESS_CONTROL_EXPOSE_HEADERS], "x-request-id");
+        assert_eq!(headers[VARY], "Origin");
+
+        let any = Cors::new(CorsConfig {
+            allow_origins: vec!["*".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        let headers = any.response_headers(Some(&other));
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!headers.contains_key(VARY));
+        assert!(any.response_headers(None).is_empty());
+        assert!(Cors::new(CorsConfig {
+            allow_origins: vec!["*".to_string()],
+            allow_credentials: true,
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
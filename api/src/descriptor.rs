@@ -0,0 +1,490 @@
+use crate::app_config::ProtoConfig;
+use crate::circuit_breaker;
+use crate::client::{self, ClientRegistry};
+use anyhow::anyhow;
+use bytes::Bytes;
+use prost::Message;
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, MessageDescriptor, MethodDescriptor, SerializeOptions,
+    ServiceDescriptor,
+};
+use std::collections::HashMap;
+use volo_grpc::Status;
+use volo_http::http::Method;
+
+/// 编译时嵌入的user、order服务的idl, 见 build.rs
+const EMBEDDED_DESCRIPTOR_SET: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/gateway_descriptor.bin"));
+
+/// `google.api.http` 注解
+const HTTP_RULE_EXTENSION: &str = "google.api.http";
+/// `MethodOptions.IdempotencyLevel` 的取值
+const NO_SIDE_EFFECTS: i32 = 1;
+const IDEMPOTENT: i32 = 2;
+
+/// 从idl中解析出的rpc方法
+#[derive(Clone)]
+pub struct DynamicMethod {
+    /// 注册中心中的服务名, 如 volo-boot-order.rpc
+    pub service: String,
+    /// idl中的方法名, 如 GetOrder
+    pub name: String,
+    /// grpc请求路径, 如 /order.OrderService/GetOrder
+    pub path: &'static str,
+    pub input: MessageDescriptor,
+    pub output: MessageDescriptor,
+    /// 方法选项 `idempotency_level` 为 NO_SIDE_EFFECTS 或 IDEMPOTENT
+    pub idempotent: bool,
+}
+
+impl DynamicMethod {
+    /// json -> protobuf -> rpc调用 -> protobuf -> json, 有生成代码的方法直接使用生成的客户端
+    pub async fn call(
+        &self,
+        clients: &ClientRegistry,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value, Status> {
+        if let Some(method) = client::find_method(self.service.as_str(), self.name.as_str()) {
+            return (method.call)(clients, params).await;
+        }
+
+        let req = json_to_message(&self.input, &params)?;
+        let rpc_cli = clients.acquire_raw(self.service.as_str()).ok_or_else(|| {
+            circuit_breaker::rejected(format!("{} is not subscribed", self.service))
+        })?;
+        let resp = rpc_cli.unary(self.path, req).await?;
+        message_to_json(&self.output, resp)
+    }
+}
+
+/// idl中 `google.api.http` 注解声明的http路由
+#[derive(Clone)]
+pub struct HttpRoute {
+    pub method: Method,
+    /// 路由路径, `{field}` 为path参数
+    pub path: String,
+    /// 请求体对应的请求字段, `*` 表示整个请求, None 表示没有请求体
+    pub body: Option<String>,
+    /// 响应中作为http响应的字段, None 表示整个响应
+    pub response_body: Option<String>,
+    pub rpc: DynamicMethod,
+}
+
+/// 运行时加载的idl, 用于转发没有生成代码的rpc方法
+pub struct ProtoRegistry {
+    pool: DescriptorPool,
+    /// 注册中心中的服务名 -> idl中的服务全名, 如 volo-boot-order.rpc -> order.OrderService
+    services: HashMap<String, String>,
+    /// idl中的服务全名 -> 方法名 -> 方法
+    methods: HashMap<String, HashMap<String, DynamicMethod>>,
+    http_routes: Vec<HttpRoute>,
+}
+
+/// 只保留原始字节的 `FileDescriptorSet`, 重新编码时不会丢失方法上的自定义注解
+#[derive(Clone, PartialEq, prost::Message)]
+struct RawFileDescriptorSet {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    file: Vec<Vec<u8>>,
+}
+
+/// `FileDescriptorProto` 中的文件名
+#[derive(Clone, PartialEq, prost::Message)]
+struct FileDescriptorName {
+    #[prost(string, tag = "1")]
+    name: String,
+}
+
+/// 把描述文件中的proto文件合并到 `files` 中, 同名的文件替换为新的版本
+fn merge_descriptor_set(files: &mut Vec<(String, Vec<u8>)>, bytes: &[u8]) -> anyhow::Result<()> {
+    let set = RawFileDescriptorSet::decode(bytes)?;
+    for file in set.file {
+        let name = FileDescriptorName::decode(file.as_slice())?.name;
+        match files.iter_mut().find(|(x, _)| *x == name) {
+            Some(existing) => existing.1 = file,
+            None => files.push((name, file)),
+        }
+    }
+    Ok(())
+}
+
+impl ProtoRegistry {
+    /// 加载编译时嵌入的idl, 再编译 `files` 中的proto文件并加载 `descriptor_sets` 中编译好的描述文件,
+    /// 运行时加载的proto文件替换嵌入的同名文件, 更新idl后不需要重新编译网关
+    pub fn load(config: Option<&ProtoConfig>) -> anyhow::Result<Self> {
+        let mut files = vec![];
+        merge_descriptor_set(&mut files, EMBEDDED_DESCRIPTOR_SET)
+            .map_err(|e| anyhow!("load embedded descriptor set failed: {}", e))?;
+        let mut service_names: HashMap<String, String> = client::proto_services()
+            .into_iter()
+            .map(|(proto_service, service)| (proto_service.to_string(), service.to_string()))
+            .collect();
+
+        if let Some(config) = config {
+            if !config.files.is_empty() {
+                let mut compiler = protox::Compiler::new(&config.includes)
+                    .map_err(|e| anyhow!("invalid proto includes: {}", e))?;
+                compiler.include_imports(true);
+                compiler
+                    .open_files(&config.files)
+                    .map_err(|e| anyhow!("compile proto files failed: {}", e))?;
+                // 使用编码后的描述文件, 保留方法上的自定义注解
+                let bytes = compiler.encode_file_descriptor_set();
+                merge_descriptor_set(&mut files, bytes.as_slice())
+                    .map_err(|e| anyhow!("load proto files failed: {}", e))?;
+            }
+            for path in config.descriptor_sets.iter() {
+                let bytes = std::fs::read(path)
+                    .map_err(|e| anyhow!("read descriptor set {} failed: {}", path, e))?;
+                merge_descriptor_set(&mut files, bytes.as_slice())
+                    .map_err(|e| anyhow!("load descriptor set {} failed: {}", path, e))?;
+            }
+            service_names.extend(config.services.clone());
+        }
+        let set = RawFileDescriptorSet {
+            file: files.into_iter().map(|(_, file)| file).collect(),
+        };
+        let pool = DescriptorPool::decode(set.encode_to_vec().as_slice())
+            .map_err(|e| anyhow!("load proto files failed: {}", e))?;
+
+        let mut services = HashMap::new();
+        let mut methods = HashMap::new();
+        let mut http_routes = vec![];
+        for (proto_service, service) in service_names.iter() {
+            let Some(descriptor) = pool.get_service_by_name(proto_service) else {
+                return Err(anyhow!(
+                    "service {} is not found in proto files",
+                    proto_service
+                ));
+            };
+            let service_methods = load_methods(&descriptor, service);
+            http_routes.extend(load_http_routes(&pool, &descriptor, &service_methods)?);
+            tracing::info!(
+                "load proto service {} -> {}, methods: {:?}",
+                proto_service,
+                service,
+                service_methods.keys().collect::<Vec<_>>()
+            );
+            services.insert(service.clone(), proto_service.clone());
+            methods.insert(proto_service.clone(), service_methods);
+        }
+
+        Ok(Self {
+            pool,
+            services,
+            methods,
+            http_routes,
+        })
+    }
+
+    pub fn pool(&self) -> &DescriptorPool {
+        &self.pool
+    }
+
+    /// 通过idl加载的服务(注册中心中的服务名)
+    pub fn contains_service(&self, service: &str) -> bool {
+        self.services.contains_key(service)
+    }
+
+    /// 按注册中心中的服务名查找方法
+    pub fn find_method(&self, service: &str, method: &str) -> Option<&DynamicMethod> {
+        let proto_service = self.services.get(service)?;
+        self.find_proto_method(proto_service, method)
+    }
+
+    /// 按idl中的服务全名查找方法, 如 order.OrderService GetOrder
+    pub fn find_proto_method(&self, proto_service: &str, method: &str) -> Option<&DynamicMethod> {
+        self.methods.get(proto_service)?.get(method)
+    }
+
+    /// idl中 `google.api.http` 注解声明的所有路由
+    pub fn http_routes(&self) -> &[HttpRoute] {
+        &self.http_routes
+    }
+}
+
+fn load_methods(descriptor: &ServiceDescriptor, service: &str) -> HashMap<String, DynamicMethod> {
+    descriptor
+        .methods()
+        .filter(|m| !m.is_client_streaming() && !m.is_server_streaming())
+        .map(|m| {
+            // 请求路径在启动时生成一次, 之后一直使用
+            let path: &'static str = format!("/{}/{}", descriptor.full_name(), m.name()).leak();
+            let method = DynamicMethod {
+                service: service.to_string(),
+                name: m.name().to_string(),
+                path,
+                input: m.input(),
+                output: m.output(),
+                idempotent: is_idempotent(&m),
+            };
+            (m.name().to_string(), method)
+        })
+        .collect()
+}
+
+/// `option idempotency_level = NO_SIDE_EFFECTS;` 或 `IDEMPOTENT` 的方法是幂等的
+fn is_idempotent(method: &MethodDescriptor) -> bool {
+    method
+        .options()
+        .get_field_by_name("idempotency_level")
+        .and_then(|v| v.as_enum_number())
+        .is_some_and(|level| level == NO_SIDE_EFFECTS || level == IDEMPOTENT)
+}
+
+/// 解析方法上的 `google.api.http` 注解, 包括 `additional_bindings`
+fn load_http_routes(
+    pool: &DescriptorPool,
+    descriptor: &ServiceDescriptor,
+    methods: &HashMap<String, DynamicMethod>,
+) -> anyhow::Result<Vec<HttpRoute>> {
+    let Some(extension) = pool.get_extension_by_name(HTTP_RULE_EXTENSION) else {
+        return Ok(vec![]);
+    };
+
+    let mut ret = vec![];
+    for m in descriptor.methods() {
+        let options = m.options();
+        if !options.has_extension(&extension) {
+            continue;
+        }
+        let Some(rpc) = methods.get(m.name()) else {
+            return Err(anyhow!(
+                "{}: google.api.http is not supported on streaming method",
+                m.full_name()
+            ));
+        };
+        let value = options.get_extension(&extension);
+        let Some(rule) = value.as_message() else {
+            continue;
+        };
+
+        let mut rules = vec![rule.clone()];
+        if let Some(bindings) = rule.get_field_by_name("additional_bindings") {
+            if let Some(list) = bindings.as_list() {
+                rules.extend(list.iter().filter_map(|x| x.as_message().cloned()));
+            }
+        }
+        for rule in rules.iter() {
+            let route = parse_http_rule(rule, rpc)
+                .map_err(|e| anyhow!("invalid google.api.http of {}: {}", m.full_name(), e))?;
+            ret.push(route);
+        }
+    }
+    Ok(ret)
+}
+
+fn string_field(message: &DynamicMessage, name: &str) -> Option<String> {
+    message
+        .get_field_by_name(name)
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .filter(|s| !s.is_empty())
+}
+
+fn parse_http_rule(rule: &DynamicMessage, rpc: &DynamicMethod) -> anyhow::Result<HttpRoute> {
+    let mut pattern = None;
+    for (field, method) in [
+        ("get", Method::GET),
+        ("put", Method::PUT),
+        ("post", Method::POST),
+        ("delete", Method::DELETE),
+        ("patch", Method::PATCH),
+    ] {
+        if let Some(path) = string_field(rule, field) {
+            pattern = Some((method, path));
+            break;
+        }
+    }
+    if pattern.is_none() {
+        if let Some(custom) = rule.get_field_by_name("custom") {
+            if let Some(custom) = custom.as_message() {
+                let kind = string_field(custom, "kind").unwrap_or_default();
+                let path = string_field(custom, "path").unwrap_or_default();
+                let method = Method::from_bytes(kind.as_bytes())
+                    .map_err(|_| anyhow!("invalid custom method {}", kind))?;
+                pattern = Some((method, path));
+            }
+        }
+    }
+    let Some((method, template)) = pattern else {
+        return Err(anyhow!("http method and path are required"));
+    };
+
+    let body = string_field(rule, "body");
+    if let Some(body) = body.as_deref() {
+        if body != "*" && rpc.input.get_field_by_name(body).is_none() {
+            return Err(anyhow!(
+                "body field {} is not in {}",
+                body,
+                rpc.input.full_name()
+            ));
+        }
+    }
+    let response_body = string_field(rule, "response_body");
+    if let Some(response_body) = response_body.as_deref() {
+        if rpc.output.get_field_by_name(response_body).is_none() {
+            return Err(anyhow!(
+                "response_body field {} is not in {}",
+                response_body,
+                rpc.output.full_name()
+            ));
+        }
+    }
+
+    Ok(HttpRoute {
+        method,
+        path: convert_path_template(template.as_str(), &rpc.input)?,
+        body,
+        response_body,
+        rpc: rpc.clone(),
+    })
+}
+
+/// 把注解中的路径模板转换为路由路径, 只支持 `{field}` 和 `{field=*}` 形式的单段path参数
+fn convert_path_template(template: &str, input: &MessageDescriptor) -> anyhow::Result<String> {
+    if !template.starts_with('/') {
+        return Err(anyhow!("path {} must start with /", template));
+    }
+    let mut segments = vec![];
+    for segment in template.split('/').skip(1) {
+        let Some(variable) = segment.strip_prefix('{').and_then(|x| x.strip_suffix('}')) else {
+            if segment.contains(['{', '}', ':']) {
+                return Err(anyhow!(
+                    "unsupported path segment {} in {}",
+                    segment,
+                    template
+                ));
+            }
+            segments.push(segment.to_string());
+            continue;
+        };
+        let field = match variable.split_once('=') {
+            Some((field, "*")) => field,
+            Some(_) => {
+                return Err(anyhow!(
+                    "unsupported path variable {} in {}, only {{field}} is supported",
+                    variable,
+                    template
+                ))
+            }
+            None => variable,
+        };
+        if input.get_field_by_name(field).is_none() {
+            return Err(anyhow!(
+                "path variable {} is not in {}",
+                field,
+                input.full_name()
+            ));
+        }
+        segments.push(format!("{{{}}}", field));
+    }
+    Ok(format!("/{}", segments.join("/")))
+}
+
+/// json转protobuf, 字段名同时支持idl中的名字和json名字(小驼峰)
+pub fn json_to_message(
+    descriptor: &MessageDescriptor,
+    json: &serde_json::Value,
+) -> Result<Bytes, Status> {
+    let message = DynamicMessage::deserialize(descriptor.clone(), json)
+        .map_err(|e| Status::invalid_argument(format!("invalid request: {}", e)))?;
+    Ok(message.encode_to_vec().into())
+}
+
+/// protobuf转json, 使用idl中的字段名, 与生成代码的serde序列化结果保持一致
+pub fn message_to_json(
+    descriptor: &MessageDescriptor,
+    bytes: Bytes,
+) -> Result<serde_json::Value, Status> {
+    let message = DynamicMessage::decode(descriptor.clone(), bytes)
+        .map_err(|e| Status::internal(format!("invalid response: {}", e)))?;
+    let options = SerializeOptions::new()
+        .use_proto_field_name(true)
+        .stringify_64_bit_integers(false)
+        .skip_default_fields(false);
+    message
+        .serialize_with_options(serde_json::value::Serializer, &options)
+        .map_err(|e| Status::internal(e.to_string()))
+}
+
+#[cfg(test)]
+mod descriptor_test {
+    use super::*;
+
+    #[test]
+    fn load_and_transcode() {
+        let registry = ProtoRegistry::load(None).unwrap();
+        let method = registry
+            .find_method("volo-boot-order.rpc", "GetOrder")
+            .unwrap();
+        assert_eq!(method.path, "/order.OrderService/GetOrder");
+        assert!(method.idempotent);
+
+        let req = json_to_message(&method.input, &serde_json::json!({ "user_id": 1 })).unwrap();
+        let json = message_to_json(&method.input, req).unwrap();
+        assert_eq!(json.get("user_id"), Some(&serde_json::json!(1)));
+
+        assert!(json_to_message(&method.input, &serde_json::json!({ "user_id": "x" })).is_err());
+    }
+
+    #[test]
+    fn runtime_proto_replaces_embedded() {
+        let dir = std::env::temp_dir().join(format!("descriptor-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let proto = std::fs::read_to_string("../rpc/order/idl/order.proto").unwrap();
+        let proto = proto.replacen(
+            "service OrderService {",
+            "service OrderService {\n  rpc CancelOrder(GetOrderRequest) returns (Order) {\n    option (google.api.http) = { post: \"/order/cancel\" body: \"*\" };\n  }",
+            1,
+        );
+        std::fs::write(dir.join("order.proto"), proto).unwrap();
+
+        let config = ProtoConfig {
+            includes: vec![dir.to_string_lossy().to_string(), "../rpc/idl".to_string()],
+            files: vec!["order.proto".to_string()],
+            ..Default::default()
+        };
+        let registry = ProtoRegistry::load(Some(&config));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let registry = registry.unwrap();
+        let cancel = registry
+            .find_method("volo-boot-order.rpc", "CancelOrder")
+            .unwrap();
+        assert_eq!(cancel.path, "/order.OrderService/CancelOrder");
+        assert!(registry
+            .find_method("volo-boot-order.rpc", "GetOrder")
+            .is_some());
+        assert!(registry
+            .http_routes()
+            .iter()
+            .any(|r| r.path == "/order/cancel" && r.method == Method::POST));
+        // 其他嵌入的idl不受影响
+        assert!(registry
+            .find_method("volo-boot-user.rpc", "GetUser")
+            .is_some());
+    }
+
+    #[test]
+    fn load_http_annotations() {
+        let registry = ProtoRegistry::load(None).unwrap();
+        let random = registry
+            .http_routes()
+            .iter()
+            .find(|r| r.rpc.path == "/order.OrderService/GetRandom")
+            .unwrap();
+        assert_eq!(random.method, Method::GET);
+        assert_eq!(random.path, "/order/random");
+        assert_eq!(random.response_body.as_deref(), Some("data"));
+
+        let input = registry
+            .find_method("volo-boot-order.rpc", "GetOrder")
+            .unwrap()
+            .input
+            .clone();
+        assert_eq!(
+            convert_path_template("/order/{id=*}", &input).unwrap(),
+            "/order/{id}"
+        );
+        assert!(convert_path_template("/order/{name}", &input).is_err());
+        assert!(convert_path_template("/order/{id=orders/*}", &input).is_err());
+    }
+}
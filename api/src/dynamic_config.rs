@@ -0,0 +1,266 @@
+use crate::app_config::DynamicConfig;
+use crate::circuit_breaker::reset_circuit_breaker;
+use crate::concurrency_limiter::reset_concurrency;
+use crate::cors::reset_cors;
+use crate::lane::reset_lane_rules;
+use crate::rate_limiter::{reset_limiter, DEFAULT_GROUP};
+use crate::registry::{ConfigListener, ServiceRegistry};
+use anyhow::anyhow;
+use arc_swap::ArcSwap;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 应用一个配置, 失败时恢复为当前生效的版本
+type Install = fn(&DynamicConfig, Option<&ConfigVersion>) -> anyhow::Result<()>;
+
+lazy_static! {
+    static ref CONFIG_STORE: ConfigStore<Install> = ConfigStore::new(install as Install);
+}
+
+/// 本进程内递增的版本号
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+/// 一个生效过的动态配置
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigVersion {
+    pub version: u64,
+    /// 配置内容的hash, 用于和nacos中的配置对比
+    pub digest: String,
+    /// 生效时间, unix秒
+    pub applied_at: u64,
+    pub config: DynamicConfig,
+}
+
+/// 最近一次失败的配置变更
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigError {
+    pub digest: String,
+    pub failed_at: u64,
+    pub error: String,
+}
+
+/// 动态配置的状态, 由 `/admin/config` 返回
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConfigStatus {
+    pub active: Option<ConfigVersion>,
+    /// 上一个生效的版本, 用于回滚
+    pub previous: Option<ConfigVersion>,
+    pub last_error: Option<ConfigError>,
+}
+
+pub fn config_status() -> Arc<ConfigStatus> {
+    CONFIG_STORE.status()
+}
+
+/// 解析并校验整个配置, 通过后应用限流、并发限制、熔断、泳道和跨域规则; 失败时保留当前生效的版本
+pub fn apply_config(content: &str) -> anyhow::Result<u64> {
+    CONFIG_STORE.apply(content)
+}
+
+/// 回滚到上一个版本, 当前版本成为新的上一个版本
+pub fn rollback_config() -> anyhow::Result<u64> {
+    CONFIG_STORE.rollback()
+}
+
+/// 动态配置的版本记录, 配置变更和回滚串行执行, 由 `install` 让配置生效
+pub struct ConfigStore<F> {
+    status: ArcSwap<ConfigStatus>,
+    lock: Mutex<()>,
+    install: F,
+}
+
+impl<F> ConfigStore<F>
+where
+    F: Fn(&DynamicConfig, Option<&ConfigVersion>) -> anyhow::Result<()>,
+{
+    pub fn new(install: F) -> Self {
+        Self {
+            status: ArcSwap::default(),
+            lock: Mutex::new(()),
+            install,
+        }
+    }
+
+    pub fn status(&self) -> Arc<ConfigStatus> {
+        self.status.load_full()
+    }
+
+    pub fn apply(&self, content: &str) -> anyhow::Result<u64> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let (h1, h2) = mur3::murmurhash3_x64_128(content.as_bytes(), 0);
+        let digest = format!("{:016x}{:016x}", h1, h2);
+        let current = self.status.load_full();
+        if let Some(active) = current.active.as_ref().filter(|x| x.digest == digest) {
+            return Ok(active.version);
+        }
+
+        let result = serde_yml::from_str::<DynamicConfig>(content)
+            .map_err(|e| anyhow!("parse dynamic config: {}", e))
+            .and_then(|config| {
+                config.validate()?;
+                (self.install)(&config, current.active.as_ref())?;
+                Ok(config)
+            });
+        match result {
+            Ok(config) => {
+                let version = ConfigVersion {
+                    version: NEXT_VERSION.fetch_add(1, Ordering::Relaxed),
+                    digest,
+                    applied_at: now_secs(),
+                    config,
+                };
+                let v = version.version;
+                tracing::info!("dynamic config version {} applied", v);
+                self.status.store(Arc::new(ConfigStatus {
+                    active: Some(version),
+                    previous: current.active.clone(),
+                    last_error: current.last_error.clone(),
+                }));
+                record("success", Some(v));
+                Ok(v)
+            }
+            Err(e) => {
+                tracing::error!("dynamic config rejected, keep current version: {}", e);
+                self.status.store(Arc::new(ConfigStatus {
+                    last_error: Some(ConfigError {
+                        digest,
+                        failed_at: now_secs(),
+                        error: e.to_string(),
+                    }),
+                    ..current.as_ref().clone()
+                }));
+                record("error", None);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn rollback(&self) -> anyhow::Result<u64> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let current = self.status.load_full();
+        let Some(previous) = current.previous.clone() else {
+            return Err(anyhow!("no previous config version"));
+        };
+        (self.install)(&previous.config, current.active.as_ref())?;
+        let v = previous.version;
+        tracing::info!("dynamic config rollback to version {}", v);
+        self.status.store(Arc::new(ConfigStatus {
+            active: Some(previous),
+            previous: current.active.clone(),
+            last_error: current.last_error.clone(),
+        }));
+        record("rollback", Some(v));
+        Ok(v)
+    }
+}
+
+/// 应用各模块的规则, 中途失败时恢复为 `active`, 避免一部分规则是新配置一部分是旧配置
+fn install(config: &DynamicConfig, active: Option<&ConfigVersion>) -> anyhow::Result<()> {
+    let result = reset_limiter(config.clone())
+        .and_then(|_| reset_concurrency(config.clone()))
+        .map(|_| {
+            reset_circuit_breaker(config.clone());
+            reset_lane_rules(config.clone());
+            reset_cors(config.clone());
+        });
+    if result.is_err() {
+        let restore = active.map(|x| x.config.clone()).unwrap_or_default();
+        if let Err(e) = reset_limiter(restore.clone()) {
+            tracing::error!("restore rate limiter failed: {}", e);
+        }
+        if let Err(e) = reset_concurrency(restore.clone()) {
+            tracing::error!("restore concurrency limiter failed: {}", e);
+        }
+        reset_circuit_breaker(restore.clone());
+        reset_lane_rules(restore.clone());
+        reset_cors(restore);
+    }
+    result
+}
+
+fn record(result: &'static str, version: Option<u64>) {
+    metrics::counter!("dynamic_config_reload_total", "result" => result).increment(1);
+    if let Some(version) = version {
+        metrics::gauge!("dynamic_config_version").set(version as f64);
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub struct DynamicConfigListener {
+    pub data_id: String,
+}
+
+impl ConfigListener for DynamicConfigListener {
+    fn notify(&self, data_id: &str, group: &str, content: &str) {
+        tracing::info!("config change event: {} {}", data_id, group);
+        if self.data_id.as_str() == data_id {
+            let _ = apply_config(content);
+        }
+    }
+}
+
+/// 启动时加载动态配置
+pub async fn init_dynamic_config<R: ServiceRegistry>(registry: &R, data_id: String) {
+    match registry
+        .get_config(data_id, DEFAULT_GROUP.to_string())
+        .await
+    {
+        Ok(content) => {
+            let _ = apply_config(content.as_str());
+        }
+        Err(e) => tracing::error!("get config content failed: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod dynamic_config_test {
+    use super::*;
+
+    const VALID: &str = r#"
+url_rate:
+  - url: "^/dynamic-config-test/"
+    method: ["GET"]
+    rate: 10
+"#;
+
+    #[test]
+    fn reject_invalid_then_rollback() {
+        // 不安装到全局的规则表, 避免和其他测试互相影响
+        let installed = Mutex::new(vec![]);
+        let store = ConfigStore::new(|config: &DynamicConfig, _: Option<&ConfigVersion>| {
+            let rate = config.url_rate.as_ref().map(|x| x[0].rate);
+            installed.lock().unwrap().push(rate);
+            Ok(())
+        });
+        let v1 = store.apply(VALID).unwrap();
+        // 相同的内容不产生新版本
+        assert_eq!(store.apply(VALID).unwrap(), v1);
+
+        let invalid = VALID.replace("^/dynamic-config-test/", "^/dynamic-config-test/(");
+        assert!(store.apply(invalid.as_str()).is_err());
+        let status = store.status();
+        assert_eq!(status.active.as_ref().unwrap().version, v1);
+        assert!(status.last_error.is_some());
+
+        let v2 = store
+            .apply(VALID.replace("rate: 10", "rate: 20").as_str())
+            .unwrap();
+        assert!(v2 > v1);
+        assert_eq!(store.rollback().unwrap(), v1);
+        let status = store.status();
+        assert_eq!(status.previous.as_ref().unwrap().version, v2);
+        assert_eq!(
+            *installed.lock().unwrap(),
+            vec![Some(10), Some(20), Some(10)]
+        );
+    }
+}
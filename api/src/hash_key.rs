@@ -0,0 +1,260 @@
+use crate::app_config::HashKeyRule;
+use crate::consts::BINCODE_CONFIG_STANDARD;
+use anyhow::anyhow;
+use rand::Rng;
+use regex::Regex;
+use std::sync::OnceLock;
+use volo::loadbalance::RequestHash;
+use volo::METAINFO;
+use volo_http::context::ServerContext;
+use volo_http::http::{StatusCode, Uri};
+use volo_http::request::Request;
+use volo_http::response::Response;
+use volo_http::server::middleware::Next;
+use volo_http::server::IntoResponse;
+
+static HASH_KEY_RULES: OnceLock<Vec<CompiledHashKeyRule>> = OnceLock::new();
+
+/// 一致性hash的key来源
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HashKeySource {
+    /// query参数, 如 `query:id`
+    Query(String),
+    /// 请求头, 如 `header:x-user-id`
+    Header(String),
+    /// path中的第n段(从0开始), 如 `/user/123` 的 `path:1` 为 `123`
+    Path(usize),
+}
+
+impl HashKeySource {
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        let Some((kind, name)) = source.split_once(':') else {
+            return Err(anyhow!(
+                "invalid hash key source: {}, expect query:<name>, header:<name> or path:<index>",
+                source
+            ));
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(anyhow!(
+                "invalid hash key source: {}, name is empty",
+                source
+            ));
+        }
+        match kind.trim() {
+            "query" => Ok(HashKeySource::Query(name.to_string())),
+            "header" => Ok(HashKeySource::Header(name.to_lowercase())),
+            "path" => name
+                .parse()
+                .map(HashKeySource::Path)
+                .map_err(|e| anyhow!("invalid hash key source: {}, {}", source, e)),
+            _ => Err(anyhow!(
+                "invalid hash key source: {}, expect query:<name>, header:<name> or path:<index>",
+                source
+            )),
+        }
+    }
+}
+
+pub struct CompiledHashKeyRule {
+    pub path_regex: Regex,
+    pub sources: Vec<HashKeySource>,
+}
+
+impl CompiledHashKeyRule {
+    pub fn compile(rule: &HashKeyRule) -> anyhow::Result<Self> {
+        let path_regex = Regex::new(rule.path.as_str())
+            .map_err(|e| anyhow!("invalid hash key path {}: {}", rule.path, e))?;
+        if rule.sources.is_empty() {
+            return Err(anyhow!("hash key sources of {} is empty", rule.path));
+        }
+        let sources = rule
+            .sources
+            .iter()
+            .map(|x| HashKeySource::parse(x))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            path_regex,
+            sources,
+        })
+    }
+
+    /// 按顺序取出每个来源的值, 所有来源都没有值时返回None
+    fn extract(&self, req: &Request) -> Option<Vec<String>> {
+        let uri = req.uri();
+        let query_pairs: Vec<(String, String)> = uri
+            .query()
+            .and_then(|q| serde_urlencoded::from_str(q).ok())
+            .unwrap_or_default();
+
+        let values: Vec<String> = self
+            .sources
+            .iter()
+            .map(|source| match source {
+                HashKeySource::Query(name) => query_pairs
+                    .iter()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| v.clone()),
+                HashKeySource::Header(name) => req
+                    .headers()
+                    .get(name.as_str())
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_string()),
+                HashKeySource::Path(index) => uri
+                    .path()
+                    .split('/')
+                    .filter(|x| !x.is_empty())
+                    .nth(*index)
+                    .map(|v| v.to_string()),
+            })
+            .map(Option::unwrap_or_default)
+            .collect();
+
+        if values.iter().all(|x| x.is_empty()) {
+            None
+        } else {
+            Some(values)
+        }
+    }
+}
+
+/// 启动时编译hash key规则
+pub fn init_hash_key(rules: &[HashKeyRule]) -> anyhow::Result<()> {
+    let compiled = rules
+        .iter()
+        .map(CompiledHashKeyRule::compile)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let _ = HASH_KEY_RULES.set(compiled);
+    Ok(())
+}
+
+/// 计算一致性hash值: bincode编码后用murmur3
+pub fn request_hash<T: bincode::Encode>(key: T) -> u64 {
+    let bytes = bincode::encode_to_vec(key, BINCODE_CONFIG_STANDARD).unwrap();
+    mur3::murmurhash3_x64_128(bytes.as_slice(), 0).0
+}
+
+/// 用第一个匹配path的规则取出的key计算hash, 没有匹配的规则或者取不到值时使用随机数
+fn hash_of(rules: &[CompiledHashKeyRule], path: &str, req: &Request) -> u64 {
+    let key = rules
+        .iter()
+        .find(|r| r.path_regex.is_match(path))
+        .and_then(|rule| rule.extract(req));
+    match key {
+        Some(key) => request_hash(key),
+        None => request_hash(rand::rng().random::<i32>()),
+    }
+}
+
+/// 如果 load_balance 用的ConsistentHashBalance, 则需要在本地变量（类似于java中的ThreadLocal变量）设置RequestHash,
+/// 这里按配置的规则从请求中提取key计算hash, 相同key的请求会落到同一个rpc实例;
+/// 没有匹配的规则或者取不到值时使用随机数
+pub async fn do_hash_key(
+    uri: Uri,
+    cx: &mut ServerContext,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let rules = HASH_KEY_RULES.get().map(Vec::as_slice).unwrap_or_default();
+    let hash = hash_of(rules, uri.path(), &req);
+    METAINFO.with(|m| {
+        m.borrow_mut().insert(RequestHash(hash));
+    });
+
+    let response = next.run(cx, req).await;
+    let Ok(r) = response else {
+        return Ok(response.into_response());
+    };
+    Ok(r)
+}
+
+#[cfg(test)]
+mod hash_key_test {
+    use super::*;
+
+    #[test]
+    fn parse_hash_key_source() {
+        assert_eq!(
+            HashKeySource::parse("query:id").unwrap(),
+            HashKeySource::Query("id".to_string())
+        );
+        assert_eq!(
+            HashKeySource::parse("header:X-User-Id").unwrap(),
+            HashKeySource::Header("x-user-id".to_string())
+        );
+        assert_eq!(
+            HashKeySource::parse("path:1").unwrap(),
+            HashKeySource::Path(1)
+        );
+        assert!(HashKeySource::parse("cookie:id").is_err());
+        assert!(HashKeySource::parse("path:x").is_err());
+        assert!(HashKeySource::parse("id").is_err());
+    }
+
+    #[test]
+    fn same_key_same_hash() {
+        let a = request_hash(vec!["123".to_string()]);
+        let b = request_hash(vec!["123".to_string()]);
+        let c = request_hash(vec!["124".to_string()]);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    fn rule(path: &str, sources: &[&str]) -> CompiledHashKeyRule {
+        CompiledHashKeyRule::compile(&HashKeyRule {
+            path: path.to_string(),
+            sources: sources.iter().map(|x| x.to_string()).collect(),
+        })
+        .unwrap()
+    }
+
+    fn request(uri: &str, header: Option<(&str, &str)>) -> Request {
+        let mut builder = volo_http::http::Request::builder().uri(uri);
+        if let Some((name, value)) = header {
+            builder = builder.header(name, value);
+        }
+        builder.body(volo_http::body::Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn extract_from_query_header_and_path() {
+        let rule = rule("^/user/", &["query:id", "header:x-user-id", "path:2"]);
+        let req = request("/user/query/42?name=a&id=7", Some(("X-User-Id", "9")));
+        assert_eq!(
+            rule.extract(&req).unwrap(),
+            vec!["7".to_string(), "9".to_string(), "42".to_string()]
+        );
+
+        // 部分来源没有值时用空串占位
+        let req = request("/user/query", Some(("x-user-id", "9")));
+        assert_eq!(
+            rule.extract(&req).unwrap(),
+            vec![String::new(), "9".to_string(), String::new()]
+        );
+
+        // 所有来源都没有值
+        assert!(rule.extract(&request("/user", None)).is_none());
+    }
+
+    #[test]
+    fn random_hash_without_key() {
+        let rules = vec![rule("^/order/", &["query:id"])];
+        let with_key = request("/order/query-one?id=1", None);
+        assert_eq!(
+            hash_of(&rules, "/order/query-one", &with_key),
+            request_hash(vec!["1".to_string()])
+        );
+
+        // 取不到key或者没有匹配的规则时使用随机数
+        let missing = request("/order/query-one", None);
+        let hashes: Vec<u64> = (0..4)
+            .map(|_| hash_of(&rules, "/order/query-one", &missing))
+            .collect();
+        assert!(hashes.iter().any(|x| *x != hashes[0]));
+        let unmatched = request("/user/query-one?id=1", None);
+        assert_ne!(
+            hash_of(&rules, "/user/query-one", &unmatched),
+            hash_of(&rules, "/user/query-one", &unmatched)
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use crate::app_config::HedgeConfig;
+use crate::hash_key::request_hash;
+use crate::prometheus::EXPONENTIAL_SECONDS;
+use crate::retry;
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use volo::loadbalance::RequestHash;
+use volo::METAINFO;
+use volo_grpc::Status;
+
+lazy_static! {
+    /// 服务名/方法名 -> 最近的rpc耗时
+    static ref HISTOGRAMS: DashMap<String, Arc<LatencyHistogram>> = DashMap::new();
+}
+
+/// `[clients.<service>.hedge]` 中的对冲配置
+static HEDGE_RULES: OnceLock<HashMap<String, HedgeConfig>> = OnceLock::new();
+
+/// 耗时桶的数量, 最后一个桶为 +Inf
+const BUCKETS: usize = EXPONENTIAL_SECONDS.len() + 1;
+/// 统计窗口, 分位数取当前窗口和上一个窗口的样本
+const WINDOW: Duration = Duration::from_secs(60);
+/// 样本少于这个数量时分位数不可信, 使用固定延迟
+const MIN_SAMPLES: u64 = 100;
+/// 计算对冲请求的一致性hash, 和重试的次数区分开
+const HEDGE_SEED: u32 = u32::MAX;
+
+struct Window {
+    since: Instant,
+    current: [u64; BUCKETS],
+    previous: [u64; BUCKETS],
+}
+
+/// 和 `http_requests_duration_seconds` 相同分桶的耗时直方图, 按窗口滚动
+pub struct LatencyHistogram {
+    inner: Mutex<Window>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Window {
+                since: Instant::now(),
+                current: [0; BUCKETS],
+                previous: [0; BUCKETS],
+            }),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Window> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let elapsed = inner.since.elapsed();
+        if elapsed >= WINDOW {
+            // 超过两个窗口没有样本时上一个窗口也已经过期
+            inner.previous = if elapsed >= WINDOW * 2 {
+                [0; BUCKETS]
+            } else {
+                inner.current
+            };
+            inner.current = [0; BUCKETS];
+            inner.since = Instant::now();
+        }
+        inner
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        let idx = EXPONENTIAL_SECONDS
+            .iter()
+            .position(|x| secs <= *x)
+            .unwrap_or(BUCKETS - 1);
+        self.lock().current[idx] += 1;
+    }
+
+    /// 与prometheus的 `histogram_quantile` 相同的算法: 在分位数所在的桶内线性插值,
+    /// 落在 +Inf 桶时返回最大的有限边界; 样本不足时返回None
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let counts: Vec<u64> = {
+            let inner = self.lock();
+            inner
+                .current
+                .iter()
+                .zip(inner.previous.iter())
+                .map(|(a, b)| a + b)
+                .collect()
+        };
+        let total: u64 = counts.iter().sum();
+        if total < MIN_SAMPLES {
+            return None;
+        }
+        let rank = q.clamp(0.0, 1.0) * total as f64;
+        let mut cumulative = 0;
+        for (idx, count) in counts.iter().enumerate() {
+            if ((cumulative + count) as f64) < rank || *count == 0 {
+                cumulative += count;
+                continue;
+            }
+            let Some(upper) = EXPONENTIAL_SECONDS.get(idx) else {
+                break;
+            };
+            let lower = idx.checked_sub(1).map_or(0.0, |x| EXPONENTIAL_SECONDS[x]);
+            let secs = lower + (upper - lower) * (rank - cumulative as f64) / *count as f64;
+            return Some(Duration::from_secs_f64(secs));
+        }
+        EXPONENTIAL_SECONDS
+            .last()
+            .map(|x| Duration::from_secs_f64(*x))
+    }
+}
+
+/// 启动时加载对冲配置
+pub fn init_hedge(rules: HashMap<String, HedgeConfig>) {
+    let _ = HEDGE_RULES.set(rules);
+}
+
+fn hedge_config(service: &str, method: &str) -> Option<&'static HedgeConfig> {
+    HEDGE_RULES
+        .get()?
+        .get(service)
+        .filter(|x| x.methods.iter().any(|m| m == method))
+}
+
+fn histogram(service: &str, method: &str) -> Arc<LatencyHistogram> {
+    let key = format!("{}/{}", service, method);
+    if let Some(histogram) = HISTOGRAMS.get(key.as_str()) {
+        return histogram.clone();
+    }
+    HISTOGRAMS.entry(key).or_default().clone()
+}
+
+/// 对冲延迟: 优先使用耗时分位数, 样本不足时使用固定延迟, 都没有时不对冲
+fn hedge_delay(config: &HedgeConfig, histogram: &LatencyHistogram) -> Option<Duration> {
+    config
+        .percentile
+        .and_then(|p| histogram.quantile(p / 100.0))
+        .or(config.delay_ms.map(Duration::from_millis))
+}
+
+/// 在派生的METAINFO中运行对冲请求, 换一个一致性hash值让负载均衡选择另一个实例,
+/// 不影响第一个请求和之后的rpc调用
+fn with_hedge_hash<Fut: Future>(fut: Fut) -> impl Future<Output = Fut::Output> {
+    let metainfo = METAINFO
+        .try_with(|m| {
+            let mut m = m.borrow_mut();
+            let (current, mut hedged) = std::mem::take(&mut *m).derive();
+            *m = current;
+            if let Some(hash) = hedged.get::<RequestHash>().map(|x| x.0) {
+                hedged.insert(RequestHash(request_hash((hash, HEDGE_SEED))));
+            }
+            hedged
+        })
+        .unwrap_or_default();
+    METAINFO.scope(RefCell::new(metainfo), fut)
+}
+
+/// 对配置了对冲的幂等方法, 第一个请求超过延迟还没有返回时发送对冲请求, 使用先成功的结果,
+/// 另一个请求随future一起被取消。对冲请求和第一个请求共用一个并发许可
+pub async fn call_with_hedge<F, Fut>(
+    service: &str,
+    method: &str,
+    idempotent: bool,
+    mut call: F,
+) -> Result<serde_json::Value, Status>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<serde_json::Value, Status>>,
+{
+    let Some(config) = hedge_config(service, method).filter(|_| idempotent) else {
+        return call().await;
+    };
+    let histogram = histogram(service, method);
+    let delay = hedge_delay(config, &histogram);
+
+    let start = Instant::now();
+    let first = call();
+    tokio::pin!(first);
+    let result = match delay {
+        None => first.await,
+        Some(delay) => tokio::select! {
+            result = &mut first => result,
+            _ = tokio::time::sleep(delay) => {
+                if retry::budget().try_retry() {
+                    metrics::counter!("rpc_hedged_requests_total", "service" => service.to_string(), "method" => method.to_string())
+                        .increment(1);
+                    let second = with_hedge_hash(call());
+                    tokio::pin!(second);
+                    // 先返回的请求失败时等待另一个请求
+                    tokio::select! {
+                        result = &mut first => match result {
+                            Ok(_) => result,
+                            Err(_) => second.await,
+                        },
+                        result = &mut second => match result {
+                            Ok(_) => {
+                                metrics::counter!("rpc_hedge_wins_total", "service" => service.to_string(), "method" => method.to_string())
+                                    .increment(1);
+                                result
+                            }
+                            Err(_) => first.await,
+                        },
+                    }
+                } else {
+                    first.await
+                }
+            }
+        },
+    };
+    if result.is_ok() {
+        histogram.record(start.elapsed());
+    }
+    result
+}
+
+#[cfg(test)]
+mod hedge_test {
+    use super::*;
+
+    #[test]
+    fn quantile_of_histogram() {
+        let histogram = LatencyHistogram::default();
+        for _ in 0..50 {
+            histogram.record(Duration::from_millis(3));
+        }
+        assert_eq!(histogram.quantile(0.5), None);
+        for _ in 0..50 {
+            histogram.record(Duration::from_millis(20));
+        }
+        // 第一个桶 0~5ms, 第二个桶 5~10ms, 第三个桶 10~25ms
+        let p50 = histogram.quantile(0.5).unwrap();
+        assert!((p50.as_secs_f64() - 0.005).abs() < 1e-6);
+        let p90 = histogram.quantile(0.9).unwrap();
+        assert!(p90 > Duration::from_millis(10) && p90 <= Duration::from_millis(25));
+
+        for _ in 0..1000 {
+            histogram.record(Duration::from_secs(60));
+        }
+        assert_eq!(histogram.quantile(0.99), Some(Duration::from_secs(10)));
+    }
+
+    #[tokio::test]
+    async fn hedge_slow_request() {
+        init_hedge(HashMap::from([(
+            "hedge-test".to_string(),
+            HedgeConfig {
+                methods: vec!["GetUser".to_string()],
+                delay_ms: Some(10),
+                percentile: None,
+            },
+        )]));
+        let mut calls = 0;
+        let start = Instant::now();
+        let result = call_with_hedge("hedge-test", "GetUser", true, || {
+            calls += 1;
+            let n = calls;
+            async move {
+                // 第一个请求很慢, 对冲请求立即返回
+                if n == 1 {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+                Ok(serde_json::Value::from(n))
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), serde_json::Value::from(2));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
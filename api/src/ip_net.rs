@@ -0,0 +1,74 @@
+use anyhow::anyhow;
+use std::net::IpAddr;
+
+/// 单个ip或网段, 如 `10.0.0.1`、`10.0.0.0/8`, 用于可信代理和管理接口的来源地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| anyhow!("invalid ip or network {}: {}", s, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|x| *x <= max)
+                .ok_or_else(|| anyhow!("invalid ip or network {}, prefix expect 0..={}", s, max))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // ipv4映射的ipv6地址按ipv4比较, 如 ::ffff:10.0.0.1
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            _ => ip,
+        };
+        let (net, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                32 - self.prefix as u32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                (u128::from(net), u128::from(ip), 128 - self.prefix as u32)
+            }
+            _ => return false,
+        };
+        net.checked_shr(bits) == ip.checked_shr(bits)
+    }
+}
+
+#[cfg(test)]
+mod ip_net_test {
+    use super::*;
+
+    #[test]
+    fn contains_ip() {
+        let net = IpNet::parse("10.0.0.0/8").unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+
+        let single = IpNet::parse("::1").unwrap();
+        assert!(single.contains("::1".parse().unwrap()));
+        assert!(!single.contains("127.0.0.1".parse().unwrap()));
+        assert!(IpNet::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("1.2.3.4".parse().unwrap()));
+
+        assert!(IpNet::parse("10.0.0.0/33").is_err());
+        assert!(IpNet::parse("localhost").is_err());
+    }
+}
@@ -0,0 +1,383 @@
+use crate::app_config::{DynamicConfig, LaneRule};
+use crate::consts;
+use crate::svc_discover::instance_tag;
+use arc_swap::ArcSwap;
+use async_broadcast::{Receiver, RecvError};
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use volo::context::Endpoint;
+use volo::discovery::{diff_address, Change, Discover, Instance};
+use volo::FastStr;
+use volo::METAINFO;
+use volo_http::context::ServerContext;
+use volo_http::http::StatusCode;
+use volo_http::request::Request;
+use volo_http::response::Response;
+use volo_http::server::middleware::Next;
+use volo_http::server::IntoResponse;
+
+lazy_static! {
+    /// 服务名 -> 泳道规则
+    static ref LANE_RULES: ArcSwap<HashMap<String, Vec<LaneRule>>> = ArcSwap::default();
+    /// 服务名 -> 请求过的泳道, 实例变化时这些泳道的缓存也需要更新
+    static ref SEEN_LANES: DashMap<String, HashSet<String>> = DashMap::new();
+    /// 服务名 -> 实例tag中的泳道, 请求头指定的泳道必须在这里或者泳道规则中
+    static ref INSTANCE_LANES: DashMap<String, HashSet<String>> = DashMap::new();
+}
+
+/// 每个请求的泳道信息, 由 `do_lane` 放入 METAINFO
+#[derive(Debug, Clone)]
+pub struct RequestLane {
+    /// 请求头 `x-lane` 指定的泳道
+    pub header: Option<FastStr>,
+    /// 0..100 的随机数, 用于按百分比分流, 同一个请求调用多个服务时保持一致
+    pub roll: u32,
+}
+
+/// 按服务分组后整体替换, 配置的合法性由 `DynamicConfig::validate` 保证
+pub fn reset_lane_rules(dynamic_config: DynamicConfig) {
+    let rules_by_service = group_by_service(dynamic_config.lane.unwrap_or_default());
+    for (svc, rules) in rules_by_service.iter() {
+        tracing::info!("reset lane rules for {}: {:?}", svc, rules);
+    }
+    LANE_RULES.store(Arc::new(rules_by_service));
+}
+
+fn group_by_service(rules: Vec<LaneRule>) -> HashMap<String, Vec<LaneRule>> {
+    let mut rules_by_service: HashMap<String, Vec<LaneRule>> = HashMap::new();
+    for rule in rules {
+        rules_by_service
+            .entry(rule.service.clone())
+            .or_default()
+            .push(rule);
+    }
+    rules_by_service
+}
+
+/// 从请求头中取出泳道信息, 放入METAINFO, 由 `LaneDiscover` 选择实例
+pub async fn do_lane(
+    cx: &mut ServerContext,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let header = req
+        .headers()
+        .get(consts::LANE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .map(|v| FastStr::new(v));
+    let roll = rand::rng().random_range(0..100u32);
+    METAINFO.with(|m| {
+        m.borrow_mut().insert(RequestLane { header, roll });
+    });
+
+    let response = next.run(cx, req).await;
+    let Ok(r) = response else {
+        return Ok(response.into_response());
+    };
+    Ok(r)
+}
+
+/// 当前请求调用 `service_name` 时应该走的泳道, None 表示基准泳道
+fn select_lane(service_name: &str) -> Option<FastStr> {
+    select_lane_by(&LANE_RULES.load(), service_name)
+}
+
+fn select_lane_by(
+    lane_rules: &HashMap<String, Vec<LaneRule>>,
+    service_name: &str,
+) -> Option<FastStr> {
+    let request_lane = METAINFO
+        .try_with(|m| m.borrow().get::<RequestLane>().cloned())
+        .ok()
+        .flatten()?;
+    let rules = lane_rules.get(service_name);
+    if let Some(header) = request_lane.header {
+        // 未知的泳道走基准泳道, 避免任意请求头值产生新的负载均衡缓存
+        let known = rules.is_some_and(|x| x.iter().any(|r| r.lane == header.as_str()))
+            || INSTANCE_LANES
+                .get(service_name)
+                .is_some_and(|x| x.contains(header.as_str()));
+        return known.then_some(header);
+    }
+
+    let rules = rules?;
+    let mut bound = 0;
+    for rule in rules.iter() {
+        bound += rule.percent;
+        if request_lane.roll < bound {
+            return Some(FastStr::new(rule.lane.as_str()));
+        }
+    }
+    None
+}
+
+fn lane_key(service_name: &FastStr, lane: &str) -> FastStr {
+    FastStr::from_string(format!("{}#{}", service_name, lane))
+}
+
+fn instance_lane(instance: &Instance) -> Option<&str> {
+    instance_tag(instance, consts::TAG_LANE)
+}
+
+/// 记录服务实例中的所有泳道
+fn record_instance_lanes(service_name: &str, instances: &[Arc<Instance>]) {
+    let lanes: HashSet<String> = instances
+        .iter()
+        .filter_map(|x| instance_lane(x).map(|l| l.to_string()))
+        .collect();
+    INSTANCE_LANES.insert(service_name.to_string(), lanes);
+}
+
+/// 过滤出某个泳道的实例, 泳道没有实例时回退到基准泳道(没有lane tag的实例)
+fn filter_instances(instances: &[Arc<Instance>], lane: Option<&str>) -> Vec<Arc<Instance>> {
+    if let Some(lane) = lane {
+        let lane_instances: Vec<Arc<Instance>> = instances
+            .iter()
+            .filter(|x| instance_lane(x) == Some(lane))
+            .cloned()
+            .collect();
+        if !lane_instances.is_empty() {
+            return lane_instances;
+        }
+    }
+    instances
+        .iter()
+        .filter(|x| instance_lane(x).is_none())
+        .cloned()
+        .collect()
+}
+
+/// 在服务发现和负载均衡之间按泳道过滤实例, 每个泳道使用单独的key, 负载均衡器会为每个泳道单独缓存实例
+#[derive(Clone)]
+pub struct LaneDiscover<D> {
+    pub inner: D,
+    pub svc_change_sender: async_broadcast::Sender<Change<FastStr>>,
+    pub svc_change_receiver: async_broadcast::Receiver<Change<FastStr>>,
+}
+
+impl<D> LaneDiscover<D>
+where
+    D: Discover<Key = FastStr>,
+{
+    pub fn new(inner: D) -> Self {
+        let (mut svc_ch_s, svc_ch_r) = async_broadcast::broadcast(100);
+        svc_ch_s.set_overflow(true);
+
+        if let Some(mut r) = inner.watch(None) {
+            let s = svc_ch_s.clone();
+            let lane_svc_instance = DashMap::new();
+            tokio::spawn(async move {
+                loop {
+                    match r.recv().await {
+                        Ok(recv) => {
+                            for ch in split_change(recv, &lane_svc_instance) {
+                                let _ = s.try_broadcast(ch);
+                            }
+                        }
+                        Err(RecvError::Closed) => break,
+                        Err(err) => tracing::warn!("lane discovering error: {:?}", err),
+                    }
+                }
+            });
+        }
+
+        Self {
+            inner,
+            svc_change_sender: svc_ch_s,
+            svc_change_receiver: svc_ch_r,
+        }
+    }
+}
+
+/// 把一个服务的实例变化拆分成基准泳道和各个泳道的变化, 通过和上一次的实例对比得到增删改,
+/// 一致性hash之类依赖增量变化的负载均衡器也能正确更新
+fn split_change(
+    change: Change<FastStr>,
+    lane_svc_instance: &DashMap<FastStr, Vec<Arc<Instance>>>,
+) -> Vec<Change<FastStr>> {
+    record_instance_lanes(change.key.as_str(), &change.all);
+    let mut lanes: HashSet<String> = change
+        .all
+        .iter()
+        .chain(change.removed.iter())
+        .filter_map(|x| instance_lane(x).map(|l| l.to_string()))
+        .collect();
+    if let Some(rules) = LANE_RULES.load().get(change.key.as_str()) {
+        lanes.extend(rules.iter().map(|r| r.lane.clone()));
+    }
+    if let Some(seen) = SEEN_LANES.get(change.key.as_str()) {
+        lanes.extend(seen.iter().cloned());
+    }
+
+    let mut keys = vec![(change.key.clone(), None)];
+    keys.extend(
+        lanes
+            .into_iter()
+            .map(|lane| (lane_key(&change.key, lane.as_str()), Some(lane))),
+    );
+
+    let mut ret = Vec::with_capacity(keys.len());
+    for (key, lane) in keys {
+        let new_instance = filter_instances(&change.all, lane.as_deref());
+        let pre_instance = lane_svc_instance
+            .get(key.as_str())
+            .map(|x| x.value().clone())
+            .unwrap_or_default();
+        let (ch, _) = diff_address(key.clone(), pre_instance, new_instance.clone());
+        lane_svc_instance.insert(key, new_instance);
+        ret.push(ch);
+    }
+    ret
+}
+
+impl<D> Discover for LaneDiscover<D>
+where
+    D: Discover<Key = FastStr>,
+{
+    type Key = FastStr;
+    type Error = D::Error;
+
+    async fn discover<'s>(
+        &'s self,
+        endpoint: &'s Endpoint,
+    ) -> Result<Vec<Arc<Instance>>, Self::Error> {
+        let instances = self.inner.discover(endpoint).await?;
+        record_instance_lanes(endpoint.service_name.as_str(), &instances);
+        let lane = select_lane(endpoint.service_name.as_str());
+        if let Some(lane) = lane.as_ref() {
+            SEEN_LANES
+                .entry(endpoint.service_name.to_string())
+                .or_default()
+                .insert(lane.to_string());
+        }
+        Ok(filter_instances(&instances, lane.as_deref()))
+    }
+
+    fn key(&self, endpoint: &Endpoint) -> Self::Key {
+        match select_lane(endpoint.service_name.as_str()) {
+            Some(lane) => lane_key(&endpoint.service_name, lane.as_str()),
+            None => endpoint.service_name.clone(),
+        }
+    }
+
+    fn watch(&self, _keys: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+        Some(self.svc_change_receiver.clone())
+    }
+}
+
+#[cfg(test)]
+mod lane_test {
+    use super::*;
+    use std::borrow::Cow;
+    use std::cell::RefCell;
+    use volo::net::Address;
+
+    fn instance(port: u16, lane: Option<&'static str>) -> Arc<Instance> {
+        let mut tags = std::collections::HashMap::new();
+        if let Some(lane) = lane {
+            tags.insert(Cow::Borrowed(consts::TAG_LANE), Cow::Borrowed(lane));
+        }
+        Arc::new(Instance {
+            address: Address::Ip(format!("127.0.0.1:{}", port).parse().unwrap()),
+            weight: 100,
+            tags,
+        })
+    }
+
+    #[test]
+    fn filter_lane_instances() {
+        let instances = vec![
+            instance(8081, None),
+            instance(8082, None),
+            instance(8083, Some("canary")),
+        ];
+
+        assert_eq!(filter_instances(&instances, None).len(), 2);
+        assert_eq!(filter_instances(&instances, Some("canary")).len(), 1);
+        // 泳道没有实例时回退到基准泳道
+        assert_eq!(filter_instances(&instances, Some("gray")).len(), 2);
+    }
+
+    fn with_lane<T>(header: Option<&'static str>, roll: u32, f: impl FnOnce() -> T) -> T {
+        METAINFO.sync_scope(RefCell::new(Default::default()), || {
+            METAINFO.with(|m| {
+                m.borrow_mut().insert(RequestLane {
+                    header: header.map(FastStr::from_static_str),
+                    roll,
+                })
+            });
+            f()
+        })
+    }
+
+    #[test]
+    fn select_known_lane() {
+        // 不修改全局的泳道规则, 避免和其他测试互相影响
+        let rules = group_by_service(vec![LaneRule {
+            service: "lane-test.rpc".to_string(),
+            lane: "canary".to_string(),
+            percent: 10,
+        }]);
+        record_instance_lanes(
+            "lane-test.rpc",
+            &[instance(8081, None), instance(8082, Some("gray"))],
+        );
+
+        let select =
+            |header, roll| with_lane(header, roll, || select_lane_by(&rules, "lane-test.rpc"));
+        // 按百分比分流
+        assert_eq!(select(None, 5).as_deref(), Some("canary"));
+        assert_eq!(select(None, 50), None);
+        // 请求头中的泳道在规则或实例中
+        assert_eq!(select(Some("canary"), 50).as_deref(), Some("canary"));
+        assert_eq!(select(Some("gray"), 50).as_deref(), Some("gray"));
+        // 未知的泳道走基准泳道
+        assert_eq!(select(Some("random-1"), 5), None);
+        // 不在请求中时走基准泳道
+        assert_eq!(select_lane_by(&rules, "lane-test.rpc"), None);
+    }
+
+    #[test]
+    fn split_change_by_lane() {
+        let key = FastStr::from_static_str("lane-split.rpc");
+        let lane_svc_instance = DashMap::new();
+        let all = vec![
+            instance(8081, None),
+            instance(8082, None),
+            instance(8083, Some("canary")),
+        ];
+        let change = |all: Vec<Arc<Instance>>, removed: Vec<Arc<Instance>>| Change {
+            key: key.clone(),
+            all: all.clone(),
+            added: all,
+            updated: vec![],
+            removed,
+        };
+
+        let mut changes = split_change(change(all.clone(), vec![]), &lane_svc_instance);
+        changes.sort_by(|a, b| a.key.as_str().cmp(b.key.as_str()));
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].key.as_str(), "lane-split.rpc");
+        assert_eq!(changes[0].all.len(), 2);
+        assert_eq!(changes[1].key.as_str(), "lane-split.rpc#canary");
+        assert_eq!(changes[1].all.len(), 1);
+        assert!(INSTANCE_LANES
+            .get("lane-split.rpc")
+            .unwrap()
+            .contains("canary"));
+
+        // canary实例下线后, canary泳道回退到基准泳道的实例
+        let canary = all[2].clone();
+        let mut changes = split_change(change(all[..2].to_vec(), vec![canary]), &lane_svc_instance);
+        changes.sort_by(|a, b| a.key.as_str().cmp(b.key.as_str()));
+        assert_eq!(changes[1].key.as_str(), "lane-split.rpc#canary");
+        assert_eq!(changes[1].all.len(), 2);
+        assert_eq!(changes[1].added.len(), 2);
+        assert_eq!(changes[1].removed.len(), 1);
+        assert!(INSTANCE_LANES.get("lane-split.rpc").unwrap().is_empty());
+    }
+}
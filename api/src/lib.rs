@@ -1,18 +1,34 @@
 pub mod app_config;
+pub mod circuit_breaker;
+pub mod client;
+pub mod concurrency_limiter;
 pub mod consts;
+pub mod cors;
+pub mod descriptor;
+pub mod dynamic_config;
+pub mod hash_key;
+pub mod hedge;
+pub mod ip_net;
+pub mod lane;
+pub mod openapi;
 pub mod prometheus;
+pub mod registry;
+pub mod retry;
+pub mod route_table;
 pub mod router;
 pub mod svc_discover;
 
 pub mod controller;
 pub mod rate_limiter;
 
-use order::order::OrderServiceClient;
-use user::user::UserServiceClient;
+use client::ClientRegistry;
+use descriptor::ProtoRegistry;
+use std::sync::Arc;
 
 /// 这个结构体里面放每个rpc的客户端
 #[derive(Clone, Default)]
 pub struct ServiceContext {
-    pub rpc_cli_user: Vec<UserServiceClient>,
-    pub rpc_cli_order: Vec<OrderServiceClient>,
+    pub clients: ClientRegistry,
+    /// 运行时加载的idl, 没有配置 `[proto]` 时为None
+    pub proto: Option<Arc<ProtoRegistry>>,
 }
@@ -0,0 +1,524 @@
+use crate::app_config::OpenApiConfig;
+use crate::consts::GRPC_PROXY_PATH;
+use crate::descriptor::ProtoRegistry;
+use crate::route_table::{BindingSource, BindingType, CompiledRoute, ParamMapping, RouteTarget};
+use prost_reflect::{FieldDescriptor, Kind, MessageDescriptor};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use volo_http::http::Method;
+
+/// 默认的文档路径
+pub const DEFAULT_OPENAPI_PATH: &str = "/openapi.json";
+pub const DEFAULT_SWAGGER_UI_PATH: &str = "/swagger-ui";
+const DEFAULT_TITLE: &str = "volo-boot-api";
+/// Swagger UI固定的版本, 升级时需要同时更新 `[openapi]` 中的 ui_css_integrity 和 ui_js_integrity
+pub const SWAGGER_UI_VERSION: &str = "5.17.14";
+
+/// 手写controller路由的文档, 和路由一起在 `router::controller_routes` 中定义
+pub struct ControllerDoc {
+    pub method: Method,
+    pub path: &'static str,
+    pub tag: &'static str,
+    pub summary: &'static str,
+    /// 响应 `R` 中data的schema
+    pub data: Value,
+}
+
+/// 从路由表和idl生成OpenAPI 3文档, 响应统一包装为 `R`, 请求和响应的schema来自idl中的message
+pub struct OpenApiBuilder<'a> {
+    /// 没有idl时只有手写controller的路由
+    proto: Option<&'a ProtoRegistry>,
+    paths: BTreeMap<String, Map<String, Value>>,
+    schemas: Map<String, Value>,
+}
+
+impl<'a> OpenApiBuilder<'a> {
+    pub fn new(proto: Option<&'a ProtoRegistry>) -> Self {
+        let mut schemas = Map::new();
+        schemas.insert(
+            "ErrorDetail".to_string(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "field": { "type": "string" },
+                    "reason": { "type": "string" },
+                },
+                "required": ["reason"],
+            }),
+        );
+        schemas.insert(
+            "R".to_string(),
+            json!({
+                "type": "object",
+                "description": "统一的响应格式, 失败时data为空",
+                "properties": {
+                    "code": { "type": "integer", "format": "int64", "description": "http状态码或业务错误码" },
+                    "msg": { "type": "string", "nullable": true },
+                    "data": { "nullable": true },
+                    "error": { "type": "string", "description": "字符串错误码, 如 USER_NOT_FOUND" },
+                    "details": { "type": "array", "items": { "$ref": "#/components/schemas/ErrorDetail" } },
+                    "request_id": { "type": "string", "description": "请求头 x-request-id" },
+                },
+                "required": ["code", "msg", "data"],
+            }),
+        );
+        Self {
+            proto,
+            paths: BTreeMap::new(),
+            schemas,
+        }
+    }
+
+    /// 路由表和idl注解中的路由
+    pub fn add_routes(&mut self, routes: &[CompiledRoute]) -> &mut Self {
+        for route in routes {
+            self.add_route(route);
+        }
+        self
+    }
+
+    /// 通用grpc转发: 请求和响应都是任意json
+    pub fn add_grpc_proxy(&mut self) -> &mut Self {
+        let parameters =
+            ["service", "method"].map(|name| parameter(name, "path", json!({ "type": "string" })));
+        let operation = json!({
+            "tags": ["grpc-proxy"],
+            "summary": "通用grpc转发, service为idl中的服务全名, 如 order.OrderService",
+            "parameters": parameters,
+            "requestBody": json_body(json!({ "type": "object" })),
+            "responses": responses(self.r_schema(json!({}))),
+        });
+        self.insert(GRPC_PROXY_PATH, "post", operation);
+        self
+    }
+
+    /// 手写controller的路由
+    pub fn add_controllers(&mut self, controllers: &[ControllerDoc]) -> &mut Self {
+        for controller in controllers {
+            let operation = json!({
+                "tags": [controller.tag],
+                "summary": controller.summary,
+                "responses": responses(self.r_schema(controller.data.clone())),
+            });
+            let method = controller.method.as_str().to_lowercase();
+            self.insert(controller.path, method.as_str(), operation);
+        }
+        self
+    }
+
+    pub fn build(&self, title: &str) -> Value {
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": title,
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "paths": self.paths,
+            "components": { "schemas": self.schemas },
+        })
+    }
+
+    fn insert(&mut self, path: &str, method: &str, operation: Value) {
+        self.paths
+            .entry(path.to_string())
+            .or_default()
+            .insert(method.to_string(), operation);
+    }
+
+    fn add_route(&mut self, route: &CompiledRoute) {
+        let rpc = route.rpc.name();
+        // 生成代码的方法也在编译时嵌入的idl中
+        let (input, output) = match &route.rpc {
+            RouteTarget::Dynamic(m) => (Some(m.input.clone()), Some(m.output.clone())),
+            RouteTarget::Generated(_) => self
+                .proto
+                .and_then(|x| x.find_method(route.service.as_str(), rpc))
+                .map(|m| (Some(m.input.clone()), Some(m.output.clone())))
+                .unwrap_or_default(),
+        };
+
+        let mut parameters = vec![];
+        let mut request_body = None;
+        match &route.mapping {
+            ParamMapping::Bindings(bindings) if bindings.is_empty() => {
+                request_body = input.as_ref().map(|x| json_body(self.message_schema(x)));
+            }
+            ParamMapping::Bindings(bindings) => {
+                let mut body_properties = Map::new();
+                for binding in bindings {
+                    let field = input
+                        .as_ref()
+                        .and_then(|x| x.get_field_by_name(binding.field.as_str()));
+                    let schema = match field {
+                        Some(field) if binding.ty == BindingType::Json => self.field_schema(&field),
+                        _ => binding_schema(binding.ty),
+                    };
+                    match &binding.source {
+                        BindingSource::Query(name) => {
+                            parameters.push(parameter(name, "query", schema))
+                        }
+                        BindingSource::Path(name) => {
+                            parameters.push(parameter(name, "path", schema))
+                        }
+                        BindingSource::Header(name) => {
+                            parameters.push(parameter(name, "header", schema))
+                        }
+                        BindingSource::Body(name) => {
+                            body_properties.insert(name.clone(), schema);
+                        }
+                    }
+                }
+                if !body_properties.is_empty() {
+                    request_body = Some(json_body(
+                        json!({ "type": "object", "properties": body_properties }),
+                    ));
+                }
+            }
+            ParamMapping::HttpRule { body, input } => {
+                let path_params = path_params(route.path.as_str());
+                for name in path_params.iter() {
+                    if let Some(field) = input.get_field_by_name(name) {
+                        let schema = self.field_schema(&field);
+                        parameters.push(parameter(name, "path", schema));
+                    }
+                }
+                match body.as_deref() {
+                    Some("*") => request_body = Some(json_body(self.message_schema(input))),
+                    body => {
+                        if let Some(field) = body.and_then(|x| input.get_field_by_name(x)) {
+                            request_body = Some(json_body(self.field_schema(&field)));
+                        }
+                        // 没有被path参数和body绑定的标量字段来自query参数
+                        for field in input.fields() {
+                            let bound = path_params.iter().any(|x| x == field.name())
+                                || body == Some(field.name());
+                            if bound || field.is_map() || matches!(field.kind(), Kind::Message(_)) {
+                                continue;
+                            }
+                            let schema = self.field_schema(&field);
+                            parameters.push(parameter(field.name(), "query", schema));
+                        }
+                    }
+                }
+            }
+        }
+
+        let data = match (output.as_ref(), route.response_body.as_deref()) {
+            (Some(output), None) => self.message_schema(output),
+            (Some(output), Some(field)) => output
+                .get_field_by_name(field)
+                .map(|x| self.field_schema(&x))
+                .unwrap_or_else(|| json!({})),
+            (None, _) => json!({}),
+        };
+        let mut operation = json!({
+            "tags": [route.service],
+            "summary": format!("{}/{}", route.service, rpc),
+            "responses": responses(self.r_schema(data)),
+        });
+        if !parameters.is_empty() {
+            operation["parameters"] = Value::Array(parameters);
+        }
+        // GET和DELETE的请求体会被大部分客户端忽略
+        if route.method != Method::GET && route.method != Method::DELETE {
+            if let Some(request_body) = request_body {
+                operation["requestBody"] = request_body;
+            }
+        }
+        self.insert(
+            route.path.as_str(),
+            route.method.as_str().to_lowercase().as_str(),
+            operation,
+        );
+    }
+
+    /// `R<T>`, message和标量类型生成命名的schema, 如 `R_user.User`、`R_int64`
+    fn r_schema(&mut self, data: Value) -> Value {
+        let name = data["$ref"]
+            .as_str()
+            .and_then(|x| x.rsplit('/').next())
+            .or(data["format"].as_str())
+            .or(data["type"]
+                .as_str()
+                .filter(|x| !matches!(*x, "array" | "object")))
+            .map(|x| format!("R_{}", x));
+        let schema = json!({
+            "allOf": [
+                { "$ref": "#/components/schemas/R" },
+                { "type": "object", "properties": { "data": data } },
+            ],
+        });
+        match name {
+            Some(name) => {
+                let reference = json!({ "$ref": format!("#/components/schemas/{}", name) });
+                self.schemas.insert(name, schema);
+                reference
+            }
+            None => schema,
+        }
+    }
+
+    /// message的schema放在components中, 返回引用; 递归的message只展开一次
+    fn message_schema(&mut self, message: &MessageDescriptor) -> Value {
+        if let Some(schema) = well_known_schema(message.full_name()) {
+            return schema;
+        }
+        let name = message.full_name().to_string();
+        let reference = json!({ "$ref": format!("#/components/schemas/{}", name) });
+        if self.schemas.contains_key(name.as_str()) {
+            return reference;
+        }
+        // 先占位, 字段引用自身时直接返回引用
+        self.schemas.insert(name.clone(), json!({}));
+        let mut properties = Map::new();
+        for field in message.fields() {
+            properties.insert(field.name().to_string(), self.field_schema(&field));
+        }
+        self.schemas
+            .insert(name, json!({ "type": "object", "properties": properties }));
+        reference
+    }
+
+    fn field_schema(&mut self, field: &FieldDescriptor) -> Value {
+        if field.is_map() {
+            let value = match field.kind() {
+                Kind::Message(entry) => entry.map_entry_value_field(),
+                _ => return json!({ "type": "object" }),
+            };
+            return json!({
+                "type": "object",
+                "additionalProperties": self.kind_schema(value.kind()),
+            });
+        }
+        let schema = self.kind_schema(field.kind());
+        if field.is_list() {
+            json!({ "type": "array", "items": schema })
+        } else {
+            schema
+        }
+    }
+
+    /// 与 `message_to_json` 的序列化结果一致: 64位整数为数字, 枚举为名字
+    fn kind_schema(&mut self, kind: Kind) -> Value {
+        match kind {
+            Kind::Double => json!({ "type": "number", "format": "double" }),
+            Kind::Float => json!({ "type": "number", "format": "float" }),
+            Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => {
+                json!({ "type": "integer", "format": "int32" })
+            }
+            Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => {
+                json!({ "type": "integer", "format": "int64" })
+            }
+            Kind::Uint32 | Kind::Fixed32 => {
+                json!({ "type": "integer", "format": "int64", "minimum": 0 })
+            }
+            Kind::Uint64 | Kind::Fixed64 => {
+                json!({ "type": "integer", "format": "int64", "minimum": 0 })
+            }
+            Kind::Bool => json!({ "type": "boolean" }),
+            Kind::String => json!({ "type": "string" }),
+            Kind::Bytes => json!({ "type": "string", "format": "byte" }),
+            Kind::Enum(e) => {
+                let values: Vec<&str> = e.values().map(|x| x.name()).collect();
+                json!({ "type": "string", "enum": values })
+            }
+            Kind::Message(m) => self.message_schema(&m),
+        }
+    }
+}
+
+fn well_known_schema(full_name: &str) -> Option<Value> {
+    let schema = match full_name {
+        "google.protobuf.Timestamp" => json!({ "type": "string", "format": "date-time" }),
+        "google.protobuf.Duration" | "google.protobuf.FieldMask" => json!({ "type": "string" }),
+        "google.protobuf.Struct" => json!({ "type": "object" }),
+        "google.protobuf.Value" | "google.protobuf.Any" => json!({}),
+        "google.protobuf.ListValue" => json!({ "type": "array", "items": {} }),
+        "google.protobuf.Empty" => json!({ "type": "object" }),
+        _ => return None,
+    };
+    Some(schema)
+}
+
+fn binding_schema(ty: BindingType) -> Value {
+    match ty {
+        BindingType::String => json!({ "type": "string" }),
+        BindingType::Int => json!({ "type": "integer", "format": "int64" }),
+        BindingType::Float => json!({ "type": "number", "format": "double" }),
+        BindingType::Bool => json!({ "type": "boolean" }),
+        BindingType::Json => json!({}),
+    }
+}
+
+/// 路由中的path参数, 如 `/order/{id}` 中的 `id`
+fn path_params(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter_map(|x| x.strip_prefix('{')?.strip_suffix('}'))
+        .map(|x| x.to_string())
+        .collect()
+}
+
+fn parameter(name: &str, location: &str, schema: Value) -> Value {
+    json!({
+        "name": name,
+        "in": location,
+        "required": location == "path",
+        "schema": schema,
+    })
+}
+
+fn json_body(schema: Value) -> Value {
+    json!({ "content": { "application/json": { "schema": schema } } })
+}
+
+/// 失败时http状态码按grpc状态码决定, 业务错误码在 `code` 和 `error` 中
+fn responses(schema: Value) -> Value {
+    json!({
+        "200": {
+            "description": "成功",
+            "content": { "application/json": { "schema": schema } },
+        },
+        "default": {
+            "description": "失败",
+            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/R" } } },
+        },
+    })
+}
+
+/// 网关的OpenAPI文档, 在启动时生成一次
+pub fn build_document(
+    config: &OpenApiConfig,
+    routes: &[CompiledRoute],
+    controllers: &[ControllerDoc],
+    proto: Option<&ProtoRegistry>,
+) -> Value {
+    let title = config.title.as_deref().unwrap_or(DEFAULT_TITLE);
+    let mut builder = OpenApiBuilder::new(proto);
+    builder.add_routes(routes);
+    if proto.is_some() {
+        builder.add_grpc_proxy();
+    }
+    builder.add_controllers(controllers).build(title)
+}
+
+/// Swagger UI页面, 静态资源使用CDN上固定版本的文件, 浏览器按配置的SRI哈希校验
+pub fn swagger_ui_html(config: &OpenApiConfig) -> String {
+    format!(
+        r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title>Swagger UI</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui.css" integrity="{css_integrity}" crossorigin="anonymous" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui-bundle.js" integrity="{js_integrity}" crossorigin="anonymous"></script>
+  <script>
+    window.onload = () => {{
+      window.ui = SwaggerUIBundle({{ url: "{spec_url}", dom_id: "#swagger-ui" }});
+    }};
+  </script>
+</body>
+</html>"##,
+        version = SWAGGER_UI_VERSION,
+        css_integrity = config.ui_css_integrity.as_deref().unwrap_or_default(),
+        js_integrity = config.ui_js_integrity.as_deref().unwrap_or_default(),
+        spec_url = config.path(),
+    )
+}
+
+#[cfg(test)]
+mod openapi_test {
+    use super::*;
+    use crate::app_config::RouteConfig;
+    use crate::route_table::compile_routes;
+    use std::collections::HashMap;
+
+    #[test]
+    fn document_of_routes() {
+        let proto = ProtoRegistry::load(None).unwrap();
+        let routes = compile_routes(
+            &[RouteConfig {
+                method: "GET".to_string(),
+                path: "/order/by-user".to_string(),
+                service: "volo-boot-order.rpc".to_string(),
+                rpc: "GetOrder".to_string(),
+                bindings: HashMap::from([("user_id".to_string(), "query:user_id:int".to_string())]),
+            }],
+            Some(&proto),
+        )
+        .unwrap();
+        let controllers = [ControllerDoc {
+            method: Method::GET,
+            path: "/random",
+            tag: "random",
+            summary: "返回一个随机数",
+            data: json!({ "type": "integer", "format": "int32" }),
+        }];
+        let doc = build_document(
+            &OpenApiConfig::default(),
+            &routes,
+            &controllers,
+            Some(&proto),
+        );
+
+        let get_user = &doc["paths"]["/user/query-one"]["get"];
+        let params = get_user["parameters"].as_array().unwrap();
+        assert!(params
+            .iter()
+            .any(|x| x["name"] == "id" && x["in"] == "query" && x["schema"]["format"] == "int64"));
+        assert_eq!(
+            get_user["responses"]["200"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/R_user.User"
+        );
+        let schemas = &doc["components"]["schemas"];
+        assert_eq!(
+            schemas["R_user.User"]["allOf"][1]["properties"]["data"]["$ref"],
+            "#/components/schemas/user.User"
+        );
+        assert_eq!(
+            schemas["user.User"]["properties"]["extra"]["additionalProperties"]["type"],
+            "string"
+        );
+
+        // response_body 为 data 的路由返回 R<i64>
+        assert!(doc["paths"]["/order/random"]["get"].is_object());
+        assert_eq!(
+            schemas["R_int64"]["allOf"][1]["properties"]["data"]["format"],
+            "int64"
+        );
+        let id = &doc["paths"]["/order/{id}"]["get"]["parameters"][0];
+        assert_eq!((&id["name"], &id["in"]), (&json!("id"), &json!("path")));
+        assert_eq!(
+            doc["paths"]["/order/by-user"]["get"]["parameters"][0]["name"],
+            "user_id"
+        );
+        assert!(doc["paths"][GRPC_PROXY_PATH]["post"].is_object());
+        assert_eq!(
+            doc["paths"]["/random"]["get"]["responses"]["200"]["content"]["application/json"]
+                ["schema"]["$ref"],
+            "#/components/schemas/R_int32"
+        );
+    }
+
+    #[test]
+    fn swagger_ui_with_integrity() {
+        let config = OpenApiConfig {
+            ui_css_integrity: Some("sha384-abc".to_string()),
+            ui_js_integrity: Some("sha384-def".to_string()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        let html = swagger_ui_html(&config);
+        assert!(html.contains(&format!("swagger-ui-dist@{}/", SWAGGER_UI_VERSION)));
+        assert!(html.contains(r#"integrity="sha384-def" crossorigin="anonymous""#));
+
+        let config = OpenApiConfig {
+            ui_js_integrity: Some("sha384-\"><script>".to_string()),
+            ..config
+        };
+        assert!(config.validate().is_err());
+        assert!(OpenApiConfig::default().validate().is_err());
+    }
+}
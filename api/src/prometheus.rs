@@ -6,11 +6,12 @@ use volo_http::response::Response;
 use volo_http::server::IntoResponse;
 use volo_http::{context::ServerContext, server::middleware::Next};
 
-pub fn setup_metrics_recorder() -> PrometheusHandle {
-    const EXPONENTIAL_SECONDS: &[f64] = &[
-        0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
-    ];
+/// `http_requests_duration_seconds` 的桶, 对冲请求统计rpc耗时时使用相同的桶
+pub const EXPONENTIAL_SECONDS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
 
+pub fn setup_metrics_recorder() -> PrometheusHandle {
     PrometheusBuilder::new()
         .set_buckets_for_metric(
             Matcher::Full("http_requests_duration_seconds".to_string()),
@@ -0,0 +1,609 @@
+use crate::app_config::{DynamicConfig, RedisConfig, UrlRateConfig};
+use crate::controller::R;
+use crate::ip_net::IpNet;
+use anyhow::anyhow;
+use arc_swap::ArcSwap;
+use lazy_static::lazy_static;
+use regex::RegexSet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tokio_cron_scheduler::JobScheduler;
+use volo::context::Context;
+use volo::net::Address;
+use volo_http::context::ServerContext;
+use volo_http::http::header::RETRY_AFTER;
+use volo_http::http::{HeaderMap, HeaderValue, StatusCode, Uri};
+use volo_http::request::Request;
+use volo_http::response::Response;
+use volo_http::server::extract::Json;
+use volo_http::server::middleware::Next;
+use volo_http::server::IntoResponse;
+
+pub mod redis;
+pub mod token_bucket;
+
+pub use redis::RedisRateLimiter;
+pub use token_bucket::TokenBucketLimiter;
+
+pub const DEFAULT_GROUP: &str = "DEFAULT_GROUP";
+
+lazy_static! {
+    static ref LIMITER_TABLE: ArcSwap<LimiterTable> =
+        ArcSwap::from_pointee(LimiterTable::default());
+}
+
+static JOB_SCHEDULER: OnceCell<JobScheduler> = OnceCell::const_new();
+static REDIS_LIMITER: OnceLock<RedisRateLimiter> = OnceLock::new();
+/// `trusted_proxies` 中的代理地址, 只有来自这些地址的请求才读取 `X-Forwarded-For` 和 `X-Real-IP`
+static TRUSTED_PROXIES: OnceLock<Vec<IpNet>> = OnceLock::new();
+
+pub struct ReteLimiterData {
+    pub url: String,
+    pub method: String,
+    pub key: RateLimitKey,
+    pub backend: LimiterBackend,
+    /// 本地令牌桶, redis不可用时也使用它
+    pub limiter: TokenBucketLimiter,
+}
+
+impl ReteLimiterData {
+    /// 取一个令牌; redis后端不可用时回退到本地令牌桶, 此时限流只对本实例生效
+    pub async fn acquire(&self, key: &str) -> RateLimitDecision {
+        if self.backend == LimiterBackend::Redis {
+            if let Some(redis) = REDIS_LIMITER.get() {
+                let (rate, window, burst) = self.limiter.get_config();
+                let redis_key = format!("{}:{}:{}", self.url, self.method, key);
+                match redis
+                    .acquire(redis_key.as_str(), rate, window, burst, 1)
+                    .await
+                {
+                    Ok(decision) => return decision,
+                    Err(e) => {
+                        tracing::warn!("redis rate limiter unavailable, fallback to local: {}", e)
+                    }
+                }
+            }
+        }
+        self.limiter.acquire(key, 1)
+    }
+}
+
+/// 一次取令牌的结果, 用于生成 `X-RateLimit-*` 响应头
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    /// 桶的容量
+    pub limit: u64,
+    /// 剩余的令牌数
+    pub remaining: u64,
+    /// 桶回满的时间
+    pub reset: Duration,
+    /// 被拒绝时, 攒够本次所需令牌的时间
+    pub retry_after: Duration,
+}
+
+impl RateLimitDecision {
+    pub fn new(
+        allowed: bool,
+        tokens: f64,
+        permits: u64,
+        rate: u64,
+        window: Duration,
+        burst: u64,
+    ) -> Self {
+        let refill_per_ms = rate as f64 / window.as_millis().max(1) as f64;
+        let wait = |missing: f64| {
+            if missing <= 0.0 {
+                Duration::ZERO
+            } else if refill_per_ms <= 0.0 {
+                window
+            } else {
+                Duration::from_millis((missing / refill_per_ms).ceil() as u64)
+            }
+        };
+        Self {
+            allowed,
+            limit: burst,
+            remaining: tokens.max(0.0).floor() as u64,
+            reset: wait(burst as f64 - tokens),
+            retry_after: if allowed {
+                Duration::ZERO
+            } else {
+                wait(permits as f64 - tokens)
+            },
+        }
+    }
+
+    /// 秒数向上取整, 避免客户端过早重试
+    pub fn reset_secs(&self) -> u64 {
+        ceil_secs(self.reset)
+    }
+
+    pub fn retry_after_secs(&self) -> u64 {
+        ceil_secs(self.retry_after).max(1)
+    }
+
+    /// 写入 `X-RateLimit-Limit`, `X-RateLimit-Remaining`, `X-RateLimit-Reset`(秒数),
+    /// 被拒绝时再写入 `Retry-After`
+    pub fn write_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("x-ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("x-ratelimit-reset", HeaderValue::from(self.reset_secs()));
+        if !self.allowed {
+            headers.insert(RETRY_AFTER, HeaderValue::from(self.retry_after_secs()));
+        }
+    }
+}
+
+fn ceil_secs(d: Duration) -> u64 {
+    d.as_millis().div_ceil(1000) as u64
+}
+
+/// 限流状态的存储位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimiterBackend {
+    /// 每个网关实例单独计数
+    Memory,
+    /// 所有网关实例共享redis中的令牌桶
+    Redis,
+}
+
+impl LimiterBackend {
+    pub fn parse(backend: &str) -> anyhow::Result<Self> {
+        match backend.trim() {
+            "memory" => Ok(LimiterBackend::Memory),
+            "redis" => Ok(LimiterBackend::Redis),
+            _ => Err(anyhow!(
+                "invalid rate limiter backend: {}, expect memory or redis",
+                backend
+            )),
+        }
+    }
+}
+
+/// 启动时根据 `[redis]` 配置初始化分布式限流
+pub fn init_redis_limiter(config: &RedisConfig) {
+    tracing::info!("redis rate limiter: {}", config.addr);
+    let _ = REDIS_LIMITER.set(RedisRateLimiter::new(config.clone()));
+}
+
+/// 启动时设置可信的代理地址
+pub fn init_trusted_proxies(proxies: &[String]) -> anyhow::Result<()> {
+    let proxies = proxies
+        .iter()
+        .map(|x| IpNet::parse(x).map_err(|e| anyhow!("invalid trusted_proxies: {}", e)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let _ = TRUSTED_PROXIES.set(proxies);
+    Ok(())
+}
+
+/// 限流key的来源, 每个key单独一个令牌桶
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RateLimitKey {
+    /// 匹配规则的所有请求共用一个桶
+    Global,
+    /// 客户端ip, 对端是可信代理时取 `X-Forwarded-For` 中的地址
+    Ip,
+    /// 请求头, 如 `header:x-api-key`
+    Header(String),
+    /// query参数, 如 `query:user_id`
+    Query(String),
+}
+
+impl RateLimitKey {
+    pub fn parse(key: &str) -> anyhow::Result<Self> {
+        match key.trim() {
+            "global" => return Ok(RateLimitKey::Global),
+            "ip" => return Ok(RateLimitKey::Ip),
+            _ => {}
+        }
+        let Some((kind, name)) = key.split_once(':') else {
+            return Err(anyhow!(
+                "invalid rate limit key: {}, expect global, ip, header:<name> or query:<name>",
+                key
+            ));
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(anyhow!("invalid rate limit key: {}, name is empty", key));
+        }
+        match kind.trim() {
+            "header" => Ok(RateLimitKey::Header(name.to_lowercase())),
+            "query" => Ok(RateLimitKey::Query(name.to_string())),
+            _ => Err(anyhow!(
+                "invalid rate limit key: {}, expect global, ip, header:<name> or query:<name>",
+                key
+            )),
+        }
+    }
+
+    /// 从请求中取出key, 取不到值的请求共用一个桶
+    pub fn extract(&self, cx: &ServerContext, req: &Request) -> String {
+        let value = match self {
+            RateLimitKey::Global => return String::new(),
+            RateLimitKey::Ip => {
+                let proxies = TRUSTED_PROXIES.get().map(Vec::as_slice).unwrap_or_default();
+                client_ip(cx, req, proxies)
+            }
+            RateLimitKey::Header(name) => req
+                .headers()
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+            RateLimitKey::Query(name) => req
+                .uri()
+                .query()
+                .and_then(|q| serde_urlencoded::from_str::<Vec<(String, String)>>(q).ok())
+                .and_then(|pairs| pairs.into_iter().find(|(k, _)| k == name))
+                .map(|(_, v)| v),
+        };
+        value.unwrap_or_default()
+    }
+}
+
+/// 客户端ip: 默认是连接的对端地址, 请求头可以伪造, 只有对端是可信代理时才读取请求头
+fn client_ip(cx: &ServerContext, req: &Request, proxies: &[IpNet]) -> Option<String> {
+    match cx.rpc_info().caller().address() {
+        Some(Address::Ip(addr)) => {
+            Some(forwarded_ip(addr.ip(), req.headers(), proxies).to_string())
+        }
+        Some(addr) => Some(addr.to_string()),
+        None => None,
+    }
+}
+
+/// 对端是可信代理时, 从右往左取 `X-Forwarded-For` 中第一个不是可信代理的地址(都是可信代理时取最左边的),
+/// 没有 `X-Forwarded-For` 时取 `X-Real-IP`
+fn forwarded_ip(peer: IpAddr, headers: &HeaderMap, proxies: &[IpNet]) -> IpAddr {
+    let trusted = |ip: IpAddr| proxies.iter().any(|x| x.contains(ip));
+    if !trusted(peer) {
+        return peer;
+    }
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    let forwarded: Vec<IpAddr> = header("x-forwarded-for")
+        .into_iter()
+        .flat_map(|v| v.split(','))
+        .filter_map(|v| v.trim().parse().ok())
+        .collect();
+    if let Some(leftmost) = forwarded.first() {
+        return forwarded
+            .iter()
+            .rev()
+            .find(|ip| !trusted(**ip))
+            .copied()
+            .unwrap_or(*leftmost);
+    }
+    header("x-real-ip")
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+pub async fn get_job_scheduler() -> &'static JobScheduler {
+    JOB_SCHEDULER
+        .get_or_init(|| async {
+            let sched = JobScheduler::new().await.unwrap();
+            sched
+        })
+        .await
+}
+
+/// 重建限流规则表后整体替换, 任何一条规则不合法时保留原来的规则表
+pub fn reset_limiter(dynamic_config: DynamicConfig) -> anyhow::Result<()> {
+    let mode = MatchMode::parse(dynamic_config.url_rate_mode.as_deref().unwrap_or("all"))?;
+    let url_rate = dynamic_config.url_rate.unwrap_or_default();
+
+    let table = LimiterTable::build(url_rate, mode, &LIMITER_TABLE.load())?;
+    tracing::info!(
+        "reset rate limiter: {} rules, mode {:?}",
+        table.rules_count(),
+        table.mode
+    );
+    LIMITER_TABLE.store(Arc::new(table));
+    Ok(())
+}
+
+/// 多条限流规则匹配同一个请求时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchMode {
+    /// 所有匹配的规则都消耗令牌
+    #[default]
+    All,
+    /// 只使用优先级最高的一条规则
+    First,
+}
+
+impl MatchMode {
+    pub fn parse(mode: &str) -> anyhow::Result<Self> {
+        match mode.trim() {
+            "all" => Ok(MatchMode::All),
+            "first" => Ok(MatchMode::First),
+            _ => Err(anyhow!(
+                "invalid url_rate_mode: {}, expect all or first",
+                mode
+            )),
+        }
+    }
+}
+
+/// 同一个http方法的规则, 按优先级排序, 与 `set` 中的正则一一对应
+#[derive(Default)]
+struct MethodRules {
+    set: RegexSet,
+    rules: Vec<Arc<ReteLimiterData>>,
+}
+
+/// 编译后的限流规则表, 每个http方法一个 `RegexSet`, 一次匹配得到所有命中的规则;
+/// 配置变更时整体重建后替换
+#[derive(Default)]
+pub struct LimiterTable {
+    mode: MatchMode,
+    by_method: HashMap<String, MethodRules>,
+}
+
+impl LimiterTable {
+    /// 编译限流规则; 配置没有变化的规则沿用 `old` 中的令牌桶, 不重置计数
+    pub fn build(
+        url_rate: Vec<UrlRateConfig>,
+        mode: MatchMode,
+        old: &LimiterTable,
+    ) -> anyhow::Result<Self> {
+        let old_rules: HashMap<(&str, &str), &Arc<ReteLimiterData>> = old
+            .by_method
+            .values()
+            .flat_map(|x| x.rules.iter())
+            .map(|x| ((x.url.as_str(), x.method.as_str()), x))
+            .collect();
+
+        // (优先级, 配置中的顺序, 规则)
+        let mut rules: HashMap<String, Vec<(i32, usize, Arc<ReteLimiterData>)>> = HashMap::new();
+        for (order, urc) in url_rate.into_iter().enumerate() {
+            urc.validate()
+                .map_err(|e| anyhow!("url_rate {}: {}", urc.url, e))?;
+            let window = Duration::from_secs(urc.window.unwrap_or(1));
+            let burst = urc.burst.unwrap_or(urc.rate);
+            let key_selector = RateLimitKey::parse(urc.key.as_deref().unwrap_or("global"))?;
+            let backend = LimiterBackend::parse(urc.backend.as_deref().unwrap_or("memory"))?;
+            if backend == LimiterBackend::Redis && REDIS_LIMITER.get().is_none() {
+                tracing::warn!(
+                    "rate limiter of {} uses redis but [redis] is not configured, use memory",
+                    urc.url
+                );
+            }
+            let priority = urc.priority.unwrap_or(0);
+
+            for m in urc.method.iter() {
+                let lower_method = m.to_lowercase();
+                let data = match old_rules.get(&(urc.url.as_str(), lower_method.as_str())) {
+                    Some(x)
+                        if x.limiter.get_config() == (urc.rate, window, burst)
+                            && x.key == key_selector
+                            && x.backend == backend =>
+                    {
+                        tracing::info!("skip reset limiter for {:?}", urc);
+                        (*x).clone()
+                    }
+                    _ => {
+                        tracing::info!(
+                            "reset rate limiter for {}:{}: rate {} per {:?}, burst {}, key {:?}, backend {:?}",
+                            urc.url,
+                            lower_method,
+                            urc.rate,
+                            window,
+                            burst,
+                            key_selector,
+                            backend
+                        );
+                        Arc::new(ReteLimiterData {
+                            url: urc.url.clone(),
+                            method: lower_method.clone(),
+                            key: key_selector.clone(),
+                            backend,
+                            limiter: TokenBucketLimiter::new(urc.rate, window, burst),
+                        })
+                    }
+                };
+                rules
+                    .entry(lower_method)
+                    .or_default()
+                    .push((priority, order, data));
+            }
+        }
+
+        let mut by_method = HashMap::new();
+        for (method, mut method_rules) in rules {
+            method_rules.sort_by_key(|(priority, order, _)| (*priority, *order));
+            let rules: Vec<Arc<ReteLimiterData>> =
+                method_rules.into_iter().map(|(_, _, x)| x).collect();
+            // 单个正则都已经校验过, 这里只可能因为总大小超限失败
+            let set = RegexSet::new(rules.iter().map(|x| x.url.as_str()))
+                .map_err(|e| anyhow!("compile rate limiter rules for {}: {}", method, e))?;
+            by_method.insert(method, MethodRules { set, rules });
+        }
+        Ok(Self { mode, by_method })
+    }
+
+    /// 按优先级返回命中的规则
+    pub fn matches(&self, path: &str, method: &str) -> Vec<Arc<ReteLimiterData>> {
+        let Some(method_rules) = self.by_method.get(method) else {
+            return vec![];
+        };
+        let matched = method_rules
+            .set
+            .matches(path)
+            .into_iter()
+            .map(|i| method_rules.rules[i].clone());
+        match self.mode {
+            MatchMode::All => matched.collect(),
+            MatchMode::First => matched.take(1).collect(),
+        }
+    }
+
+    pub fn rules_count(&self) -> usize {
+        self.by_method.values().map(|x| x.rules.len()).sum()
+    }
+}
+
+pub async fn do_rate_limiter(
+    uri: Uri,
+    cx: &mut ServerContext,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let path = uri.path();
+    let method = req.method().to_string().to_lowercase();
+
+    let mut limited = None;
+    let matched = LIMITER_TABLE.load().matches(path, method.as_str());
+    if !matched.is_empty() {
+        // 多条规则匹配时, 响应头取剩余令牌最少的一条
+        let mut tightest: Option<RateLimitDecision> = None;
+        for m in matched {
+            let key = m.key.extract(cx, &req);
+            let decision = m.acquire(key.as_str()).await;
+            if !decision.allowed {
+                return Ok(too_many_requests(&decision));
+            }
+            if tightest
+                .filter(|x| x.remaining <= decision.remaining)
+                .is_none()
+            {
+                tightest = Some(decision);
+            }
+        }
+        limited = tightest;
+    }
+
+    let response = next.run(cx, req).await;
+    let Ok(mut r) = response else {
+        return Ok(response.into_response());
+    };
+    if let Some(decision) = limited {
+        decision.write_headers(r.headers_mut());
+    }
+    Ok(r)
+}
+
+/// 429响应, 使用 `R` 格式的响应体
+fn too_many_requests(decision: &RateLimitDecision) -> Response {
+    let body = R::<()>::error_status_code(StatusCode::TOO_MANY_REQUESTS, "too many requests");
+    let mut resp = (StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response();
+    decision.write_headers(resp.headers_mut());
+    resp
+}
+
+#[cfg(test)]
+mod regex_test {
+    use super::{forwarded_ip, LimiterTable, MatchMode, RateLimitDecision, RateLimitKey};
+    use crate::app_config::UrlRateConfig;
+    use crate::ip_net::IpNet;
+    use regex::Regex;
+    use std::time::Duration;
+    use volo_http::http::{HeaderMap, HeaderValue};
+
+    #[test]
+    fn regex_test() {
+        let re = Regex::new("/user.*").unwrap();
+        assert_eq!(re.is_match("/user/query-one"), true);
+    }
+
+    #[test]
+    fn parse_rate_limit_key() {
+        assert_eq!(RateLimitKey::parse("global").unwrap(), RateLimitKey::Global);
+        assert_eq!(RateLimitKey::parse("ip").unwrap(), RateLimitKey::Ip);
+        assert_eq!(
+            RateLimitKey::parse("header:X-Api-Key").unwrap(),
+            RateLimitKey::Header("x-api-key".to_string())
+        );
+        assert_eq!(
+            RateLimitKey::parse("query:user_id").unwrap(),
+            RateLimitKey::Query("user_id".to_string())
+        );
+        assert!(RateLimitKey::parse("cookie:id").is_err());
+        assert!(RateLimitKey::parse("header:").is_err());
+    }
+
+    #[test]
+    fn rate_limit_headers() {
+        let decision = RateLimitDecision::new(false, 0.5, 1, 10, Duration::from_secs(1), 20);
+        let mut headers = HeaderMap::new();
+        decision.write_headers(&mut headers);
+        assert_eq!(headers["x-ratelimit-limit"], "20");
+        assert_eq!(headers["x-ratelimit-remaining"], "0");
+        assert_eq!(headers["x-ratelimit-reset"], "2");
+        // 还差0.5个令牌, 50ms后可用, 向上取整为1秒
+        assert_eq!(headers["retry-after"], "1");
+
+        let decision = RateLimitDecision::new(true, 5.0, 1, 10, Duration::from_secs(1), 20);
+        let mut headers = HeaderMap::new();
+        decision.write_headers(&mut headers);
+        assert_eq!(headers["x-ratelimit-remaining"], "5");
+        assert!(!headers.contains_key("retry-after"));
+    }
+
+    fn url_rate(url: &str, method: &str, rate: u64, priority: Option<i32>) -> UrlRateConfig {
+        UrlRateConfig {
+            url: url.to_string(),
+            method: vec![method.to_string()],
+            rate,
+            priority,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn limiter_table_priority() {
+        let rules = vec![
+            url_rate("^/user/", "GET", 10, None),
+            url_rate("^/user/query-one", "GET", 1, Some(-1)),
+            url_rate("^/order/", "GET", 10, None),
+            url_rate("^/user/", "POST", 10, None),
+        ];
+        let table =
+            LimiterTable::build(rules.clone(), MatchMode::All, &LimiterTable::default()).unwrap();
+        let matched = table.matches("/user/query-one", "get");
+        let urls: Vec<&str> = matched.iter().map(|x| x.url.as_str()).collect();
+        assert_eq!(urls, vec!["^/user/query-one", "^/user/"]);
+        assert!(table.matches("/random", "get").is_empty());
+
+        let first = LimiterTable::build(rules, MatchMode::First, &table).unwrap();
+        let matched = first.matches("/user/query-one", "get");
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].url, "^/user/query-one");
+        // 配置不变的规则沿用原来的令牌桶
+        assert!(std::sync::Arc::ptr_eq(
+            &matched[0],
+            &table.matches("/user/query-one", "get")[0]
+        ));
+
+        let invalid = vec![url_rate("^/user/(", "GET", 10, None)];
+        assert!(LimiterTable::build(invalid, MatchMode::All, &table).is_err());
+    }
+
+    #[test]
+    fn client_ip_from_trusted_proxy() {
+        let proxies: Vec<IpNet> = ["10.0.0.0/8", "192.168.1.1"]
+            .iter()
+            .map(|x| IpNet::parse(x).unwrap())
+            .collect();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.1.1.1, 2.2.2.2, 10.0.0.2"),
+        );
+        headers.insert("x-real-ip", HeaderValue::from_static("3.3.3.3"));
+        let ip = |peer: &str, headers: &HeaderMap| {
+            forwarded_ip(peer.parse().unwrap(), headers, &proxies).to_string()
+        };
+        // 对端不是可信代理时忽略请求头
+        assert_eq!(ip("8.8.8.8", &headers), "8.8.8.8");
+        // 从右往左跳过可信代理, 最左边的地址可以被客户端伪造
+        assert_eq!(ip("192.168.1.1", &headers), "2.2.2.2");
+        headers.remove("x-forwarded-for");
+        assert_eq!(ip("10.0.0.1", &headers), "3.3.3.3");
+    }
+}
@@ -0,0 +1,392 @@
+use crate::app_config::RedisConfig;
+use crate::rate_limiter::RateLimitDecision;
+use anyhow::anyhow;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+const DEFAULT_POOL_SIZE: usize = 8;
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(50);
+const DEFAULT_KEY_PREFIX: &str = "volo-boot:rate:";
+/// 连接失败后的这段时间内直接使用本地限流, 不再尝试连接
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 令牌桶脚本, 与 `TokenBucketLimiter` 的算法一致, 时间取redis服务器的时间, 避免网关之间的时钟偏差;
+/// 返回 {是否允许, 剩余令牌数 * 1000}, lua的小数返回时会被截断为整数
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local rate = tonumber(ARGV[1])
+local window_ms = tonumber(ARGV[2])
+local burst = tonumber(ARGV[3])
+local permits = tonumber(ARGV[4])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(bucket[1]) or burst
+local ts = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - ts) * rate / window_ms)
+local allowed = 0
+if tokens >= permits then
+  tokens = tokens - permits
+  allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tokens, 'ts', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst * window_ms / rate) + 1000)
+return {allowed, math.floor(tokens * 1000)}
+"#;
+
+/// RESP协议的响应
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<RespValue>>),
+}
+
+/// 一个redis连接, 只实现了限流需要的请求/响应
+struct Connection {
+    stream: BufStream<TcpStream>,
+    /// `SCRIPT LOAD` 返回的令牌桶脚本sha1, 之后用 `EVALSHA` 调用, 不再每次发送脚本
+    script_sha: Option<Vec<u8>>,
+}
+
+impl Connection {
+    async fn connect(config: &RedisConfig) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(config.addr.as_str()).await?;
+        stream.set_nodelay(true)?;
+        let mut conn = Self {
+            stream: BufStream::new(stream),
+            script_sha: None,
+        };
+        if let Some(password) = config.password.as_deref().filter(|x| !x.is_empty()) {
+            conn.command(&[b"AUTH", password.as_bytes()]).await?;
+        }
+        if let Some(db) = config.db.filter(|x| *x != 0) {
+            conn.command(&[b"SELECT", db.to_string().as_bytes()])
+                .await?;
+        }
+        Ok(conn)
+    }
+
+    async fn command(&mut self, args: &[&[u8]]) -> anyhow::Result<RespValue> {
+        match self.raw_command(args).await? {
+            RespValue::Error(e) => Err(anyhow!("redis error: {}", e)),
+            value => Ok(value),
+        }
+    }
+
+    /// 发送命令并返回原始响应, redis返回的错误也作为响应返回
+    async fn raw_command(&mut self, args: &[&[u8]]) -> anyhow::Result<RespValue> {
+        self.stream
+            .write_all(encode_command(args).as_slice())
+            .await?;
+        self.stream.flush().await?;
+        read_value(&mut self.stream).await
+    }
+
+    async fn load_script(&mut self) -> anyhow::Result<Vec<u8>> {
+        match self
+            .command(&[b"SCRIPT", b"LOAD", TOKEN_BUCKET_SCRIPT.as_bytes()])
+            .await?
+        {
+            RespValue::Bulk(Some(sha)) => Ok(sha),
+            value => Err(anyhow!("unexpected SCRIPT LOAD response: {:?}", value)),
+        }
+    }
+
+    /// 用 `EVALSHA` 执行令牌桶脚本; redis重启或执行过 `SCRIPT FLUSH` 后返回NOSCRIPT, 此时重新加载脚本再执行一次
+    async fn eval_token_bucket(
+        &mut self,
+        key: &[u8],
+        args: &[String],
+    ) -> anyhow::Result<RespValue> {
+        for _ in 0..2 {
+            let sha = match self.script_sha.take() {
+                Some(sha) => sha,
+                None => self.load_script().await?,
+            };
+            let mut command: Vec<&[u8]> =
+                vec![b"EVALSHA".as_slice(), sha.as_slice(), b"1".as_slice(), key];
+            command.extend(args.iter().map(|x| x.as_bytes()));
+            match self.raw_command(command.as_slice()).await? {
+                RespValue::Error(e) if e.starts_with("NOSCRIPT") => continue,
+                RespValue::Error(e) => return Err(anyhow!("redis error: {}", e)),
+                value => {
+                    self.script_sha = Some(sha);
+                    return Ok(value);
+                }
+            }
+        }
+        Err(anyhow!("redis error: NOSCRIPT after SCRIPT LOAD"))
+    }
+}
+
+/// 编码为RESP数组
+pub fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(64);
+    buf.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+async fn read_line<R>(reader: &mut R) -> anyhow::Result<String>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line).await? == 0 {
+        return Err(anyhow!("connection closed"));
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(anyhow!("invalid resp line"));
+    }
+    line.truncate(line.len() - 2);
+    Ok(String::from_utf8(line)?)
+}
+
+/// 读取一个RESP值, 数组需要递归读取
+pub fn read_value<R>(
+    reader: &mut R,
+) -> Pin<Box<dyn Future<Output = anyhow::Result<RespValue>> + Send + '_>>
+where
+    R: AsyncBufReadExt + Unpin + Send,
+{
+    Box::pin(async move {
+        let line = read_line(reader).await?;
+        let Some(kind) = line.bytes().next().filter(|x| x.is_ascii()) else {
+            return Err(anyhow!("invalid resp line: {}", line));
+        };
+        let rest = &line[1..];
+        match kind {
+            b'+' => Ok(RespValue::Simple(rest.to_string())),
+            b'-' => Ok(RespValue::Error(rest.to_string())),
+            b':' => Ok(RespValue::Integer(rest.parse()?)),
+            b'$' => {
+                let len: i64 = rest.parse()?;
+                if len < 0 {
+                    return Ok(RespValue::Bulk(None));
+                }
+                let mut data = vec![0; len as usize + 2];
+                reader.read_exact(data.as_mut_slice()).await?;
+                data.truncate(len as usize);
+                Ok(RespValue::Bulk(Some(data)))
+            }
+            b'*' => {
+                let len: i64 = rest.parse()?;
+                if len < 0 {
+                    return Ok(RespValue::Array(None));
+                }
+                let mut values = Vec::with_capacity(len as usize);
+                for _ in 0..len {
+                    values.push(read_value(reader).await?);
+                }
+                Ok(RespValue::Array(Some(values)))
+            }
+            _ => Err(anyhow!("invalid resp type: {}", line)),
+        }
+    })
+}
+
+/// 基于redis的分布式令牌桶, 所有网关实例共享同一个桶; 连接池中的每个连接同一时间只处理一个请求
+pub struct RedisRateLimiter {
+    config: RedisConfig,
+    timeout: Duration,
+    key_prefix: String,
+    conns: Vec<Mutex<Option<Connection>>>,
+    next: AtomicUsize,
+    unavailable_until_ms: AtomicU64,
+}
+
+impl RedisRateLimiter {
+    pub fn new(config: RedisConfig) -> Self {
+        let pool_size = config.pool_size.unwrap_or(DEFAULT_POOL_SIZE).max(1);
+        Self {
+            timeout: config
+                .timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_TIMEOUT),
+            key_prefix: config
+                .key_prefix
+                .clone()
+                .unwrap_or_else(|| DEFAULT_KEY_PREFIX.to_string()),
+            conns: (0..pool_size).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+            unavailable_until_ms: AtomicU64::new(0),
+            config,
+        }
+    }
+
+    /// 从 `key` 的桶中取出 `permits` 个令牌, redis不可用时返回错误, 由调用方回退到本地限流
+    pub async fn acquire(
+        &self,
+        key: &str,
+        rate: u64,
+        window: Duration,
+        burst: u64,
+        permits: u64,
+    ) -> anyhow::Result<RateLimitDecision> {
+        if now_ms() < self.unavailable_until_ms.load(Ordering::Relaxed) {
+            return Err(anyhow!("redis {} is unavailable", self.config.addr));
+        }
+
+        let key = format!("{}{}", self.key_prefix, key);
+        let args = [
+            rate.max(1).to_string(),
+            (window.as_millis() as u64).max(1).to_string(),
+            burst.max(1).to_string(),
+            permits.to_string(),
+        ];
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.conns.len();
+        // 等待连接的时间也算在超时时间内
+        let result = tokio::time::timeout(self.timeout, async {
+            let mut slot = self.conns[idx].lock().await;
+            let mut conn = match slot.take() {
+                Some(conn) => conn,
+                None => Connection::connect(&self.config).await?,
+            };
+            let value = conn.eval_token_bucket(key.as_bytes(), &args).await?;
+            // 出错或超时的连接可能处于半读写状态, 直接丢弃, 下次重连; 只有成功的连接放回池中
+            *slot = Some(conn);
+            anyhow::Ok(value)
+        })
+        .await
+        .map_err(|_| anyhow!("redis {} timeout", self.config.addr))
+        .and_then(|x| x);
+
+        match result {
+            Ok(RespValue::Array(Some(values))) => match values.as_slice() {
+                [RespValue::Integer(allowed), RespValue::Integer(milli_tokens)] => {
+                    Ok(RateLimitDecision::new(
+                        *allowed == 1,
+                        *milli_tokens as f64 / 1000.0,
+                        permits,
+                        rate,
+                        window,
+                        burst,
+                    ))
+                }
+                _ => Err(anyhow!("unexpected redis response: {:?}", values)),
+            },
+            Ok(value) => Err(anyhow!("unexpected redis response: {:?}", value)),
+            Err(e) => {
+                self.unavailable_until_ms.store(
+                    now_ms() + RETRY_INTERVAL.as_millis() as u64,
+                    Ordering::Relaxed,
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod redis_test {
+    use super::*;
+    use tokio::io::BufReader;
+    use tokio::net::TcpListener;
+
+    /// 本地的redis替身: `SCRIPT LOAD` 返回固定的sha, `EVALSHA` 返回固定的响应;
+    /// `flushed` 时第一次 `EVALSHA` 返回NOSCRIPT, 模拟redis重启后脚本丢失
+    async fn stand_in(response: &'static [u8], flushed: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut flushed = flushed;
+            while let Ok(RespValue::Array(Some(args))) = read_value(&mut stream).await {
+                let response: &[u8] = match args.first() {
+                    Some(RespValue::Bulk(Some(cmd))) if cmd == b"SCRIPT" => b"$3\r\nsha\r\n",
+                    Some(RespValue::Bulk(Some(cmd))) if cmd == b"EVALSHA" && flushed => {
+                        flushed = false;
+                        b"-NOSCRIPT No matching script.\r\n"
+                    }
+                    Some(RespValue::Bulk(Some(cmd))) if cmd == b"EVALSHA" => {
+                        assert_eq!(args.get(1), Some(&RespValue::Bulk(Some(b"sha".to_vec()))));
+                        response
+                    }
+                    _ => b"-ERR unknown command\r\n",
+                };
+                stream.get_mut().write_all(response).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    fn config(addr: String) -> RedisConfig {
+        RedisConfig {
+            addr,
+            timeout_ms: Some(500),
+            pool_size: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn acquire_from_stand_in() {
+        let limiter =
+            RedisRateLimiter::new(config(stand_in(b"*2\r\n:1\r\n:9000\r\n", false).await));
+        let decision = limiter
+            .acquire("a", 10, Duration::from_secs(1), 10, 1)
+            .await
+            .unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 9);
+
+        // 同一个连接上第二次请求直接使用已加载的脚本
+        assert!(
+            limiter
+                .acquire("a", 10, Duration::from_secs(1), 10, 1)
+                .await
+                .unwrap()
+                .allowed
+        );
+
+        let limiter = RedisRateLimiter::new(config(stand_in(b"*2\r\n:0\r\n:0\r\n", true).await));
+        let decision = limiter
+            .acquire("a", 10, Duration::from_secs(1), 10, 1)
+            .await
+            .unwrap();
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn unreachable_backend() {
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let limiter = RedisRateLimiter::new(config(addr));
+        assert!(limiter
+            .acquire("a", 10, Duration::from_secs(1), 10, 1)
+            .await
+            .is_err());
+        // 失败后一段时间内不再尝试连接
+        assert!(limiter.unavailable_until_ms.load(Ordering::Relaxed) > now_ms());
+    }
+
+    #[test]
+    fn encode_resp_command() {
+        assert_eq!(
+            encode_command(&[b"GET", b"k"]),
+            b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n".to_vec()
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use crate::rate_limiter::RateLimitDecision;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 超过这个数量的key时清理已经回满的桶
+const CLEANUP_THRESHOLD: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    last_ms: u64,
+}
+
+/// 令牌桶限流: 每个 `window` 补充 `rate` 个令牌, 桶的容量为 `burst`,
+/// 空闲一段时间后允许最多 `burst` 个请求的突发; 每个key一个桶
+pub struct TokenBucketLimiter {
+    rate: u64,
+    window: Duration,
+    burst: u64,
+    /// 每毫秒补充的令牌数
+    refill_per_ms: f64,
+    buckets: DashMap<String, Bucket>,
+    last_cleanup_ms: AtomicU64,
+}
+
+impl TokenBucketLimiter {
+    pub fn new(rate: u64, window: Duration, burst: u64) -> Self {
+        let window_ms = window.as_millis().max(1) as f64;
+        Self {
+            rate,
+            window,
+            burst: burst.max(1),
+            refill_per_ms: rate as f64 / window_ms,
+            buckets: DashMap::new(),
+            last_cleanup_ms: AtomicU64::new(now_ms()),
+        }
+    }
+
+    /// (rate, window, burst)
+    pub fn get_config(&self) -> (u64, Duration, u64) {
+        (self.rate, self.window, self.burst)
+    }
+
+    /// 尝试从 `key` 的桶中取出 `permits` 个令牌
+    pub fn try_acquire(&self, key: &str, permits: u64) -> bool {
+        self.acquire(key, permits).allowed
+    }
+
+    /// 同 `try_acquire`, 同时返回桶的剩余令牌数和恢复时间
+    pub fn acquire(&self, key: &str, permits: u64) -> RateLimitDecision {
+        let now = now_ms();
+        self.cleanup(now);
+
+        if let Some(mut bucket) = self.buckets.get_mut(key) {
+            return self.take(&mut bucket, now, permits);
+        }
+        let mut bucket = self
+            .buckets
+            .entry(key.to_string())
+            .or_insert_with(|| Bucket {
+                tokens: self.burst as f64,
+                last_ms: now,
+            });
+        self.take(&mut bucket, now, permits)
+    }
+
+    fn take(&self, bucket: &mut Bucket, now: u64, permits: u64) -> RateLimitDecision {
+        self.refill(bucket, now);
+        let allowed = bucket.tokens >= permits as f64;
+        if allowed {
+            bucket.tokens -= permits as f64;
+        }
+        RateLimitDecision::new(
+            allowed,
+            bucket.tokens,
+            permits,
+            self.rate,
+            self.window,
+            self.burst,
+        )
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: u64) {
+        let elapsed = now.saturating_sub(bucket.last_ms) as f64;
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_ms).min(self.burst as f64);
+        bucket.last_ms = now;
+    }
+
+    /// key很多时(如按ip限流)清理已经回满的桶, 回满的桶和新建的桶等价, 不影响限流结果;
+    /// 每个窗口最多清理一次
+    fn cleanup(&self, now: u64) {
+        if self.buckets.len() <= CLEANUP_THRESHOLD {
+            return;
+        }
+        let last = self.last_cleanup_ms.load(Ordering::Relaxed);
+        if now.saturating_sub(last) < self.window.as_millis() as u64
+            || self
+                .last_cleanup_ms
+                .compare_exchange(last, now, Ordering::AcqRel, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        self.buckets.retain(|_, bucket| {
+            self.refill(bucket, now);
+            bucket.tokens < self.burst as f64
+        });
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod token_bucket_test {
+    use super::*;
+
+    #[test]
+    fn burst_then_reject() {
+        let limiter = TokenBucketLimiter::new(1, Duration::from_secs(60), 3);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire("a", 1));
+        }
+        assert!(!limiter.try_acquire("a", 1));
+        // 每个key单独计数
+        assert!(limiter.try_acquire("b", 1));
+    }
+
+    #[test]
+    fn refill_over_time() {
+        let limiter = TokenBucketLimiter::new(1000, Duration::from_secs(1), 1);
+        assert!(limiter.try_acquire("a", 1));
+        assert!(!limiter.try_acquire("a", 1));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(limiter.try_acquire("a", 1));
+    }
+
+    #[test]
+    fn decision_of_rejected() {
+        let limiter = TokenBucketLimiter::new(2, Duration::from_secs(10), 2);
+        let first = limiter.acquire("a", 1);
+        assert!(first.allowed);
+        assert_eq!((first.limit, first.remaining), (2, 1));
+        assert!(limiter.acquire("a", 1).allowed);
+
+        let rejected = limiter.acquire("a", 1);
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.remaining, 0);
+        // 每5秒补充一个令牌, 回满需要10秒
+        assert_eq!(rejected.retry_after_secs(), 5);
+        assert_eq!(rejected.reset_secs(), 10);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::app_config::{SdKind, ServerDiscover};
+use crate::svc_discover::SvcDiscover;
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+pub mod nacos;
+pub mod static_file;
+
+pub use nacos::NacosRegistry;
+pub use static_file::StaticRegistry;
+
+/// 配置变更监听, 与具体的注册中心解耦
+pub trait ConfigListener: Send + Sync + 'static {
+    fn notify(&self, data_id: &str, group: &str, content: &str);
+}
+
+/// 服务注册中心抽象: 服务注册/下线、订阅、配置获取与监听
+pub trait ServiceRegistry: Send + Sync + 'static {
+    /// 注册服务实例
+    fn register(
+        &self,
+        service_name: String,
+        port: u32,
+        metadata: HashMap<String, String>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// 下线本服务注册的所有实例
+    fn deregister(&self) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// 订阅服务, 之后可以通过 `discover` 发现该服务的实例
+    fn subscribe(&self, service_name: String) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// 获取配置内容
+    fn get_config(
+        &self,
+        data_id: String,
+        group: String,
+    ) -> impl Future<Output = anyhow::Result<String>> + Send;
+
+    /// 监听配置变更
+    fn add_listener(
+        &self,
+        data_id: String,
+        group: String,
+        listener: Arc<dyn ConfigListener>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// 用于构建rpc客户端的服务发现
+    fn discover(&self) -> SvcDiscover;
+}
+
+/// 根据 `AppConfig.sd` 选择的注册中心
+#[derive(Clone)]
+pub enum Registry {
+    Nacos(NacosRegistry),
+    Static(StaticRegistry),
+}
+
+impl Registry {
+    pub fn from_config(sd: &ServerDiscover) -> anyhow::Result<Self> {
+        match sd.kind {
+            SdKind::Nacos => {
+                let Some(nacos_config) = sd.nacos.as_ref() else {
+                    return Err(anyhow!("sd.kind is nacos but [sd.nacos] is missing"));
+                };
+                Ok(Registry::Nacos(NacosRegistry::new(nacos_config)?))
+            }
+            SdKind::Static => {
+                let Some(static_config) = sd.static_sd.as_ref() else {
+                    return Err(anyhow!("sd.kind is static but [sd.static] is missing"));
+                };
+                Ok(Registry::Static(StaticRegistry::new(
+                    static_config.clone(),
+                )?))
+            }
+        }
+    }
+}
+
+impl ServiceRegistry for Registry {
+    async fn register(
+        &self,
+        service_name: String,
+        port: u32,
+        metadata: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Registry::Nacos(r) => r.register(service_name, port, metadata).await,
+            Registry::Static(r) => r.register(service_name, port, metadata).await,
+        }
+    }
+
+    async fn deregister(&self) -> anyhow::Result<()> {
+        match self {
+            Registry::Nacos(r) => r.deregister().await,
+            Registry::Static(r) => r.deregister().await,
+        }
+    }
+
+    async fn subscribe(&self, service_name: String) -> anyhow::Result<()> {
+        match self {
+            Registry::Nacos(r) => r.subscribe(service_name).await,
+            Registry::Static(r) => r.subscribe(service_name).await,
+        }
+    }
+
+    async fn get_config(&self, data_id: String, group: String) -> anyhow::Result<String> {
+        match self {
+            Registry::Nacos(r) => r.get_config(data_id, group).await,
+            Registry::Static(r) => r.get_config(data_id, group).await,
+        }
+    }
+
+    async fn add_listener(
+        &self,
+        data_id: String,
+        group: String,
+        listener: Arc<dyn ConfigListener>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Registry::Nacos(r) => r.add_listener(data_id, group, listener).await,
+            Registry::Static(r) => r.add_listener(data_id, group, listener).await,
+        }
+    }
+
+    fn discover(&self) -> SvcDiscover {
+        match self {
+            Registry::Nacos(r) => r.discover(),
+            Registry::Static(r) => r.discover(),
+        }
+    }
+}
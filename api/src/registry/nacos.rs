@@ -0,0 +1,107 @@
+use crate::app_config::NacosConfig;
+use crate::registry::{ConfigListener, ServiceRegistry};
+use crate::svc_discover::{NacosDiscover, SvcDiscover};
+use anyhow::anyhow;
+use nacos_sdk::api::config::{ConfigChangeListener, ConfigResponse};
+use pd_rs_common::svc::nacos::NacosNamingAndConfigData;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+/// 基于nacos的注册中心
+#[derive(Clone)]
+pub struct NacosRegistry {
+    pub nacos_naming_data: Arc<NacosNamingAndConfigData>,
+    discover: Arc<OnceLock<NacosDiscover>>,
+}
+
+impl NacosRegistry {
+    pub fn new(nacos_config: &NacosConfig) -> anyhow::Result<Self> {
+        let nacos_naming_data = NacosNamingAndConfigData::new(
+            nacos_config.server_addr.clone(),
+            nacos_config.namespace.clone().unwrap_or("".to_string()),
+            nacos_config.service_name.clone(),
+            nacos_config.username.clone(),
+            nacos_config.password.clone(),
+        )
+        .map_err(|e| anyhow!("create nacos client failed: {}", e))?;
+
+        Ok(Self {
+            nacos_naming_data: Arc::new(nacos_naming_data),
+            discover: Arc::new(OnceLock::new()),
+        })
+    }
+}
+
+impl ServiceRegistry for NacosRegistry {
+    async fn register(
+        &self,
+        service_name: String,
+        port: u32,
+        metadata: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        self.nacos_naming_data
+            .register_service(service_name, port as i32, None, None, metadata)
+            .await
+            .map(|_| ())
+            .map_err(|e| anyhow!("register service failed: {}", e))
+    }
+
+    async fn deregister(&self) -> anyhow::Result<()> {
+        self.nacos_naming_data
+            .deregister_service()
+            .await
+            .map(|_| ())
+            .map_err(|e| anyhow!("deregister service failed: {}", e))
+    }
+
+    async fn subscribe(&self, service_name: String) -> anyhow::Result<()> {
+        self.nacos_naming_data
+            .subscribe_service(service_name)
+            .await
+            .map(|_| ())
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    async fn get_config(&self, data_id: String, group: String) -> anyhow::Result<String> {
+        self.nacos_naming_data
+            .get_config(data_id, group)
+            .await
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    async fn add_listener(
+        &self,
+        data_id: String,
+        group: String,
+        listener: Arc<dyn ConfigListener>,
+    ) -> anyhow::Result<()> {
+        self.nacos_naming_data
+            .add_config_listener(data_id, group, Arc::new(NacosConfigListener { listener }))
+            .await
+            .map(|_| ())
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    fn discover(&self) -> SvcDiscover {
+        // NacosDiscover 创建时会启动监听任务, 这里只创建一次
+        let discover = self
+            .discover
+            .get_or_init(|| NacosDiscover::new(self.nacos_naming_data.clone()));
+        SvcDiscover::Nacos(discover.clone())
+    }
+}
+
+/// 把nacos的配置变更事件转发给 `ConfigListener`
+struct NacosConfigListener {
+    listener: Arc<dyn ConfigListener>,
+}
+
+impl ConfigChangeListener for NacosConfigListener {
+    fn notify(&self, config_resp: ConfigResponse) {
+        self.listener.notify(
+            config_resp.data_id().as_str(),
+            config_resp.group().as_str(),
+            config_resp.content().as_str(),
+        );
+    }
+}
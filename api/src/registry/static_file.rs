@@ -0,0 +1,156 @@
+use crate::app_config::StaticConfig;
+use crate::registry::{ConfigListener, ServiceRegistry};
+use crate::svc_discover::{StaticDiscover, SvcDiscover};
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// 本地文件轮询间隔
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 静态注册中心: 实例列表来自配置或实例文件, 动态配置来自本地文件, 注册/下线为空操作
+#[derive(Clone)]
+pub struct StaticRegistry {
+    pub config: StaticConfig,
+    discover: StaticDiscover,
+}
+
+impl StaticRegistry {
+    pub fn new(config: StaticConfig) -> anyhow::Result<Self> {
+        let discover = match config.instance_file.as_deref() {
+            Some(instance_file) => StaticDiscover::from_file(instance_file)?,
+            None => StaticDiscover::new(&config.services),
+        };
+        Ok(Self { config, discover })
+    }
+
+    fn config_file(&self) -> anyhow::Result<&str> {
+        self.config
+            .config_file
+            .as_deref()
+            .ok_or_else(|| anyhow!("[sd.static] config_file is not set"))
+    }
+}
+
+impl ServiceRegistry for StaticRegistry {
+    async fn register(
+        &self,
+        service_name: String,
+        port: u32,
+        _metadata: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        tracing::info!("static registry, skip register {}:{}", service_name, port);
+        Ok(())
+    }
+
+    async fn deregister(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn subscribe(&self, service_name: String) -> anyhow::Result<()> {
+        if self.discover.contains(service_name.as_str()) {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "no static instances configured for {}",
+                service_name
+            ))
+        }
+    }
+
+    async fn get_config(&self, _data_id: String, _group: String) -> anyhow::Result<String> {
+        let config_file = self.config_file()?;
+        tokio::fs::read_to_string(config_file)
+            .await
+            .map_err(|e| anyhow!("read {} failed: {}", config_file, e))
+    }
+
+    async fn add_listener(
+        &self,
+        data_id: String,
+        group: String,
+        listener: Arc<dyn ConfigListener>,
+    ) -> anyhow::Result<()> {
+        let config_file = self.config_file()?;
+        watch_file(PathBuf::from(config_file), WATCH_INTERVAL, move |content| {
+            listener.notify(data_id.as_str(), group.as_str(), content.as_str())
+        });
+        Ok(())
+    }
+
+    fn discover(&self) -> SvcDiscover {
+        SvcDiscover::Static(self.discover.clone())
+    }
+}
+
+/// 轮询文件的修改时间, 文件变化后把新内容交给回调
+pub fn watch_file<F>(path: PathBuf, interval: Duration, on_change: F)
+where
+    F: Fn(String) + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut last_modified = modified_time(&path).await;
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let modified = modified_time(&path).await;
+            if modified.is_none() || modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            match tokio::fs::read_to_string(&path).await {
+                Ok(content) => {
+                    tracing::info!("file changed: {}", path.display());
+                    on_change(content)
+                }
+                Err(e) => tracing::error!("read {} failed: {}", path.display(), e),
+            }
+        }
+    });
+}
+
+async fn modified_time(path: &PathBuf) -> Option<SystemTime> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod static_registry_test {
+    use super::*;
+    use volo::context::Endpoint;
+    use volo::discovery::Discover;
+
+    #[tokio::test]
+    async fn discover_static_instances() {
+        let mut services = HashMap::new();
+        services.insert(
+            "volo-boot-user.rpc".to_string(),
+            vec!["127.0.0.1:8081".to_string(), "127.0.0.1:18081".to_string()],
+        );
+        let registry = StaticRegistry::new(StaticConfig {
+            service_name: "volo-boot-api.http".to_string(),
+            services,
+            instance_file: None,
+            config_file: None,
+        })
+        .unwrap();
+
+        assert!(registry
+            .subscribe("volo-boot-user.rpc".to_string())
+            .await
+            .is_ok());
+        assert!(registry
+            .subscribe("volo-boot-order.rpc".to_string())
+            .await
+            .is_err());
+
+        let endpoint = Endpoint::new("volo-boot-user.rpc".into());
+        let instances = registry.discover().discover(&endpoint).await.unwrap();
+        assert_eq!(instances.len(), 2);
+    }
+}
@@ -0,0 +1,521 @@
+use crate::app_config::{RetryBudgetConfig, RetryConfig, RetryPolicyConfig};
+use crate::hash_key::request_hash;
+use anyhow::anyhow;
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use volo::context::{Context, Endpoint};
+use volo::discovery::{Change, Discover};
+use volo::loadbalance::error::LoadBalanceError;
+use volo::loadbalance::{LoadBalance, RequestHash};
+use volo::net::Address;
+use volo::METAINFO;
+use volo_grpc::context::ClientContext;
+use volo_grpc::{Code, Status};
+
+lazy_static! {
+    /// 服务名/方法名 -> 合并后的重试策略, 第一次调用方法时生成
+    static ref POLICIES: DashMap<String, Arc<RetryPolicy>> = DashMap::new();
+}
+
+/// `[clients.<service>.retry]` 中的重试策略
+static RETRY_RULES: OnceLock<HashMap<String, RetryConfig>> = OnceLock::new();
+static RETRY_BUDGET: OnceLock<RetryBudget> = OnceLock::new();
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 2;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(10);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_millis(200);
+/// 重试预算的统计窗口, 每秒一个桶
+const BUDGET_BUCKETS: usize = 10;
+
+/// 按名字解析grpc状态码, 如 `unavailable`、`deadline_exceeded`
+pub fn parse_code(name: &str) -> anyhow::Result<Code> {
+    let code = match name.to_lowercase().as_str() {
+        "cancelled" => Code::Cancelled,
+        "unknown" => Code::Unknown,
+        "invalid_argument" => Code::InvalidArgument,
+        "deadline_exceeded" => Code::DeadlineExceeded,
+        "not_found" => Code::NotFound,
+        "already_exists" => Code::AlreadyExists,
+        "permission_denied" => Code::PermissionDenied,
+        "resource_exhausted" => Code::ResourceExhausted,
+        "failed_precondition" => Code::FailedPrecondition,
+        "aborted" => Code::Aborted,
+        "out_of_range" => Code::OutOfRange,
+        "unimplemented" => Code::Unimplemented,
+        "internal" => Code::Internal,
+        "unavailable" => Code::Unavailable,
+        "data_loss" => Code::DataLoss,
+        "unauthenticated" => Code::Unauthenticated,
+        _ => return Err(anyhow!("invalid grpc code: {}", name)),
+    };
+    Ok(code)
+}
+
+/// 一个rpc方法最终生效的重试策略: 方法配置 > 服务配置 > 默认值
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub enabled: Option<bool>,
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub codes: Vec<Code>,
+}
+
+impl RetryPolicy {
+    pub fn resolve(config: Option<&RetryConfig>, method: &str) -> Self {
+        let layers: Vec<&RetryPolicyConfig> = config
+            .into_iter()
+            .flat_map(|x| [Some(&x.policy), x.methods.get(method)])
+            .flatten()
+            .collect();
+        // 后面的配置覆盖前面的
+        let pick =
+            |f: fn(&RetryPolicyConfig) -> Option<u64>| layers.iter().rev().find_map(|x| f(x));
+        Self {
+            enabled: layers.iter().rev().find_map(|x| x.enabled),
+            max_attempts: layers
+                .iter()
+                .rev()
+                .find_map(|x| x.max_attempts)
+                .unwrap_or(DEFAULT_MAX_ATTEMPTS),
+            backoff: pick(|x| x.backoff_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_BACKOFF),
+            max_backoff: pick(|x| x.max_backoff_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_MAX_BACKOFF),
+            codes: layers
+                .iter()
+                .rev()
+                .find_map(|x| x.codes.as_ref())
+                .map(|codes| codes.iter().filter_map(|x| parse_code(x).ok()).collect())
+                .unwrap_or_else(|| vec![Code::Unavailable]),
+        }
+    }
+
+    /// 没有显式开启或关闭时, 只有幂等方法重试
+    pub fn should_retry(&self, idempotent: bool) -> bool {
+        self.enabled.unwrap_or(idempotent) && self.max_attempts > 1
+    }
+
+    /// 第 `retry` 次重试前的退避时间: 指数退避 + 全抖动
+    fn backoff(&self, retry: u32) -> Duration {
+        let max = self
+            .backoff
+            .saturating_mul(1 << retry.saturating_sub(1).min(16))
+            .min(self.max_backoff);
+        if max.is_zero() {
+            return max;
+        }
+        rand::rng().random_range(Duration::ZERO..=max)
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct BudgetBucket {
+    /// 桶对应的秒数
+    slot: u64,
+    requests: u64,
+    retries: u64,
+}
+
+/// 全局重试预算: 窗口内的重试数不超过 请求数 * `ratio` + 每秒 `min_retries_per_sec`
+pub struct RetryBudget {
+    ratio: f64,
+    min_retries_per_sec: u64,
+    start: Instant,
+    buckets: Mutex<[BudgetBucket; BUDGET_BUCKETS]>,
+}
+
+impl RetryBudget {
+    pub fn new(config: &RetryBudgetConfig) -> Self {
+        Self {
+            ratio: config.ratio.unwrap_or(0.1),
+            min_retries_per_sec: config.min_retries_per_sec.unwrap_or(10),
+            start: Instant::now(),
+            buckets: Mutex::new([BudgetBucket::default(); BUDGET_BUCKETS]),
+        }
+    }
+
+    /// 当前秒的桶, 过期的桶先清零
+    fn bucket<'a>(&self, buckets: &'a mut [BudgetBucket; BUDGET_BUCKETS]) -> &'a mut BudgetBucket {
+        let slot = self.start.elapsed().as_secs();
+        let bucket = &mut buckets[slot as usize % BUDGET_BUCKETS];
+        if bucket.slot != slot {
+            *bucket = BudgetBucket {
+                slot,
+                ..Default::default()
+            };
+        }
+        bucket
+    }
+
+    pub fn record_request(&self) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        self.bucket(&mut buckets).requests += 1;
+    }
+
+    /// 预算足够时记录一次重试并返回true
+    pub fn try_retry(&self) -> bool {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let current = self.bucket(&mut buckets).slot;
+        let (requests, retries) = buckets
+            .iter()
+            .filter(|x| current - x.slot < BUDGET_BUCKETS as u64)
+            .fold((0, 0), |(req, ret), x| (req + x.requests, ret + x.retries));
+        let budget = requests as f64 * self.ratio
+            + (self.min_retries_per_sec * BUDGET_BUCKETS as u64) as f64;
+        if retries as f64 >= budget {
+            return false;
+        }
+        self.bucket(&mut buckets).retries += 1;
+        true
+    }
+}
+
+/// 启动时加载重试策略和重试预算
+pub fn init_retry(rules: HashMap<String, RetryConfig>, budget: Option<RetryBudgetConfig>) {
+    let _ = RETRY_RULES.set(rules);
+    let _ = RETRY_BUDGET.set(RetryBudget::new(&budget.unwrap_or_default()));
+}
+
+/// 重试和对冲请求共享的预算
+pub fn budget() -> &'static RetryBudget {
+    RETRY_BUDGET.get_or_init(|| RetryBudget::new(&RetryBudgetConfig::default()))
+}
+
+pub fn retry_policy(service: &str, method: &str) -> Arc<RetryPolicy> {
+    let key = format!("{}/{}", service, method);
+    if let Some(policy) = POLICIES.get(key.as_str()) {
+        return policy.clone();
+    }
+    let config = RETRY_RULES.get().and_then(|x| x.get(service));
+    POLICIES
+        .entry(key)
+        .or_insert_with(|| Arc::new(RetryPolicy::resolve(config, method)))
+        .clone()
+}
+
+/// 当前请求调用过的实例: 服务名 -> 实例地址, 由 `TriedInstanceLayer` 记录, `RetryBalance` 跳过这些实例
+#[derive(Debug, Clone, Default)]
+struct TriedInstances(HashMap<String, Vec<Address>>);
+
+fn tried_instances(service: &str) -> Vec<Address> {
+    METAINFO
+        .try_with(|m| {
+            m.borrow()
+                .get::<TriedInstances>()
+                .and_then(|x| x.0.get(service).cloned())
+        })
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+fn record_tried(service: &str, address: Address) {
+    let _ = METAINFO.try_with(|m| {
+        let mut m = m.borrow_mut();
+        let mut tried = m.get::<TriedInstances>().cloned().unwrap_or_default();
+        tried
+            .0
+            .entry(service.to_string())
+            .or_default()
+            .push(address);
+        m.insert(tried);
+    });
+}
+
+fn clear_tried(service: &str) {
+    let _ = METAINFO.try_with(|m| {
+        let mut m = m.borrow_mut();
+        if let Some(mut tried) = m.get::<TriedInstances>().cloned() {
+            tried.0.remove(service);
+            m.insert(tried);
+        }
+    });
+}
+
+/// 包装负载均衡器, 优先选择当前请求没有调用过的实例, 重试和对冲请求因此会换一个实例
+#[derive(Clone)]
+pub struct RetryBalance<LB> {
+    inner: LB,
+}
+
+impl<LB> RetryBalance<LB> {
+    pub fn new(inner: LB) -> Self {
+        Self { inner }
+    }
+}
+
+impl<D, LB> LoadBalance<D> for RetryBalance<LB>
+where
+    D: Discover,
+    LB: LoadBalance<D>,
+{
+    type InstanceIter = SkipTried<LB::InstanceIter>;
+
+    async fn get_picker<'future>(
+        &'future self,
+        endpoint: &'future Endpoint,
+        discover: &'future D,
+    ) -> Result<Self::InstanceIter, LoadBalanceError> {
+        let inner = self.inner.get_picker(endpoint, discover).await?;
+        Ok(SkipTried {
+            inner,
+            tried: tried_instances(endpoint.service_name_ref()),
+            skipped: None,
+        })
+    }
+
+    fn rebalance(&self, changes: Change<D::Key>) {
+        self.inner.rebalance(changes)
+    }
+}
+
+/// 跳过调用过的实例; 所有实例都调用过时返回第一个被跳过的实例, 只有一个实例时也能重试
+pub struct SkipTried<I> {
+    inner: I,
+    tried: Vec<Address>,
+    skipped: Option<Address>,
+}
+
+impl<I> Iterator for SkipTried<I>
+where
+    I: Iterator<Item = Address>,
+{
+    type Item = Address;
+
+    fn next(&mut self) -> Option<Address> {
+        for address in self.inner.by_ref() {
+            if !self.tried.contains(&address) {
+                return Some(address);
+            }
+            self.skipped.get_or_insert(address);
+        }
+        self.skipped.take()
+    }
+}
+
+/// 负载均衡选出实例之后记录调用的实例
+#[derive(Clone, Copy, Default)]
+pub struct TriedInstanceLayer;
+
+impl<S> volo::Layer<S> for TriedInstanceLayer {
+    type Service = TriedInstanceService<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        TriedInstanceService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct TriedInstanceService<S> {
+    inner: S,
+}
+
+impl<S, Req> volo::Service<ClientContext, Req> for TriedInstanceService<S>
+where
+    S: volo::Service<ClientContext, Req, Error = Status> + Send + Sync,
+    Req: Send,
+{
+    type Response = S::Response;
+    type Error = Status;
+
+    async fn call(&self, cx: &mut ClientContext, req: Req) -> Result<Self::Response, Status> {
+        let callee = cx.rpc_info().callee();
+        if let Some(address) = callee.address() {
+            record_tried(callee.service_name_ref(), address);
+        }
+        self.inner.call(cx, req).await
+    }
+}
+
+/// 换一个一致性hash值, 让一致性hash从环上另一个位置开始选择实例, 再由 `RetryBalance` 跳过调用过的实例
+fn rehash(original: Option<u64>, attempt: u32) {
+    let _ = METAINFO.try_with(|m| {
+        let hash = request_hash((original.unwrap_or_default(), attempt));
+        m.borrow_mut().insert(RequestHash(hash));
+    });
+}
+
+fn current_hash() -> Option<u64> {
+    METAINFO
+        .try_with(|m| m.borrow().get::<RequestHash>().map(|x| x.0))
+        .ok()
+        .flatten()
+}
+
+fn restore_hash(original: Option<u64>) {
+    let _ = METAINFO.try_with(|m| {
+        let mut m = m.borrow_mut();
+        match original {
+            Some(hash) => m.insert(RequestHash(hash)),
+            None => {
+                m.remove::<RequestHash>();
+            }
+        }
+    });
+}
+
+/// 按方法的重试策略调用rpc, 失败的状态码可以重试且重试预算足够时换一个实例重试。
+/// 重试在并发限制之内, 所有尝试共用一个并发许可
+pub async fn call_with_retry<F, Fut>(
+    service: &str,
+    method: &str,
+    idempotent: bool,
+    mut call: F,
+) -> Result<serde_json::Value, Status>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<serde_json::Value, Status>>,
+{
+    let budget = budget();
+    budget.record_request();
+    clear_tried(service);
+    let mut result = call().await;
+    let policy = retry_policy(service, method);
+    if result.is_ok() || !policy.should_retry(idempotent) {
+        clear_tried(service);
+        return result;
+    }
+
+    let original = current_hash();
+    let mut attempt = 1;
+    while let Err(e) = result.as_ref() {
+        if attempt >= policy.max_attempts || !policy.codes.contains(&e.code()) {
+            break;
+        }
+        if !budget.try_retry() {
+            tracing::warn!("retry budget exhausted, {}/{} not retried", service, method);
+            metrics::counter!("rpc_retry_budget_exhausted_total", "service" => service.to_string())
+                .increment(1);
+            break;
+        }
+        tokio::time::sleep(policy.backoff(attempt)).await;
+        rehash(original, attempt);
+        tracing::warn!(
+            "retry {}/{} attempt {} after error: {:?}",
+            service,
+            method,
+            attempt + 1,
+            e
+        );
+        metrics::counter!("rpc_retries_total", "service" => service.to_string(), "method" => method.to_string())
+            .increment(1);
+        result = call().await;
+        attempt += 1;
+    }
+    restore_hash(original);
+    clear_tried(service);
+    result
+}
+
+#[cfg(test)]
+mod retry_test {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn resolve_policy() {
+        let config: RetryConfig = toml::from_str(
+            r#"
+max_attempts = 3
+codes = ["unavailable", "deadline_exceeded"]
+[methods.CreateOrder]
+enabled = true
+max_attempts = 2
+"#,
+        )
+        .unwrap();
+        let policy = RetryPolicy::resolve(Some(&config), "GetOrder");
+        assert_eq!(policy.max_attempts, 3);
+        assert_eq!(
+            policy.codes,
+            vec![Code::Unavailable, Code::DeadlineExceeded]
+        );
+        assert!(policy.should_retry(true));
+        assert!(!policy.should_retry(false));
+
+        let policy = RetryPolicy::resolve(Some(&config), "CreateOrder");
+        assert_eq!(policy.max_attempts, 2);
+        assert!(policy.should_retry(false));
+
+        let policy = RetryPolicy::resolve(None, "GetOrder");
+        assert_eq!(
+            policy,
+            RetryPolicy::resolve(Some(&Default::default()), "GetOrder")
+        );
+        assert_eq!(policy.codes, vec![Code::Unavailable]);
+        assert!(parse_code("unavaliable").is_err());
+    }
+
+    #[test]
+    fn budget_limits_retries() {
+        let budget = RetryBudget::new(&RetryBudgetConfig {
+            ratio: Some(0.5),
+            min_retries_per_sec: Some(0),
+        });
+        assert!(!budget.try_retry());
+        for _ in 0..4 {
+            budget.record_request();
+        }
+        assert!(budget.try_retry());
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+    }
+
+    #[test]
+    fn skip_tried_instances() {
+        let addr = |port: u16| Address::Ip(format!("127.0.0.1:{}", port).parse().unwrap());
+        METAINFO.sync_scope(RefCell::new(Default::default()), || {
+            record_tried("user", addr(8081));
+            record_tried("order", addr(8082));
+            assert_eq!(tried_instances("user"), vec![addr(8081)]);
+
+            let picker = |tried| SkipTried {
+                inner: vec![addr(8081), addr(8082), addr(8083)].into_iter(),
+                tried,
+                skipped: None,
+            };
+            let picked: Vec<Address> = picker(tried_instances("user")).collect();
+            assert_eq!(picked, vec![addr(8082), addr(8083), addr(8081)]);
+            let mut all = picker(vec![addr(8081), addr(8082), addr(8083)]);
+            assert_eq!(all.next(), Some(addr(8081)));
+
+            clear_tried("user");
+            assert!(tried_instances("user").is_empty());
+            assert_eq!(tried_instances("order"), vec![addr(8082)]);
+        });
+    }
+
+    #[tokio::test]
+    async fn retry_until_success() {
+        let mut calls = 0;
+        let result = call_with_retry("retry-test", "GetUser", true, || {
+            calls += 1;
+            let result = if calls < 2 {
+                Err(Status::unavailable("down"))
+            } else {
+                Ok(serde_json::Value::from(calls))
+            };
+            async move { result }
+        })
+        .await;
+        assert_eq!(result.unwrap(), serde_json::Value::from(2));
+
+        // 非幂等方法默认不重试
+        let mut calls = 0;
+        let result = call_with_retry("retry-test", "CreateUser", false, || {
+            calls += 1;
+            async { Err(Status::unavailable("down")) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,687 @@
+use crate::app_config::RouteConfig;
+use crate::client::{self, ClientRegistry, RpcMethod};
+use crate::controller::extract::Validator;
+use crate::controller::{self, R};
+use crate::descriptor::{DynamicMethod, HttpRoute, ProtoRegistry};
+use crate::ServiceContext;
+use crate::{circuit_breaker, concurrency_limiter, hedge, retry};
+use anyhow::anyhow;
+use biz_error::ErrorDetail;
+use bytes::Bytes;
+use prost_reflect::{FieldDescriptor, Kind, MessageDescriptor};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use volo_grpc::Status;
+use volo_http::http::{HeaderMap, Method, Uri};
+use volo_http::server::param::PathParams;
+use volo_http::server::route::MethodRouter;
+use volo_http::utils::Extension;
+use volo_http::Router;
+
+/// 字段值的来源
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindingSource {
+    Query(String),
+    /// 路由中的path参数, 如 `/user/{id}` 中的 `id`
+    Path(String),
+    Header(String),
+    /// json请求体中的字段
+    Body(String),
+}
+
+/// 字段值的类型, query/path/header中取到的都是字符串, 需要转换成rpc请求字段的类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingType {
+    String,
+    Int,
+    Float,
+    Bool,
+    /// 原样使用json值, 只对body有意义
+    Json,
+}
+
+/// 一个rpc请求字段的绑定, 配置格式为 `<source>:<name>[:<type>]`, 如 `query:id:int`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub field: String,
+    pub source: BindingSource,
+    pub ty: BindingType,
+}
+
+impl Binding {
+    pub fn parse(field: &str, spec: &str) -> anyhow::Result<Self> {
+        let parts: Vec<&str> = spec.split(':').map(|x| x.trim()).collect();
+        if parts.len() < 2 || parts.len() > 3 || parts[1].is_empty() {
+            return Err(anyhow!(
+                "invalid binding {} = \"{}\", expect <source>:<name>[:<type>]",
+                field,
+                spec
+            ));
+        }
+        let name = parts[1].to_string();
+        let source = match parts[0] {
+            "query" => BindingSource::Query(name),
+            "path" => BindingSource::Path(name),
+            "header" => BindingSource::Header(name.to_lowercase()),
+            "body" => BindingSource::Body(name),
+            other => {
+                return Err(anyhow!(
+                    "invalid binding source {} of {}, expect query, path, header or body",
+                    other,
+                    field
+                ))
+            }
+        };
+        let ty = match parts.get(2).copied() {
+            None => match source {
+                BindingSource::Body(_) => BindingType::Json,
+                _ => BindingType::String,
+            },
+            Some("string") => BindingType::String,
+            Some("int") => BindingType::Int,
+            Some("float") => BindingType::Float,
+            Some("bool") => BindingType::Bool,
+            Some("json") => BindingType::Json,
+            Some(other) => {
+                return Err(anyhow!(
+                    "invalid binding type {} of {}, expect string, int, float, bool or json",
+                    other,
+                    field
+                ))
+            }
+        };
+        Ok(Self {
+            field: field.to_string(),
+            source,
+            ty,
+        })
+    }
+}
+
+/// 路由调用的rpc方法, 优先使用生成代码的方法, 没有时使用idl中的方法
+pub enum RouteTarget {
+    Generated(RpcMethod),
+    Dynamic(DynamicMethod),
+}
+
+impl RouteTarget {
+    pub fn name(&self) -> &str {
+        match self {
+            RouteTarget::Generated(m) => m.name,
+            RouteTarget::Dynamic(m) => m.name.as_str(),
+        }
+    }
+
+    pub fn idempotent(&self) -> bool {
+        match self {
+            RouteTarget::Generated(m) => m.idempotent,
+            RouteTarget::Dynamic(m) => m.idempotent,
+        }
+    }
+
+    /// 按生成代码的方法中声明的规则校验rpc请求, 只有idl的方法不校验
+    pub fn validate(&self, params: &serde_json::Value) -> Result<(), Vec<ErrorDetail>> {
+        let validate = match self {
+            RouteTarget::Generated(m) => m.validate,
+            RouteTarget::Dynamic(m) => {
+                client::find_method(m.service.as_str(), m.name.as_str()).and_then(|x| x.validate)
+            }
+        };
+        let mut v = Validator::default();
+        if let Some(validate) = validate {
+            validate(params, &mut v);
+        }
+        v.into_result()
+    }
+
+    pub async fn call(
+        &self,
+        clients: &ClientRegistry,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value, Status> {
+        match self {
+            RouteTarget::Generated(m) => (m.call)(clients, params).await,
+            RouteTarget::Dynamic(m) => m.call(clients, params).await,
+        }
+    }
+}
+
+/// 从请求中组装rpc请求的方式
+pub enum ParamMapping {
+    /// 路由表中配置的绑定, 没有绑定时使用json请求体
+    Bindings(Vec<Binding>),
+    /// idl中的 `google.api.http` 注解: path参数和query参数按请求字段的类型转换,
+    /// `body` 为请求体对应的字段, `*` 表示整个请求
+    HttpRule {
+        body: Option<String>,
+        input: MessageDescriptor,
+    },
+}
+
+impl ParamMapping {
+    /// 是否从请求体中取值: 没有绑定时整个请求体就是rpc请求, 有绑定时只有 `body:` 绑定需要请求体
+    pub fn uses_body(&self) -> bool {
+        match self {
+            ParamMapping::Bindings(bindings) => {
+                bindings.is_empty()
+                    || bindings
+                        .iter()
+                        .any(|x| matches!(x.source, BindingSource::Body(_)))
+            }
+            ParamMapping::HttpRule { body, .. } => body.is_some(),
+        }
+    }
+}
+
+/// 编译后的路由
+pub struct CompiledRoute {
+    pub method: Method,
+    pub path: String,
+    pub service: String,
+    pub rpc: RouteTarget,
+    pub mapping: ParamMapping,
+    /// 响应中作为http响应的字段, None 表示整个响应
+    pub response_body: Option<String>,
+}
+
+impl CompiledRoute {
+    pub fn compile(route: &RouteConfig, proto: Option<&ProtoRegistry>) -> anyhow::Result<Self> {
+        let method = match route.method.to_uppercase().as_str() {
+            "GET" => Method::GET,
+            "POST" => Method::POST,
+            "PUT" => Method::PUT,
+            "DELETE" => Method::DELETE,
+            "PATCH" => Method::PATCH,
+            other => {
+                return Err(anyhow!(
+                    "unsupported method {} of route {}",
+                    other,
+                    route.path
+                ))
+            }
+        };
+        let rpc = client::find_method(route.service.as_str(), route.rpc.as_str())
+            .map(RouteTarget::Generated)
+            .or_else(|| {
+                proto?
+                    .find_method(route.service.as_str(), route.rpc.as_str())
+                    .cloned()
+                    .map(RouteTarget::Dynamic)
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown rpc {}/{} of route {}",
+                    route.service,
+                    route.rpc,
+                    route.path
+                )
+            })?;
+        let mut bindings = route
+            .bindings
+            .iter()
+            .map(|(field, spec)| Binding::parse(field, spec))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        bindings.sort_by(|a, b| a.field.cmp(&b.field));
+
+        Ok(Self {
+            method,
+            path: route.path.clone(),
+            service: route.service.clone(),
+            rpc,
+            mapping: ParamMapping::Bindings(bindings),
+            response_body: None,
+        })
+    }
+
+    /// idl中 `google.api.http` 注解声明的路由
+    pub fn from_http_route(route: &HttpRoute) -> Self {
+        let rpc = client::find_method(route.rpc.service.as_str(), route.rpc.name.as_str())
+            .map(RouteTarget::Generated)
+            .unwrap_or_else(|| RouteTarget::Dynamic(route.rpc.clone()));
+        Self {
+            method: route.method.clone(),
+            path: route.path.clone(),
+            service: route.rpc.service.clone(),
+            rpc,
+            mapping: ParamMapping::HttpRule {
+                body: route.body.clone(),
+                input: route.rpc.input.clone(),
+            },
+            response_body: route.response_body.clone(),
+        }
+    }
+
+    /// 按绑定从请求中组装rpc请求的json
+    fn build_params(
+        &self,
+        path_params: &HashMap<String, String>,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: &Bytes,
+    ) -> Result<serde_json::Value, String> {
+        // 只有需要请求体的路由才解析, 其他路由忽略请求体
+        let body_json: Option<serde_json::Value> = if body.is_empty() || !self.mapping.uses_body() {
+            None
+        } else {
+            Some(serde_json::from_slice(body).map_err(|e| format!("invalid json body: {}", e))?)
+        };
+
+        match &self.mapping {
+            ParamMapping::Bindings(bindings) if bindings.is_empty() => {
+                Ok(body_json.unwrap_or_else(|| serde_json::Value::Object(Default::default())))
+            }
+            ParamMapping::Bindings(bindings) => {
+                bind_params(bindings, path_params, uri, headers, body_json)
+            }
+            ParamMapping::HttpRule { body, input } => {
+                http_rule_params(input, body.as_deref(), path_params, uri, body_json)
+            }
+        }
+    }
+}
+
+fn query_pairs(uri: &Uri) -> Vec<(String, String)> {
+    uri.query()
+        .and_then(|q| serde_urlencoded::from_str(q).ok())
+        .unwrap_or_default()
+}
+
+fn bind_params(
+    bindings: &[Binding],
+    path_params: &HashMap<String, String>,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body_json: Option<serde_json::Value>,
+) -> Result<serde_json::Value, String> {
+    let query_pairs = query_pairs(uri);
+    let body_json = body_json.unwrap_or_default();
+
+    let mut params = serde_json::Map::new();
+    for binding in bindings.iter() {
+        let raw = match &binding.source {
+            BindingSource::Query(name) => query_pairs
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| serde_json::Value::String(v.clone())),
+            BindingSource::Path(name) => path_params
+                .get(name)
+                .map(|v| serde_json::Value::String(v.clone())),
+            BindingSource::Header(name) => headers
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(|v| serde_json::Value::String(v.to_string())),
+            BindingSource::Body(name) => body_json.get(name).cloned(),
+        };
+        // 没有值的字段不设置, 对应rpc请求中的optional字段为None
+        let Some(raw) = raw else {
+            continue;
+        };
+        params.insert(
+            binding.field.clone(),
+            convert(&binding.field, raw, binding.ty)?,
+        );
+    }
+    Ok(serde_json::Value::Object(params))
+}
+
+/// 按 `google.api.http` 的规则组装请求: body为 `*` 时请求体就是整个请求, 否则没有被path参数和body
+/// 绑定的字段来自query参数; path参数的优先级最高
+fn http_rule_params(
+    input: &MessageDescriptor,
+    body_field: Option<&str>,
+    path_params: &HashMap<String, String>,
+    uri: &Uri,
+    body_json: Option<serde_json::Value>,
+) -> Result<serde_json::Value, String> {
+    let mut params = serde_json::Map::new();
+
+    if body_field != Some("*") {
+        for (name, value) in query_pairs(uri) {
+            let Some(field) = input
+                .get_field_by_name(name.as_str())
+                .or_else(|| input.get_field_by_json_name(name.as_str()))
+            else {
+                continue;
+            };
+            let value = convert(
+                field.name(),
+                serde_json::Value::String(value),
+                field_type(&field),
+            )?;
+            if field.is_list() {
+                let values = params
+                    .entry(field.name().to_string())
+                    .or_insert_with(|| serde_json::Value::Array(vec![]));
+                if let serde_json::Value::Array(values) = values {
+                    values.push(value);
+                }
+            } else {
+                params.insert(field.name().to_string(), value);
+            }
+        }
+    }
+
+    match (body_field, body_json) {
+        (Some("*"), Some(serde_json::Value::Object(body))) => params.extend(body),
+        (Some("*"), Some(_)) => return Err("json body must be an object".to_string()),
+        (Some(field), Some(body)) => {
+            params.insert(field.to_string(), body);
+        }
+        _ => {}
+    }
+
+    for (name, value) in path_params.iter() {
+        let Some(field) = input.get_field_by_name(name.as_str()) else {
+            continue;
+        };
+        let value = convert(
+            field.name(),
+            serde_json::Value::String(value.clone()),
+            field_type(&field),
+        )?;
+        params.insert(field.name().to_string(), value);
+    }
+
+    Ok(serde_json::Value::Object(params))
+}
+
+/// 请求字段对应的转换类型
+fn field_type(field: &FieldDescriptor) -> BindingType {
+    match field.kind() {
+        Kind::Double | Kind::Float => BindingType::Float,
+        Kind::Bool => BindingType::Bool,
+        Kind::String | Kind::Bytes | Kind::Enum(_) => BindingType::String,
+        Kind::Message(_) => BindingType::Json,
+        _ => BindingType::Int,
+    }
+}
+
+fn convert(
+    field: &str,
+    raw: serde_json::Value,
+    ty: BindingType,
+) -> Result<serde_json::Value, String> {
+    let serde_json::Value::String(s) = raw else {
+        return Ok(raw);
+    };
+    let invalid = |ty: &str| format!("{} 解析失败, 需要 {}", field, ty);
+    match ty {
+        BindingType::String => Ok(serde_json::Value::String(s)),
+        BindingType::Int => s
+            .parse::<i64>()
+            .map(serde_json::Value::from)
+            .map_err(|_| invalid("int")),
+        BindingType::Float => s
+            .parse::<f64>()
+            .map(serde_json::Value::from)
+            .map_err(|_| invalid("float")),
+        BindingType::Bool => s
+            .parse::<bool>()
+            .map(serde_json::Value::from)
+            .map_err(|_| invalid("bool")),
+        BindingType::Json => serde_json::from_str(s.as_str()).map_err(|_| invalid("json")),
+    }
+}
+
+async fn handle_route(
+    route: Arc<CompiledRoute>,
+    ctx: ServiceContext,
+    path_params: HashMap<String, String>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> R<serde_json::Value> {
+    let request_id = controller::request_id(&headers);
+    let params = route
+        .build_params(&path_params, &uri, &headers, &body)
+        .map_err(|reason| {
+            vec![ErrorDetail {
+                field: None,
+                reason,
+            }]
+        })
+        .and_then(|params| route.rpc.validate(&params).map(|_| params));
+    let params = match params {
+        Ok(params) => params,
+        Err(details) => return R::invalid_argument(details).with_request_id(request_id),
+    };
+
+    let result = call_upstream(&ctx.clients, route.service.as_str(), &route.rpc, params)
+        .await
+        .map(|data| match route.response_body.as_deref() {
+            Some(field) => data.get(field).cloned().unwrap_or_default(),
+            None => data,
+        });
+    if let Err(e) = result.as_ref() {
+        tracing::error!(
+            "route {} {} -> {}/{} error: {:?}",
+            route.method,
+            route.path,
+            route.service,
+            route.rpc.name(),
+            e
+        );
+    }
+    match result {
+        Ok(data) => R::ok(data),
+        Err(e) => R::from_status(e, controller::accept_language(&headers)),
+    }
+    .with_request_id(request_id)
+}
+
+/// 调用上游rpc服务: 熔断 -> 并发限制 -> 重试 -> 对冲 -> rpc调用
+pub async fn call_upstream(
+    clients: &ClientRegistry,
+    service: &str,
+    rpc: &RouteTarget,
+    params: serde_json::Value,
+) -> Result<serde_json::Value, Status> {
+    let (method, idempotent, params) = (rpc.name(), rpc.idempotent(), &params);
+    let call = retry::call_with_retry(service, method, idempotent, move || {
+        hedge::call_with_hedge(service, method, idempotent, move || {
+            rpc.call(clients, params.clone())
+        })
+    });
+    circuit_breaker::call_service(
+        service,
+        method,
+        concurrency_limiter::call_service(service, call),
+    )
+    .await
+}
+
+/// 编译idl中的 `google.api.http` 注解和配置的路由表, 路由表中的路由会覆盖注解中相同method和path的路由
+pub fn compile_routes(
+    routes: &[RouteConfig],
+    proto: Option<&ProtoRegistry>,
+) -> anyhow::Result<Vec<CompiledRoute>> {
+    let mut compiled: Vec<CompiledRoute> = proto
+        .map(|p| {
+            p.http_routes()
+                .iter()
+                .map(CompiledRoute::from_http_route)
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut configured: Vec<CompiledRoute> = vec![];
+    for route in routes {
+        let route = CompiledRoute::compile(route, proto)?;
+        if configured
+            .iter()
+            .any(|x| x.method == route.method && x.path == route.path)
+        {
+            return Err(anyhow!(
+                "duplicate route {} {} in [[routes]]",
+                route.method,
+                route.path
+            ));
+        }
+        compiled.retain(|x| {
+            let overridden = x.method == route.method && x.path == route.path;
+            if overridden {
+                tracing::warn!(
+                    "route {} {} in idl is overridden by [[routes]]",
+                    x.method,
+                    x.path
+                );
+            }
+            !overridden
+        });
+        configured.push(route);
+    }
+    compiled.extend(configured);
+    Ok(compiled)
+}
+
+/// 两个路由路径是否会匹配同一个请求, `{name}` 可以匹配任意一段
+pub fn paths_overlap(a: &str, b: &str) -> bool {
+    let is_param = |x: &str| x.starts_with('{') && x.ends_with('}');
+    let a: Vec<&str> = a.trim_end_matches('/').split('/').collect();
+    let b: Vec<&str> = b.trim_end_matches('/').split('/').collect();
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .all(|(x, y)| x == y || is_param(x) || is_param(y))
+}
+
+/// 根据编译后的路由构建路由, 同一个path的多个method合并到一个 `MethodRouter`
+pub fn build_route_table(compiled: Vec<CompiledRoute>) -> anyhow::Result<Router> {
+    let mut by_path: BTreeMap<String, Vec<Arc<CompiledRoute>>> = BTreeMap::new();
+    for route in compiled {
+        by_path
+            .entry(route.path.clone())
+            .or_default()
+            .push(Arc::new(route));
+    }
+
+    let mut router = Router::new();
+    for (path, routes) in by_path {
+        let mut builder = MethodRouter::builder();
+        for route in routes {
+            tracing::info!(
+                "route {} {} -> {}/{}",
+                route.method,
+                route.path,
+                route.service,
+                route.rpc.name()
+            );
+            let method = route.method.clone();
+            let handler = move |Extension(ctx): Extension<ServiceContext>,
+                                PathParams(path_params): PathParams<HashMap<String, String>>,
+                                uri: Uri,
+                                headers: HeaderMap,
+                                body: Bytes| {
+                handle_route(route.clone(), ctx, path_params, uri, headers, body)
+            };
+            builder = match method {
+                Method::GET => builder.get(handler),
+                Method::POST => builder.post(handler),
+                Method::PUT => builder.put(handler),
+                Method::DELETE => builder.delete(handler),
+                Method::PATCH => builder.patch(handler),
+                _ => return Err(anyhow!("unsupported method {} of route {}", method, path)),
+            };
+        }
+        router = router.route(path, builder.build());
+    }
+    Ok(router)
+}
+
+#[cfg(test)]
+mod route_table_test {
+    use super::*;
+
+    #[test]
+    fn parse_binding() {
+        let b = Binding::parse("id", "query:id:int").unwrap();
+        assert_eq!(b.source, BindingSource::Query("id".to_string()));
+        assert_eq!(b.ty, BindingType::Int);
+
+        let b = Binding::parse("user", "body:user").unwrap();
+        assert_eq!(b.ty, BindingType::Json);
+
+        assert!(Binding::parse("id", "query").is_err());
+        assert!(Binding::parse("id", "cookie:id").is_err());
+        assert!(Binding::parse("id", "query:id:long").is_err());
+    }
+
+    #[test]
+    fn body_only_for_body_bindings() {
+        let bindings = |specs: &[(&str, &str)]| {
+            ParamMapping::Bindings(
+                specs
+                    .iter()
+                    .map(|(field, spec)| Binding::parse(field, spec).unwrap())
+                    .collect(),
+            )
+        };
+        assert!(bindings(&[]).uses_body());
+        assert!(bindings(&[("id", "query:id:int"), ("user", "body:user")]).uses_body());
+        assert!(!bindings(&[("id", "query:id:int")]).uses_body());
+    }
+
+    #[test]
+    fn configured_routes_override_idl() {
+        let proto = ProtoRegistry::load(None).unwrap();
+        let route = RouteConfig {
+            method: "GET".to_string(),
+            path: "/order/random".to_string(),
+            service: "volo-boot-order.rpc".to_string(),
+            rpc: "GetRandom".to_string(),
+            bindings: Default::default(),
+        };
+        let compiled = compile_routes(std::slice::from_ref(&route), Some(&proto)).unwrap();
+        let random: Vec<&CompiledRoute> = compiled
+            .iter()
+            .filter(|x| x.path == "/order/random")
+            .collect();
+        assert_eq!(random.len(), 1);
+        assert!(matches!(random[0].mapping, ParamMapping::Bindings(_)));
+
+        let lowercase = RouteConfig {
+            method: "get".to_string(),
+            ..route.clone()
+        };
+        assert!(compile_routes(&[route, lowercase], Some(&proto)).is_err());
+    }
+
+    #[test]
+    fn overlapping_paths() {
+        assert!(paths_overlap("/random", "/random/"));
+        assert!(paths_overlap("/grpc/{service}/{method}", "/grpc/a/b"));
+        assert!(paths_overlap("/user/{id}", "/user/query-one"));
+        assert!(!paths_overlap(
+            "/grpc/{service}",
+            "/grpc/{service}/{method}"
+        ));
+        assert!(!paths_overlap("/random", "/metrics"));
+    }
+
+    #[test]
+    fn validate_generated_method() {
+        let get_order = RouteTarget::Generated(
+            client::find_method(client::OrderRpc::NAME, "GetOrder").unwrap(),
+        );
+        assert!(get_order
+            .validate(&serde_json::json!({ "user_id": 1 }))
+            .is_ok());
+        let details = get_order
+            .validate(&serde_json::json!({ "id": null }))
+            .err()
+            .unwrap();
+        assert_eq!(details[0].reason, "id 和 user_id 不能同时为空");
+
+        let get_user =
+            RouteTarget::Generated(client::find_method(client::UserRpc::NAME, "GetUser").unwrap());
+        let r = R::<()>::invalid_argument(get_user.validate(&serde_json::json!({})).err().unwrap());
+        assert_eq!((r.code, r.msg.as_deref()), (400, Some("id 不能为空")));
+        assert_eq!(r.error.as_deref(), Some("INVALID_ARGUMENT"));
+    }
+
+    #[test]
+    fn convert_binding_value() {
+        let v = convert("id", serde_json::Value::from("123"), BindingType::Int).unwrap();
+        assert_eq!(v, serde_json::Value::from(123));
+        assert!(convert("id", serde_json::Value::from("abc"), BindingType::Int).is_err());
+    }
+}
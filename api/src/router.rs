@@ -1,10 +1,24 @@
+use crate::app_config::{OpenApiConfig, RouteConfig};
+use crate::concurrency_limiter::do_concurrency_limiter;
+use crate::consts::{
+    ADMIN_CONFIG_PATH, ADMIN_CONFIG_ROLLBACK_PATH, GRPC_PROXY_PATH, METRICS_PATH, RANDOM_PATH,
+};
+use crate::cors::do_cors;
+use crate::hash_key::do_hash_key;
+use crate::lane::do_lane;
+use crate::openapi::{self, ControllerDoc};
 use crate::prometheus::{setup_metrics_recorder, track_metrics};
 use crate::rate_limiter::do_rate_limiter;
+use crate::route_table::{build_route_table, compile_routes};
 use crate::{controller, ServiceContext};
+use serde_json::json;
 use std::future::ready;
+use volo_http::http::Method;
 use volo_http::{
-    response::Response,
-    server::{middleware, route::get, IntoResponse},
+    server::{
+        middleware,
+        route::{get, post, MethodRouter},
+    },
     utils::Extension,
     Router,
 };
@@ -14,43 +28,96 @@ use volo_http::{
 pub fn build_metrics_router() -> Router {
     let record_handler = setup_metrics_recorder();
     Router::new()
-        .route("/metrics", get(move || ready(record_handler.render())))
+        .route(METRICS_PATH, get(move || ready(record_handler.render())))
+        .merge(build_admin_router())
         .layer(middleware::from_fn(track_metrics))
-        .layer(middleware::map_response(headers_map_response))
+        .layer(middleware::from_fn(do_cors))
+}
+// 管理接口, 只在单独的metrics端口上提供, 需要通过 `[admin]` 的访问控制
+fn build_admin_router() -> Router {
+    Router::new()
+        .route(
+            ADMIN_CONFIG_PATH,
+            get(controller::admin_controller::get_config_status),
+        )
+        .route(
+            ADMIN_CONFIG_ROLLBACK_PATH,
+            post(controller::admin_controller::rollback_config),
+        )
+        .layer(middleware::from_fn(
+            controller::admin_controller::do_admin_auth,
+        ))
+}
+// 手写controller的路由和文档, 注册路由和生成OpenAPI文档都使用这个列表
+fn controller_routes() -> Vec<(ControllerDoc, MethodRouter)> {
+    vec![(
+        ControllerDoc {
+            method: Method::GET,
+            path: RANDOM_PATH,
+            tag: "random",
+            summary: "返回一个随机数",
+            data: json!({ "type": "integer", "format": "int32" }),
+        },
+        get(controller::random_controller::get_random),
+    )]
+}
+// OpenAPI文档和Swagger UI, 文档在启动时生成
+fn build_openapi_router(config: &OpenApiConfig, document: serde_json::Value) -> Router {
+    let document = document.to_string();
+    let html = openapi::swagger_ui_html(config);
+    Router::new()
+        .route(
+            config.path(),
+            get(move || ready(([("Content-Type", "application/json")], document.clone()))),
+        )
+        .route(
+            config.ui_path(),
+            get(move || ready(([("Content-Type", "text/html; charset=utf-8")], html.clone()))),
+        )
 }
 // 业务相关路由
-pub fn build_biz_router(cxt: ServiceContext, with_metrics: bool) -> Router {
-    let mut r = Router::new();
+pub fn build_biz_router(
+    cxt: ServiceContext,
+    routes: &[RouteConfig],
+    openapi_config: Option<&OpenApiConfig>,
+    with_metrics: bool,
+) -> anyhow::Result<Router> {
+    // idl注解中的路由和配置的路由表, 在启动时已经校验过
+    let compiled = compile_routes(routes, cxt.proto.as_deref())?;
+    let (controller_docs, controller_routers): (Vec<_>, Vec<_>) =
+        controller_routes().into_iter().unzip();
+    let openapi_router = openapi_config.map(|x| {
+        build_openapi_router(
+            x,
+            openapi::build_document(x, &compiled, &controller_docs, cxt.proto.as_deref()),
+        )
+    });
+    let mut r = build_route_table(compiled)?;
+    if let Some(openapi_router) = openapi_router {
+        r = r.merge(openapi_router);
+    }
+    if cxt.proto.is_some() {
+        r = r.route(
+            GRPC_PROXY_PATH,
+            post(controller::grpc_proxy_controller::proxy),
+        );
+    }
     if with_metrics {
         let record_handler = setup_metrics_recorder();
-        r = r.route("/metrics", get(move || ready(record_handler.render())));
+        // 管理接口不在业务端口上提供
+        r = r.route(METRICS_PATH, get(move || ready(record_handler.render())));
     }
-    r.route(
-        "/user/query-one",
-        get(controller::user_controller::get_user),
-    )
-    .route(
-        "/order/query-one",
-        get(controller::order_controller::get_order),
-    )
-    .route(
-        "/order/random",
-        get(controller::order_controller::get_order_random),
-    )
-    .route("/random", get(controller::random_controller::get_random))
-    .layer(middleware::from_fn(track_metrics))
-    .layer(middleware::from_fn(do_rate_limiter))
-    .layer(middleware::map_response(headers_map_response))
-    .layer(Extension(cxt))
-}
-
-async fn headers_map_response(response: Response) -> impl IntoResponse {
-    (
-        [
-            ("Access-Control-Allow-Origin", "*"),
-            ("Access-Control-Allow-Headers", "*"),
-            ("Access-Control-Allow-Method", "*"),
-        ],
-        response,
-    )
+    for (doc, router) in controller_docs.iter().zip(controller_routers) {
+        r = r.route(doc.path, router);
+    }
+    let r = r
+        .layer(middleware::from_fn(do_hash_key))
+        .layer(middleware::from_fn(do_lane))
+        .layer(middleware::from_fn(track_metrics))
+        .layer(middleware::from_fn(do_concurrency_limiter))
+        .layer(middleware::from_fn(do_rate_limiter))
+        // 预检请求不计入限流
+        .layer(middleware::from_fn(do_cors))
+        .layer(Extension(cxt));
+    Ok(r)
 }
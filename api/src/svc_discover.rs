@@ -1,7 +1,17 @@
+use crate::circuit_breaker;
+use crate::client;
+use crate::consts;
+use crate::registry::static_file::{watch_file, WATCH_INTERVAL};
 use anyhow::anyhow;
 use async_broadcast::{Receiver, RecvError};
 use dashmap::DashMap;
+use nacos_sdk::api::naming::ServiceInstance;
 use pd_rs_common::svc::nacos::NacosNamingAndConfigData;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tracing::warn;
 use volo::context::Endpoint;
@@ -44,21 +54,7 @@ impl NacosDiscover {
                         tracing::info!("received svc change event: {:?}", recv);
                         let key: FastStr = recv.service_name.clone().into();
                         if let Some(is) = recv.instances.clone() {
-                            let mut new_instance = Vec::with_capacity(is.len());
-                            for x in is {
-                                match format!("{}:{}", x.ip, x.port).parse() {
-                                    Ok(addr) => new_instance.push(Arc::new(Instance {
-                                        address: volo::net::Address::from(Address::Ip(addr)),
-                                        weight: x.weight as u32,
-                                        tags: Default::default(),
-                                    })),
-                                    Err(e) => tracing::error!(
-                                        "failed to parse instance address: {:?}, err: {}",
-                                        x,
-                                        e
-                                    ),
-                                }
-                            }
+                            let new_instance = to_volo_instances(is.iter());
 
                             let mut pre_svc_instance = vec![];
                             match current_svc_instance.get(key.as_str()) {
@@ -68,11 +64,12 @@ impl NacosDiscover {
                                 None => {}
                             }
 
-                            let (ch, is_change) =
+                            let (ch, _is_change) =
                                 diff_address(key.clone(), pre_svc_instance, new_instance.clone());
-                            if is_change {
-                                current_svc_instance.insert(key, new_instance);
-                            }
+                            // 地址不变时元数据(tags)也可能变化, 所以总是更新
+                            current_svc_instance.insert(key, new_instance);
+                            circuit_breaker::evict_instances(&ch.key, &ch.removed);
+                            client::evict_instances(&ch.key, &ch.removed);
 
                             // always broadcast
                             let _ = s.try_broadcast(ch);
@@ -93,6 +90,48 @@ impl NacosDiscover {
     }
 }
 
+/// nacos实例转换为volo实例: 过滤掉不健康或者未启用的实例, 实例元数据和状态写入 `Instance.tags`,
+/// 负载均衡器可以通过tags做版本、可用区等路由
+pub fn to_volo_instances<'a>(
+    nacos_instances: impl Iterator<Item = &'a ServiceInstance>,
+) -> Vec<Arc<Instance>> {
+    let mut new_instance = vec![];
+    for x in nacos_instances {
+        if !x.healthy || !x.enabled {
+            tracing::debug!("skip unavailable instance: {:?}", x);
+            continue;
+        }
+        match format!("{}:{}", x.ip, x.port).parse() {
+            Ok(addr) => {
+                let mut tags: HashMap<Cow<'static, str>, Cow<'static, str>> = x
+                    .metadata
+                    .iter()
+                    .map(|(k, v)| (Cow::Owned(k.clone()), Cow::Owned(v.clone())))
+                    .collect();
+                if let Some(cluster_name) = x.cluster_name.as_ref() {
+                    tags.insert(consts::TAG_CLUSTER.into(), Cow::Owned(cluster_name.clone()));
+                }
+                tags.insert(consts::TAG_HEALTHY.into(), x.healthy.to_string().into());
+                tags.insert(consts::TAG_ENABLED.into(), x.enabled.to_string().into());
+                tags.insert(consts::TAG_EPHEMERAL.into(), x.ephemeral.to_string().into());
+
+                new_instance.push(Arc::new(Instance {
+                    address: Address::Ip(addr),
+                    weight: x.weight as u32,
+                    tags,
+                }))
+            }
+            Err(e) => tracing::error!("failed to parse instance address: {:?}, err: {}", x, e),
+        }
+    }
+    new_instance
+}
+
+/// 获取实例的tag
+pub fn instance_tag<'a>(instance: &'a Instance, key: &str) -> Option<&'a str> {
+    instance.tags.get(key).map(|v| v.as_ref())
+}
+
 impl Discover for NacosDiscover {
     type Key = FastStr;
     type Error = LoadBalanceError;
@@ -109,19 +148,7 @@ impl Discover for NacosDiscover {
             .sub_svc_map
             .get(key.as_str());
         if let Some(inst_list) = inst_list {
-            let mut new_instance = Vec::with_capacity(inst_list.len());
-            for x in inst_list.iter() {
-                match format!("{}:{}", x.ip, x.port).parse() {
-                    Ok(addr) => new_instance.push(Arc::new(Instance {
-                        address: volo::net::Address::from(Address::Ip(addr)),
-                        weight: x.weight as u32,
-                        tags: Default::default(),
-                    })),
-                    Err(e) => {
-                        tracing::error!("failed to parse instance address: {:?}, err: {}", x, e)
-                    }
-                }
-            }
+            let new_instance = to_volo_instances(inst_list.iter());
 
             self.current_svc_instance.insert(key, new_instance.clone());
             Ok(new_instance)
@@ -139,3 +166,389 @@ impl Discover for NacosDiscover {
         Some(self.svc_change_receiver.clone())
     }
 }
+
+/// 静态服务发现, 实例列表来自 `[sd.static.services]` 或者 `instance_file` 文件
+#[derive(Clone)]
+pub struct StaticDiscover {
+    pub svc_change_sender: async_broadcast::Sender<Change<FastStr>>,
+    pub svc_change_receiver: async_broadcast::Receiver<Change<FastStr>>,
+    pub svc_instance: Arc<DashMap<FastStr, Vec<Arc<Instance>>>>,
+}
+
+/// 实例文件中的一个实例
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StaticInstance {
+    pub address: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+fn default_weight() -> u32 {
+    100
+}
+
+impl StaticDiscover {
+    /// 使用配置中写死的实例地址
+    pub fn new(services: &HashMap<String, Vec<String>>) -> Self {
+        let services = services
+            .iter()
+            .map(|(svc_name, addrs)| {
+                let instances = addrs
+                    .iter()
+                    .map(|addr| StaticInstance {
+                        address: addr.clone(),
+                        weight: default_weight(),
+                        tags: Default::default(),
+                    })
+                    .collect();
+                (svc_name.clone(), instances)
+            })
+            .collect();
+        Self::with_services(to_instances(services))
+    }
+
+    /// 从实例文件(toml/yaml)中加载实例, 并监听文件变化
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("read instance file {} failed: {}", path, e))?;
+        let ret = Self::with_services(to_instances(parse_instance_file(path, &content)?));
+
+        let s = ret.svc_change_sender.clone();
+        let svc_instance = ret.svc_instance.clone();
+        let file_path = path.to_string();
+        watch_file(
+            PathBuf::from(path),
+            WATCH_INTERVAL,
+            move |content| match parse_instance_file(file_path.as_str(), &content) {
+                Ok(services) => reload_instances(&svc_instance, &s, to_instances(services)),
+                Err(e) => tracing::error!("reload instance file {} failed: {}", file_path, e),
+            },
+        );
+
+        Ok(ret)
+    }
+
+    fn with_services(services: HashMap<FastStr, Vec<Arc<Instance>>>) -> Self {
+        let (mut svc_ch_s, svc_ch_r) = async_broadcast::broadcast(100);
+        svc_ch_s.set_overflow(true);
+
+        Self {
+            svc_change_sender: svc_ch_s,
+            svc_change_receiver: svc_ch_r,
+            svc_instance: Arc::new(services.into_iter().collect()),
+        }
+    }
+
+    pub fn contains(&self, service_name: &str) -> bool {
+        self.svc_instance.contains_key(service_name)
+    }
+}
+
+/// 实例文件格式:
+/// ```toml
+/// [[services."volo-boot-user.rpc"]]
+/// address = "127.0.0.1:8081"
+/// weight = 100
+/// tags = { zone = "a" }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct InstanceFile {
+    #[serde(default)]
+    pub services: HashMap<String, Vec<StaticInstance>>,
+}
+
+/// 根据文件后缀解析实例文件, 支持 toml/yml/yaml
+pub fn parse_instance_file(
+    path: &str,
+    content: &str,
+) -> anyhow::Result<HashMap<String, Vec<StaticInstance>>> {
+    let file: InstanceFile = if path.ends_with(".yml") || path.ends_with(".yaml") {
+        serde_yml::from_str(content)?
+    } else {
+        toml::from_str(content)?
+    };
+    Ok(file.services)
+}
+
+fn to_instances(
+    services: HashMap<String, Vec<StaticInstance>>,
+) -> HashMap<FastStr, Vec<Arc<Instance>>> {
+    let mut ret = HashMap::with_capacity(services.len());
+    for (svc_name, instances) in services {
+        let mut new_instance = Vec::with_capacity(instances.len());
+        for x in instances {
+            match x.address.trim().parse::<SocketAddr>() {
+                Ok(addr) => new_instance.push(Arc::new(Instance {
+                    address: Address::Ip(addr),
+                    weight: x.weight,
+                    tags: x
+                        .tags
+                        .into_iter()
+                        .map(|(k, v)| (Cow::Owned(k), Cow::Owned(v)))
+                        .collect(),
+                })),
+                Err(e) => tracing::error!(
+                    "failed to parse static instance address: {}, err: {}",
+                    x.address,
+                    e
+                ),
+            }
+        }
+        ret.insert(FastStr::new(svc_name), new_instance);
+    }
+    ret
+}
+
+fn same_instance(a: &Instance, b: &Instance) -> bool {
+    a.address == b.address && a.weight == b.weight && a.tags == b.tags
+}
+
+/// 用新的实例列表替换旧的, 并广播变化(包括被删除的服务)
+fn reload_instances(
+    current_svc_instance: &DashMap<FastStr, Vec<Arc<Instance>>>,
+    s: &async_broadcast::Sender<Change<FastStr>>,
+    mut services: HashMap<FastStr, Vec<Arc<Instance>>>,
+) {
+    let removed_keys: Vec<FastStr> = current_svc_instance
+        .iter()
+        .filter(|x| !services.contains_key(x.key()))
+        .map(|x| x.key().clone())
+        .collect();
+    for key in removed_keys {
+        services.insert(key, vec![]);
+    }
+
+    for (key, new_instance) in services {
+        let pre_svc_instance = current_svc_instance
+            .get(key.as_str())
+            .map(|x| x.value().clone())
+            .unwrap_or_default();
+
+        // diff_address只比较地址, 只修改了权重或tags(如泳道)时也要更新
+        let updated: Vec<Arc<Instance>> = new_instance
+            .iter()
+            .filter(|x| {
+                pre_svc_instance
+                    .iter()
+                    .any(|y| y.address == x.address && !same_instance(x, y))
+            })
+            .cloned()
+            .collect();
+        let (mut ch, is_change) = diff_address(key.clone(), pre_svc_instance, new_instance.clone());
+        if !is_change && updated.is_empty() {
+            continue;
+        }
+        ch.updated = updated;
+        tracing::info!("static instances of {} changed: {:?}", key, ch.all);
+        if new_instance.is_empty() {
+            current_svc_instance.remove(key.as_str());
+        } else {
+            current_svc_instance.insert(key, new_instance);
+        }
+        circuit_breaker::evict_instances(&ch.key, &ch.removed);
+        client::evict_instances(&ch.key, &ch.removed);
+        let _ = s.try_broadcast(ch);
+    }
+}
+
+impl Discover for StaticDiscover {
+    type Key = FastStr;
+    type Error = LoadBalanceError;
+
+    async fn discover<'s>(
+        &'s self,
+        endpoint: &'s Endpoint,
+    ) -> Result<Vec<Arc<Instance>>, Self::Error> {
+        match self.svc_instance.get(endpoint.service_name.as_str()) {
+            Some(inst_list) if !inst_list.is_empty() => Ok(inst_list.value().clone()),
+            _ => {
+                let ee = anyhow!("no instances for {}", endpoint.service_name.to_string()).into();
+                Err(LoadBalanceError::Discover(ee))
+            }
+        }
+    }
+
+    fn key(&self, endpoint: &Endpoint) -> Self::Key {
+        endpoint.service_name.clone()
+    }
+
+    fn watch(&self, _keys: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+        Some(self.svc_change_receiver.clone())
+    }
+}
+
+/// 根据注册中心类型选择具体的服务发现实现
+#[derive(Clone)]
+pub enum SvcDiscover {
+    Nacos(NacosDiscover),
+    Static(StaticDiscover),
+}
+
+impl Discover for SvcDiscover {
+    type Key = FastStr;
+    type Error = LoadBalanceError;
+
+    async fn discover<'s>(
+        &'s self,
+        endpoint: &'s Endpoint,
+    ) -> Result<Vec<Arc<Instance>>, Self::Error> {
+        match self {
+            SvcDiscover::Nacos(d) => d.discover(endpoint).await,
+            SvcDiscover::Static(d) => d.discover(endpoint).await,
+        }
+    }
+
+    fn key(&self, endpoint: &Endpoint) -> Self::Key {
+        match self {
+            SvcDiscover::Nacos(d) => d.key(endpoint),
+            SvcDiscover::Static(d) => d.key(endpoint),
+        }
+    }
+
+    fn watch(&self, keys: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+        match self {
+            SvcDiscover::Nacos(d) => d.watch(keys),
+            SvcDiscover::Static(d) => d.watch(keys),
+        }
+    }
+}
+
+#[cfg(test)]
+mod svc_discover_test {
+    use super::*;
+
+    fn nacos_instance(port: i32, healthy: bool, enabled: bool) -> ServiceInstance {
+        ServiceInstance {
+            ip: "127.0.0.1".to_string(),
+            port,
+            weight: 10.0,
+            healthy,
+            enabled,
+            ephemeral: true,
+            cluster_name: Some("DEFAULT".to_string()),
+            metadata: HashMap::from([("lane".to_string(), "canary".to_string())]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn nacos_instances_to_tags() {
+        let nacos_instances = [
+            nacos_instance(8081, true, true),
+            nacos_instance(8082, false, true),
+            nacos_instance(8083, true, false),
+        ];
+        let instances = to_volo_instances(nacos_instances.iter());
+        // 不健康或未启用的实例被过滤
+        assert_eq!(instances.len(), 1);
+        let instance = &instances[0];
+        assert_eq!(instance.address.to_string(), "127.0.0.1:8081");
+        assert_eq!(instance.weight, 10);
+        assert_eq!(instance_tag(instance, consts::TAG_LANE), Some("canary"));
+        assert_eq!(instance_tag(instance, consts::TAG_CLUSTER), Some("DEFAULT"));
+        assert_eq!(instance_tag(instance, consts::TAG_HEALTHY), Some("true"));
+        assert_eq!(instance_tag(instance, consts::TAG_EPHEMERAL), Some("true"));
+    }
+
+    #[test]
+    fn parse_toml_and_yaml() {
+        let toml = r#"
+[[services."volo-boot-user.rpc"]]
+address = "127.0.0.1:8081"
+tags = { lane = "canary" }
+[[services."volo-boot-user.rpc"]]
+address = "127.0.0.1:8082"
+weight = 50
+"#;
+        let services = parse_instance_file("instances.toml", toml).unwrap();
+        let user = &services["volo-boot-user.rpc"];
+        assert_eq!(user.len(), 2);
+        assert_eq!(user[0].weight, 100);
+        assert_eq!(user[0].tags["lane"], "canary");
+        assert_eq!(user[1].weight, 50);
+
+        let yaml = r#"
+services:
+  volo-boot-order.rpc:
+    - address: "127.0.0.1:8083"
+    - address: "bad address"
+"#;
+        let services = parse_instance_file("instances.yaml", yaml).unwrap();
+        // 地址错误的实例被跳过
+        let instances = to_instances(services);
+        assert_eq!(instances["volo-boot-order.rpc"].len(), 1);
+
+        assert!(parse_instance_file("instances.toml", "services = 1").is_err());
+    }
+
+    #[test]
+    fn reload_broadcasts_changes() {
+        let (mut s, mut r) = async_broadcast::broadcast(10);
+        s.set_overflow(true);
+        let parse = |content: &str| to_instances(parse_instance_file("x.toml", content).unwrap());
+        let current: DashMap<FastStr, Vec<Arc<Instance>>> = parse(
+            r#"
+services."volo-boot-user.rpc" = [{ address = "127.0.0.1:8081" }]
+services."volo-boot-order.rpc" = [{ address = "127.0.0.1:8083" }]
+"#,
+        )
+        .into_iter()
+        .collect();
+
+        // 没有变化时不广播
+        reload_instances(
+            &current,
+            &s,
+            parse(
+                r#"
+services."volo-boot-user.rpc" = [{ address = "127.0.0.1:8081" }]
+services."volo-boot-order.rpc" = [{ address = "127.0.0.1:8083" }]
+"#,
+            ),
+        );
+        assert!(r.try_recv().is_err());
+
+        // user新增一个实例, order整个服务被删除
+        reload_instances(
+            &current,
+            &s,
+            parse(
+                r#"
+services."volo-boot-user.rpc" = [{ address = "127.0.0.1:8081" }, { address = "127.0.0.1:8082" }]
+"#,
+            ),
+        );
+        let mut changes = vec![r.try_recv().unwrap(), r.try_recv().unwrap()];
+        changes.sort_by(|a, b| a.key.as_str().cmp(b.key.as_str()));
+        assert_eq!(changes[0].key.as_str(), "volo-boot-order.rpc");
+        assert_eq!(changes[0].removed.len(), 1);
+        assert_eq!(changes[1].key.as_str(), "volo-boot-user.rpc");
+        assert_eq!(changes[1].added.len(), 1);
+        assert!(!current.contains_key("volo-boot-order.rpc"));
+        assert_eq!(current.get("volo-boot-user.rpc").unwrap().len(), 2);
+
+        // 只修改权重也广播
+        reload_instances(
+            &current,
+            &s,
+            parse(
+                r#"
+services."volo-boot-user.rpc" = [{ address = "127.0.0.1:8081", weight = 10 }, { address = "127.0.0.1:8082" }]
+"#,
+            ),
+        );
+        let change = r.try_recv().unwrap();
+        assert!(change.added.is_empty() && change.removed.is_empty());
+        assert_eq!(change.updated.len(), 1);
+        assert_eq!(change.updated[0].weight, 10);
+        let current_weights: Vec<u32> = current
+            .get("volo-boot-user.rpc")
+            .unwrap()
+            .iter()
+            .map(|x| x.weight)
+            .collect();
+        assert!(current_weights.contains(&10));
+    }
+}
@@ -0,0 +1,215 @@
+//! 业务错误码目录, rpc服务和api网关共用: rpc服务返回带业务错误码的 `Status`,
+//! 网关从 `Status` 的metadata中取出错误码, 原样返回给调用方
+
+use serde::{Deserialize, Serialize};
+use volo_grpc::metadata::{MetadataMap, MetadataValue};
+use volo_grpc::{Code, Status};
+
+/// 数字错误码
+pub const CODE_METADATA_KEY: &str = "x-biz-code";
+/// 字符串错误码
+pub const NAME_METADATA_KEY: &str = "x-biz-error";
+/// 错误详情, json数组
+pub const DETAILS_METADATA_KEY: &str = "x-biz-details-bin";
+
+/// 一个业务错误码, 错误码发布后不能修改
+#[derive(Debug, PartialEq, Eq)]
+pub struct ErrorCode {
+    /// 字符串错误码, 如 USER_NOT_FOUND
+    pub name: &'static str,
+    /// 数字错误码, 前两位为服务编号: 10 通用, 20 user, 30 order
+    pub code: i64,
+    /// 对应的grpc状态码, 网关按它返回http状态码
+    pub grpc_code: Code,
+    /// 各语言的提示信息, 第一个为默认语言
+    pub messages: &'static [(&'static str, &'static str)],
+}
+
+impl ErrorCode {
+    /// 按语言取提示信息, `lang` 如 zh、en-US, 没有对应的语言时返回默认语言
+    pub fn message(&self, lang: Option<&str>) -> &'static str {
+        let lang = lang
+            .and_then(|x| x.split(['-', '_']).next())
+            .map(|x| x.trim().to_ascii_lowercase());
+        self.messages
+            .iter()
+            .find(|(l, _)| Some(*l) == lang.as_deref())
+            .or(self.messages.first())
+            .map(|(_, msg)| *msg)
+            .unwrap_or(self.name)
+    }
+}
+
+pub const INTERNAL: ErrorCode = ErrorCode {
+    name: "INTERNAL",
+    code: 10000,
+    grpc_code: Code::Internal,
+    messages: &[("zh", "服务内部错误"), ("en", "internal error")],
+};
+
+pub const INVALID_ARGUMENT: ErrorCode = ErrorCode {
+    name: "INVALID_ARGUMENT",
+    code: 10001,
+    grpc_code: Code::InvalidArgument,
+    messages: &[("zh", "参数错误"), ("en", "invalid argument")],
+};
+
+pub const USER_NOT_FOUND: ErrorCode = ErrorCode {
+    name: "USER_NOT_FOUND",
+    code: 20001,
+    grpc_code: Code::NotFound,
+    messages: &[("zh", "用户不存在"), ("en", "user not found")],
+};
+
+pub const ORDER_QUERY_REQUIRED: ErrorCode = ErrorCode {
+    name: "ORDER_QUERY_REQUIRED",
+    code: 30001,
+    grpc_code: Code::InvalidArgument,
+    messages: &[
+        ("zh", "id 和 user_id 不能同时为空"),
+        ("en", "either id or user_id is required"),
+    ],
+};
+
+/// 所有的错误码, 新增错误码时需要加到这里
+pub const CATALOGUE: &[&ErrorCode] = &[
+    &INTERNAL,
+    &INVALID_ARGUMENT,
+    &USER_NOT_FOUND,
+    &ORDER_QUERY_REQUIRED,
+];
+
+pub fn find(name: &str) -> Option<&'static ErrorCode> {
+    CATALOGUE.iter().find(|x| x.name == name).copied()
+}
+
+/// 错误详情, 如某个字段校验失败的原因
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    pub reason: String,
+}
+
+/// rpc服务返回的业务错误, 通过 `into_status` 转换为 `Status`
+#[derive(Debug)]
+pub struct BizError {
+    pub code: &'static ErrorCode,
+    /// 不设置时使用错误码默认语言的提示信息
+    pub message: Option<String>,
+    pub details: Vec<ErrorDetail>,
+}
+
+impl BizError {
+    pub fn new(code: &'static ErrorCode) -> Self {
+        Self {
+            code,
+            message: None,
+            details: vec![],
+        }
+    }
+
+    pub fn with_message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    pub fn with_detail(mut self, field: Option<&str>, reason: impl Into<String>) -> Self {
+        self.details.push(ErrorDetail {
+            field: field.map(|x| x.to_string()),
+            reason: reason.into(),
+        });
+        self
+    }
+
+    /// 错误码和详情放在metadata中, 经过网关时不会丢失
+    pub fn into_status(self) -> Status {
+        let mut metadata = MetadataMap::new();
+        if let Ok(v) = self.code.code.to_string().parse() {
+            metadata.insert(CODE_METADATA_KEY, v);
+        }
+        if let Ok(v) = self.code.name.parse() {
+            metadata.insert(NAME_METADATA_KEY, v);
+        }
+        if !self.details.is_empty() {
+            if let Ok(json) = serde_json::to_vec(&self.details) {
+                metadata.insert_bin(DETAILS_METADATA_KEY, MetadataValue::from_bytes(&json));
+            }
+        }
+        let message = self
+            .message
+            .unwrap_or_else(|| self.code.message(None).to_string());
+        Status::with_metadata(self.code.grpc_code, message, metadata)
+    }
+}
+
+impl From<BizError> for Status {
+    fn from(e: BizError) -> Self {
+        e.into_status()
+    }
+}
+
+/// 从rpc返回的 `Status` 中取出的业务错误, 可能是网关还不认识的新错误码
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorInfo {
+    pub name: String,
+    pub code: i64,
+    pub details: Vec<ErrorDetail>,
+}
+
+impl ErrorInfo {
+    /// 没有业务错误码的 `Status` 返回None
+    pub fn from_status(status: &Status) -> Option<Self> {
+        let metadata = status.metadata();
+        let code = metadata
+            .get(CODE_METADATA_KEY)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())?;
+        let name = metadata
+            .get(NAME_METADATA_KEY)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let details = metadata
+            .get_bin(DETAILS_METADATA_KEY)
+            .and_then(|v| v.to_bytes().ok())
+            .and_then(|v| serde_json::from_slice(&v).ok())
+            .unwrap_or_default();
+        Some(Self {
+            name,
+            code,
+            details,
+        })
+    }
+
+    /// 网关认识的错误码按语言返回提示信息
+    pub fn message(&self, lang: Option<&str>) -> Option<&'static str> {
+        find(self.name.as_str()).map(|x| x.message(lang))
+    }
+}
+
+#[cfg(test)]
+mod biz_error_test {
+    use super::*;
+
+    #[test]
+    fn status_round_trip() {
+        let status = BizError::new(&ORDER_QUERY_REQUIRED)
+            .with_detail(Some("id"), "id 和 user_id 不能同时为空")
+            .into_status();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "id 和 user_id 不能同时为空");
+
+        let info = ErrorInfo::from_status(&status).unwrap();
+        assert_eq!(info.code, 30001);
+        assert_eq!(info.name, "ORDER_QUERY_REQUIRED");
+        assert_eq!(info.details[0].field.as_deref(), Some("id"));
+        assert_eq!(
+            info.message(Some("en-US")),
+            Some("either id or user_id is required")
+        );
+
+        assert!(ErrorInfo::from_status(&Status::not_found("x")).is_none());
+        assert_eq!(USER_NOT_FOUND.message(Some("fr")), "用户不存在");
+    }
+}
@@ -0,0 +1,107 @@
+//! rpc服务的注册: 按 `[sd]` 配置往nacos注册, 或者使用静态服务发现(不注册), user、order服务共用
+
+use anyhow::anyhow;
+use pd_rs_common::svc::nacos::NacosNamingAndConfigData;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// rpc服务配置中的 `[sd]`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ServerDiscover {
+    /// 注册中心类型, 不配置时默认为nacos
+    #[serde(default)]
+    pub kind: SdKind,
+    pub nacos: Option<NacosConfig>,
+    /// 静态服务发现, 不需要往注册中心注册
+    #[serde(rename = "static")]
+    pub static_sd: Option<StaticConfig>,
+}
+impl ServerDiscover {
+    /// 注册到注册中心时用的服务名称
+    pub fn service_name(&self) -> Option<&str> {
+        match self.kind {
+            SdKind::Nacos => self.nacos.as_ref().map(|c| c.service_name.as_str()),
+            SdKind::Static => self.static_sd.as_ref().map(|c| c.service_name.as_str()),
+        }
+    }
+}
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SdKind {
+    #[default]
+    Nacos,
+    Static,
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NacosConfig {
+    pub server_addr: String,
+    pub namespace: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub service_name: String,
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StaticConfig {
+    pub service_name: String,
+}
+
+/// 根据 `[sd]` 选择的注册中心
+#[derive(Clone)]
+pub enum Registry {
+    Nacos(Arc<NacosNamingAndConfigData>),
+    /// 静态服务发现, 由调用方在配置中写死实例地址, 注册/下线都是空操作
+    Static,
+}
+
+impl Registry {
+    pub fn from_config(sd: &ServerDiscover) -> anyhow::Result<Self> {
+        match sd.kind {
+            SdKind::Nacos => {
+                let Some(nacos_config) = sd.nacos.clone() else {
+                    return Err(anyhow!("sd.kind is nacos but [sd.nacos] is missing"));
+                };
+                let nacos_naming_data = NacosNamingAndConfigData::new(
+                    nacos_config.server_addr,
+                    nacos_config.namespace.unwrap_or("".to_string()),
+                    nacos_config.service_name,
+                    nacos_config.username,
+                    nacos_config.password,
+                )
+                .map_err(|e| anyhow!("create nacos client failed: {}", e))?;
+                Ok(Registry::Nacos(Arc::new(nacos_naming_data)))
+            }
+            SdKind::Static => Ok(Registry::Static),
+        }
+    }
+
+    pub async fn register(
+        &self,
+        service_name: String,
+        port: u32,
+        metadata: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Registry::Nacos(nacos_naming_data) => nacos_naming_data
+                .register_service(service_name, port as i32, None, None, metadata)
+                .await
+                .map(|_| ())
+                .map_err(|e| anyhow!("register service failed: {}", e)),
+            Registry::Static => {
+                tracing::info!("static registry, skip register {}:{}", service_name, port);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn deregister(&self) -> anyhow::Result<()> {
+        match self {
+            Registry::Nacos(nacos_naming_data) => nacos_naming_data
+                .deregister_service()
+                .await
+                .map(|_| ())
+                .map_err(|e| anyhow!("deregister service failed: {}", e)),
+            Registry::Static => Ok(()),
+        }
+    }
+}
@@ -1,7 +1,8 @@
+use biz_error::{BizError, ORDER_QUERY_REQUIRED};
 pub use order_volo_gen::order;
 use order_volo_gen::order::{GetRandomReq, RandomResp};
 use rand::Rng;
-use volo_grpc::{Request};
+use volo_grpc::Request;
 
 pub mod app_config;
 
@@ -43,9 +44,9 @@ impl order_volo_gen::order::OrderService for S {
 
             return Ok(volo_grpc::Response::new(order));
         } else {
-            return Err(::volo_grpc::Status::invalid_argument(
-                "id 和 user_id 不能同时为空",
-            ));
+            return Err(BizError::new(&ORDER_QUERY_REQUIRED)
+                .with_detail(Some("id"), "id 和 user_id 不能同时为空")
+                .into_status());
         }
     }
 
@@ -1,20 +1,12 @@
+use rpc_registry::ServerDiscover;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AppConfig {
     pub port: u32,
     pub disable_metrics: bool,
+    /// 注册到注册中心的实例元数据, 如 zone、lane, 网关会把它们作为实例的tags用于路由
+    pub metadata: Option<HashMap<String, String>>,
     pub sd: ServerDiscover,
 }
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct ServerDiscover {
-    pub nacos: NacosConfig,
-}
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct NacosConfig {
-    pub server_addr: String,
-    pub namespace: Option<String>,
-    pub username: Option<String>,
-    pub password: Option<String>,
-    pub service_name: String,
-}
@@ -1,9 +1,9 @@
-use std::collections::HashMap;
 use anyhow::anyhow;
 use clap::Parser;
 use pd_rs_common::load_config::LoadConfig;
+use rpc_registry::Registry;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
 use std::time::Duration;
 use tokio::signal;
 use user::app_config::AppConfig;
@@ -31,19 +31,13 @@ async fn main() {
     let addr: SocketAddr = format!("[::]:{}", app_config.port).parse().unwrap();
     let addr = volo::net::Address::from(addr);
 
-    // 注册服务
-    let nacos_config = app_config.sd.nacos;
-
-    let nacos_naming_data = Arc::new(
-        pd_rs_common::svc::nacos::NacosNamingAndConfigData::new(
-            nacos_config.server_addr,
-            nacos_config.namespace.unwrap_or("".to_string()),
-            nacos_config.service_name.clone(),
-            nacos_config.username,
-            nacos_config.password,
-        )
-        .unwrap(),
-    );
+    // 注册服务, 根据配置选择注册中心
+    let registry = Registry::from_config(&app_config.sd).unwrap();
+    let service_name = app_config
+        .sd
+        .service_name()
+        .expect("service_name is required in [sd]")
+        .to_string();
 
     // 优雅停机
     let (shutdown_tx, mut shutdown_rx) = tokio::sync::watch::channel(());
@@ -60,7 +54,8 @@ async fn main() {
             .http2_init_stream_window_size(2 * 1024 * 1024u32)
             // 发送缓冲区：2MB（匹配流窗口大小）
             .http2_max_send_buf_size(2 * 1024 * 1024usize)
-            .http2_max_concurrent_streams(None)
+            // 单个连接的最大并发stream数，网关的 max_concurrent_streams 不应超过这个值
+            .http2_max_concurrent_streams(Some(100))
             .add_service(
                 ServiceBuilder::new(user_volo_gen::user::UserServiceServer::new(S)).build(),
             )
@@ -80,14 +75,12 @@ async fn main() {
     if app_config.disable_metrics {
         meta_map.insert("disable_metrics".to_string(), "true".to_string());
     }
-    let nacos_svc_inst = nacos_naming_data
-        .register_service(
-            nacos_config.service_name,
-            app_config.port as i32,
-            None,
-            None,
-            meta_map,
-        )
+    meta_map.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
+    if let Some(metadata) = app_config.metadata {
+        meta_map.extend(metadata);
+    }
+    let svc_inst = registry
+        .register(service_name, app_config.port, meta_map)
         .await;
 
     let signal_task = tokio::spawn(async move {
@@ -99,8 +92,8 @@ async fn main() {
             _ = int => tracing::info!("receive ctrl_c")
         }
 
-        if let Ok(_) = nacos_svc_inst {
-            let _ret = nacos_naming_data.deregister_service().await;
+        if let Ok(_) = svc_inst {
+            let _ret = registry.deregister().await;
 
             tokio::time::sleep(Duration::from_secs(3)).await;
         }
@@ -1,5 +1,6 @@
 pub mod app_config;
 
+use biz_error::{BizError, USER_NOT_FOUND};
 pub use user_volo_gen::user;
 
 pub struct S;
@@ -12,7 +13,7 @@ impl user::UserService for S {
         let req_data = _req.into_inner();
         tracing::info!("获取用户: {:?}", req_data);
         if let None = req_data.id {
-            return Err(volo_grpc::Status::not_found("User not found"));
+            return Err(BizError::new(&USER_NOT_FOUND).into_status());
         }
         let user = user::User {
             id: req_data.id.unwrap_or_default(),